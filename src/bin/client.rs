@@ -4,36 +4,183 @@
 
 use bevy::{
     prelude::*, 
+    app::AppExit,
     window::WindowSettings, 
     time::Timer,
 };
 
+use iyes_loopless::prelude::*;
+
 use bevy_renet::{
     renet::{
         ClientAuthentication, 
         RenetClient, 
-        RenetError, ConnectToken, 
+        RenetError, ConnectToken, NetcodeError,
     },
     run_if_client_connected, 
     RenetClientPlugin,
 };
 
+/// What the server has told us we are doing in our room.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ClientRole {
+    /// Still waiting to hear from the server.
+    Connecting,
+    /// Waiting for a match, possibly watching someone else's in the meantime. Position 1 is next in line.
+    Queued { position: usize },
+    Player(PlayerSide),
+}
+
+/// Counts down to the serve once both paddles in our match are taken.
+#[derive(Default)]
+struct MatchCountdown(Option<Timer>);
+
+/// Marks the text telling the player what they're doing, for example where they are in the queue.
 #[derive(Component)]
-struct ClientSide(PlayerSide);
+struct RoleHud;
+
+const HUD_FONT_SIZE: f32 = 30.0;
+const HUD_COLOR: Color = Color::rgb(0.5, 0.5, 1.0);
+
+/// Inputs we've sent and already applied to our paddle, which the server hasn't caught up with yet.
+#[derive(Default)]
+struct InputHistory {
+    last_sent: u32,
+    /// Newest input the server has told us about, anything up to this is already in its positions.
+    acknowledged: u32,
+    pending: VecDeque<InputMessage>,
+}
+
+impl InputHistory {
+    /// Numbers an input and remembers it until the server confirms it.
+    fn record(&mut self, input: PlayerInput) -> InputMessage {
+        self.last_sent += 1;
+        let message = InputMessage { sequence: self.last_sent, input };
+        self.pending.push_back(message);
+        message
+    }
+
+    /// Forgets every input the server has already applied.
+    fn acknowledge(&mut self, sequence: u32) {
+        self.acknowledged = sequence;
+        while self.pending.front().is_some_and(|message| message.sequence <= sequence) {
+            self.pending.pop_front();
+        }
+    }
+
+    /// Where our paddle ends up once the inputs the server hasn't seen yet are applied on top of its position.
+    fn replay(&self, y: f32) -> f32 {
+        self.pending.iter().fold(y, |y, message| move_paddle(y, &message.input, POLL_RATE))
+    }
+}
 
+/// GameEvents from the server, held back until the snapshots we're showing catch up to them.
+#[derive(Default)]
+struct PendingEvents(Vec<GameEvent>);
+
+/// Marks the big text in the middle of the screen used to announce goals and winners.
 #[derive(Component)]
-struct ServInput(PlayerInput);
+struct EventBanner;
 
-use std::{time::{SystemTime}, net::{SocketAddr, TcpStream}, io::Write};
-use std::{net::UdpSocket};
+/// What the banner says, and how long it stays up.
+struct BannerMessage {
+    text: String,
+    timer: Timer,
+}
+
+const BANNER_FONT_SIZE: f32 = 60.0;
+const BANNER_COLOR: Color = Color::rgb(1.0, 1.0, 1.0);
+const GOAL_BANNER_SECONDS: f32 = 1.5;
+const WINNER_BANNER_SECONDS: f32 = 5.0;
 
-//const PROTOCOL_ID: u64 = 7;
+/// How the connection to the server is doing. The server pings us, so all we do is answer and notice when it goes quiet.
+/// Starts out empty, and is set up the first time we're connected.
+#[derive(Default)]
+struct ServerLink(Option<LinkStats>);
 
-use pong_multiplayer_rs::{common_net::*, common_game::*};
+/// Lets us take our paddle back if we drop out. Replaced every time the token service gives us a new one.
+struct Session {
+    /// Picked at random when the game starts, and sent with every token request so the token service knows it's still us asking.
+    /// That way a token we couldn't use doesn't keep our name from us.
+    instance: u64,
+    /// None until we've been let in, or once the server has no match for us to go back to.
+    resume_token: Option<ResumeToken>,
+    /// Set once the player closes the window, so the disconnect that follows isn't mistaken for a dropped connection.
+    quitting: bool,
+}
 
-fn new_renet_client(token: ConnectToken) -> RenetClient {
+/// Sent when we lose the server, either because renet says so or because it went quiet.
+struct ConnectionLost;
+
+/// Whether we have a connection to the server, are trying to get one, or have given up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum ConnectionState {
+    /// There's a RenetClient, either connected or still shaking hands with the server.
+    Online,
+    /// No RenetClient. Asking the token service for a connect token, or waiting to ask again.
+    Connecting,
+    /// Ran out of attempts, or the server turned us away. Waiting for the player to try again.
+    Menu,
+}
+
+/// The first retry waits this long, and each one after waits twice as long as the last.
+const RECONNECT_BASE_DELAY: f32 = 0.5;
+const RECONNECT_MAX_DELAY: f32 = 8.0;
+
+type TokenResult = Result<Granted, TokenRequestError>;
+
+/// What the token service gave us to connect with.
+struct Granted {
+    token: ConnectToken,
+    resume_token: ResumeToken,
+    /// The network simulator our game traffic goes through, if we're running one.
+    net_sim: Option<NetSimProxy>,
+}
+
+/// How we're doing at getting (back) onto the server.
+struct Reconnect {
+    /// Token requests made since we were last connected.
+    attempt: u32,
+    /// Counts down to the next request.
+    timer: Timer,
+    /// The request in flight, if any. It runs on its own thread so the window doesn't freeze while the token service takes its time.
+    pending: Option<Mutex<Receiver<TokenResult>>>,
+    /// Whether the request in flight is a resume, so if it's refused we can join from scratch instead.
+    resuming: bool,
+    /// Why the last attempt failed, shown on the menu.
+    reason: String,
+}
+
+impl Default for Reconnect {
+    fn default() -> Self {
+        Reconnect { attempt: 0, timer: Timer::from_seconds(0.0, false), pending: None, resuming: false, reason: String::new() }
+    }
+}
+
+/// How long to wait before the next token request, given how many have been made already.
+fn reconnect_delay(attempt: u32) -> f32 {
+    match attempt {
+        0 => 0.0,
+        n => (RECONNECT_BASE_DELAY * 2f32.powi(n as i32 - 1)).min(RECONNECT_MAX_DELAY),
+    }
+}
+
+/// Marks the text shown on the menu.
+#[derive(Component)]
+struct MenuText;
+
+/// Names of the players we've been told about, and which side they play on.
+#[derive(Default)]
+struct PlayerNames(HashMap<u64, (String, PlayerSide)>);
+
+use std::{time::{Duration, SystemTime}, net::{SocketAddr, TcpStream}, fmt, collections::{HashMap, VecDeque}, thread, sync::{Mutex, mpsc::{self, Receiver, TryRecvError}}};
+use std::{net::UdpSocket};
+
+use pong_multiplayer_rs::{common_net::*, common_game::*, common_config::*, common_wire::DeltaDecoder, common_netsim::NetSimProxy, common_rollback::RollbackSession};
+
+fn new_renet_client(token: ConnectToken) -> Result<RenetClient, RenetError> {
     //let server_addr = "45.33.33.109:5000".parse().unwrap();
-    let socket = UdpSocket::bind("0.0.0.0:0").unwrap();
+    let socket = UdpSocket::bind("0.0.0.0:0")?;
     //socket.connect(server_addr).unwrap();
     let connection_config = connection_config();
     let current_time = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap();
@@ -41,23 +188,163 @@ fn new_renet_client(token: ConnectToken) -> RenetClient {
     let authentication = ClientAuthentication::Secure {
         connect_token: token
     };
-    RenetClient::new(current_time, socket, client_id, connection_config, authentication).unwrap()
+    RenetClient::new(current_time, socket, client_id, connection_config, authentication)
+}
+
+/// Reasons we might not get a connect token from the server.
+#[derive(Debug)]
+enum TokenRequestError {
+    Io(std::io::Error),
+    Decode(bincode::Error),
+    /// The server's answer decoded fine, but the connect token in it didn't.
+    InvalidToken(NetcodeError),
+    InvalidUsername(UsernameError),
+    /// Someone else has our name, or was just given a token for it.
+    NameTaken(String),
+    Rejected(String),
+    ServerFull,
+    VersionMismatch { server_version: String, server_rules_hash: u64 },
+    /// We got a token, but couldn't set up a connection to use it with.
+    Client(RenetError),
+}
+
+impl fmt::Display for TokenRequestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TokenRequestError::Io(e) => write!(f, "could not reach the server: {}", e),
+            TokenRequestError::Decode(e) => write!(f, "the server sent something we don't understand: {}", e),
+            TokenRequestError::InvalidToken(e) => write!(f, "the server sent a connect token we can't read: {}", e),
+            TokenRequestError::InvalidUsername(e) => write!(f, "invalid username: {}", e),
+            TokenRequestError::NameTaken(username) => write!(f, "the name {} is already taken", username),
+            TokenRequestError::Rejected(reason) => write!(f, "the server rejected us: {}", reason),
+            TokenRequestError::ServerFull => write!(f, "the server is full"),
+            TokenRequestError::VersionMismatch { server_version, server_rules_hash } if server_version == GAME_VERSION => write!(
+                f, "the server is running different game rules or netcode ({:016x}, ours are {:016x})", server_rules_hash, rules_hash()
+            ),
+            TokenRequestError::VersionMismatch { server_version, .. } => write!(
+                f, "the server is running version {}, we are running {}. Please update to the same version", server_version, GAME_VERSION
+            ),
+            TokenRequestError::Client(e) => write!(f, "could not set up the connection to the game server: {}", e),
+        }
+    }
+}
+
+impl TokenRequestError {
+    /// Whether asking again could go any differently.
+    /// A taken name can come free, whoever has it might leave or never use their token.
+    fn is_permanent(&self) -> bool {
+        matches!(self, TokenRequestError::InvalidUsername(_) | TokenRequestError::Rejected(_) | TokenRequestError::VersionMismatch { .. })
+    }
+}
+
+impl From<std::io::Error> for TokenRequestError {
+    fn from(e: std::io::Error) -> Self {
+        TokenRequestError::Io(e)
+    }
+}
+
+impl From<bincode::Error> for TokenRequestError {
+    fn from(e: bincode::Error) -> Self {
+        TokenRequestError::Decode(e)
+    }
+}
+
+impl From<NetcodeError> for TokenRequestError {
+    fn from(e: NetcodeError) -> Self {
+        TokenRequestError::InvalidToken(e)
+    }
+}
+
+/// Asks the server's token service for a connect token.
+fn request_token(settings: &ClientSettings, id: u64, instance: u64) -> TokenResult {
+    // No point bothering the server with a name it will refuse.
+    let username = validate_username(&settings.username).map_err(TokenRequestError::InvalidUsername)?;
+    let message = ClientMessagesTcp::AuthenticationRequest {
+        id,
+        username,
+        instance,
+        version: GAME_VERSION.to_string(),
+        rules_hash: rules_hash(),
+    };
+    exchange_token(settings, &message)
+}
+
+/// Asks the token service for a connect token to get back into the match we dropped out of.
+fn request_resume(settings: &ClientSettings, id: u64, resume_token: ResumeToken) -> TokenResult {
+    let message = ClientMessagesTcp::ResumeRequest {
+        id,
+        resume_token,
+        version: GAME_VERSION.to_string(),
+        rules_hash: rules_hash(),
+    };
+    exchange_token(settings, &message)
+}
+
+/// Asks the token service for a connect token on another thread. Resumes the session we had if there's a resume token.
+fn start_token_request(settings: &ClientSettings, instance: u64, resume_token: Option<ResumeToken>) -> Receiver<TokenResult> {
+    let (sender, receiver) = mpsc::channel();
+    let settings = settings.clone();
+    thread::spawn(move || {
+        // Every connection gets a new id, the token service ties the new one to our old paddle when resuming.
+        let id = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_millis() as u64;
+        let result = match resume_token {
+            Some(resume_token) => request_resume(&settings, id, resume_token),
+            None => request_token(&settings, id, instance),
+        };
+        // If the game closed in the meantime there's nobody to tell.
+        let _ = sender.send(result);
+    });
+    receiver
+}
+
+/// Sends a request to the token service and reads back its answer.
+/// Starts the network simulator in front of the server the token is for, if there's meant to be one.
+fn exchange_token(settings: &ClientSettings, message: &ClientMessagesTcp) -> TokenResult {
+    let sockaddr: SocketAddr = settings.token_service_addr()?;
+    let mut stream = TcpStream::connect_timeout(&sockaddr, TCP_TIMEOUT)?;
+    stream.set_read_timeout(Some(TCP_TIMEOUT))?;
+    stream.set_write_timeout(Some(TCP_TIMEOUT))?;
+
+    write_tcp_message(&mut stream, message)?;
+    match read_tcp_message(&mut stream)? {
+        ServerMessagesTcp::TokenGranted { mut token, resume_token } => {
+            let net_sim = settings.net_sim.and_then(|conditions| match NetSimProxy::in_front_of_token(&mut token, conditions) {
+                Ok(proxy) => Some(proxy),
+                Err(e) => {
+                    println!("Could not start the network simulator, connecting directly: {}", e);
+                    None
+                }
+            });
+            Ok(Granted { token: ConnectToken::read(&mut token.as_slice())?, resume_token, net_sim })
+        }
+        ServerMessagesTcp::Rejected { reason } => Err(TokenRequestError::Rejected(reason)),
+        ServerMessagesTcp::ServerFull => Err(TokenRequestError::ServerFull),
+        ServerMessagesTcp::NameTaken { username } => Err(TokenRequestError::NameTaken(username)),
+        ServerMessagesTcp::VersionMismatch { server_version, server_rules_hash } => Err(TokenRequestError::VersionMismatch { server_version, server_rules_hash }),
+    }
 }
 
 fn main() {
-    //Get our token first.
-    let sockaddr: SocketAddr = "45.33.33.109:5000".parse().unwrap();
-    let mut stream = TcpStream::connect(sockaddr).unwrap();
-    let current_time = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap();
-    let id = current_time.as_millis() as u64;
+    if std::env::args().any(|arg| arg == "--help" || arg == "-h") {
+        println!("Usage: client [--config <file>] [--setting value]...\n{}", usage::<ClientSettings>());
+        return;
+    }
+    let settings = match ClientSettings::load() {
+        Ok(settings) => settings,
+        Err(e) => {
+            eprintln!("Failed to load configuration: {}", e);
+            std::process::exit(1);
+        }
+    };
 
-    //let auth_request = ClientMessages::AuthenticationRequest { id };
-    //let auth_request_bytes = bincode::serialize(&auth_request).unwrap();
-    //let client_id_bytes: [u8; 8] = id.to_be_bytes();
-    //stream.write(&client_id_bytes).unwrap();
-    let message = ClientMessagesTcp::AuthenticationRequest { id, username: format!("TestUsername") };
-    bincode::serialize_into(&mut stream, &message).unwrap();
-    let token = ConnectToken::read(&mut stream).unwrap();
+    // No point opening a window to tell them the server won't take this name.
+    if let Err(e) = validate_username(&settings.username) {
+        eprintln!("Could not join the game: invalid username: {}", e);
+        std::process::exit(1);
+    }
+    if let Some(conditions) = settings.net_sim {
+        println!("Simulating network conditions: {}", conditions);
+    }
 
     let mut app = App::new();
 
@@ -66,65 +353,442 @@ fn main() {
         close_when_requested:false,
         ..default()
     });
-    app.insert_resource(ClientSide(PlayerSide::Left));
+    app.insert_resource(ClientRole::Connecting);
 
     app.add_plugins(DefaultPlugins);
 
     app.add_plugin(RenetClientPlugin);
-    app.insert_resource(new_renet_client(token));
+    // There's no RenetClient until reconnect_system gets a connect token.
+    app.add_loopless_state(ConnectionState::Connecting);
+    app.insert_resource(Session { instance: rand::random(), resume_token: None, quitting: false });
+    app.insert_resource(Reconnect::default());
+    app.add_event::<ConnectionLost>();
+    app.add_system(reconnect_system.run_in_state(ConnectionState::Connecting));
+    app.add_enter_system(ConnectionState::Menu, setup_menu);
+    app.add_system(menu_input.run_in_state(ConnectionState::Menu));
+    app.add_exit_system(ConnectionState::Menu, despawn_menu);
+    app.insert_resource(settings);
     app.insert_resource(PlayerInput::default());
-    app.insert_resource(ServInput(PlayerInput::default()));
+    app.insert_resource(InputHistory::default());
+    app.insert_resource(SnapshotBuffer::default());
+    app.insert_resource(DeltaDecoder::default());
+    app.insert_resource(PendingEvents::default());
+    app.insert_resource(BannerMessage { text: String::new(), timer: Timer::from_seconds(0.0, false) });
+    app.insert_resource(PlayerNames::default());
+    app.insert_resource(MatchCountdown::default());
     app.insert_resource(SendTimer(Timer::from_seconds(POLL_RATE, true)));
     app.add_system(player_input);
     app.add_system(client_send_input.with_run_criteria(run_if_client_connected));
     app.add_system(client_sync_players.with_run_criteria(run_if_client_connected));
     app.add_system(on_exit);
-    app.add_system(local_move);
+    app.insert_resource(ServerLink::default());
+    app.add_system(client_heartbeat.with_run_criteria(run_if_client_connected));
+    app.add_system(interpolate_snapshots);
+    app.add_system(play_game_events);
+    app.add_startup_system(setup_event_banner);
+    app.add_system(update_event_banner);
+    app.add_system(update_name_labels);
+    app.add_startup_system(setup_role_hud);
+    app.add_system(tick_match_countdown);
+    app.add_system(update_role_hud);
+    // Rollback matches move on a frame at a time, the same rate the server simulates at.
+    app.add_stage_before(
+        CoreStage::Update,
+        "rollback",
+        FixedTimestepStage::new(Duration::from_secs_f32(TIME_STEP))
+            .with_stage(SystemStage::single_threaded().with_system(rollback_system))
+    );
 
     // Gets game systems and resources from common_game.rs
     app = add_to_app_client(app);
-    app.add_system(panic_on_error_system);
+    app.add_system(detect_connection_loss.run_in_state(ConnectionState::Online));
     app.run();
 }
 
 /// Recieves information from the server and synchronizes the client.
+#[allow(clippy::too_many_arguments)]
 fn client_sync_players(
+    mut commands: Commands,
     mut client: ResMut<RenetClient>,
-    mut ball: Query<(&mut Transform, &mut Velocity), (With<Ball>,Without<Paddle>)>, 
     mut paddles: Query<(&mut Transform,&PaddleSide), With<Paddle>>, 
-    mut scoreboard: ResMut<Scoreboard>,
-    mut playing: ResMut<Playing>,
-    mut cside: ResMut<ClientSide>,
+    mut role: ResMut<ClientRole>,
+    mut names: ResMut<PlayerNames>,
+    mut countdown: ResMut<MatchCountdown>,
+    mut history: ResMut<InputHistory>,
+    mut snapshots: ResMut<SnapshotBuffer>,
+    mut decoder: ResMut<DeltaDecoder>,
+    mut pending_events: ResMut<PendingEvents>,
+    mut banner: ResMut<BannerMessage>,
+    mut rollback: Option<ResMut<RollbackSession>>,
+    settings: Res<ClientSettings>,
+    time: Res<Time>,
 ) {
     // Recieving specific messages from the server.
     while let Some(message) = client.receive_message(0) {
-        let server_message = bincode::deserialize(&message).unwrap();
+        // A server that sends something we can't read is probably newer than us, so skip it rather than fall over.
+        let server_message = match bincode::deserialize(&message) {
+            Ok(server_message) => server_message,
+            Err(e) => {
+                println!("Dropped a message from the server we couldn't read: {}", e);
+                continue;
+            }
+        };
         match server_message {
-            ServerMessages::PlayerConnected { id } => {
-                // Simply relay player connected to the console for debugging.
-                println!("Player {} connected.", id);
+            ServerMessages::PlayerConnected { id, username, side } => {
+                // Relay player connected to the console for debugging, and remember players' names for the scoreboard.
+                match side {
+                    Some(side) => {
+                        println!("Player {} ({}) connected.", id, username);
+                        names.0.insert(id, (username, side));
+                    }
+                    None => println!("{} ({}) is spectating.", username, id),
+                }
             }
+            ServerMessages::PlayerReconnecting { id, grace } => {
+                // The match is on hold until they're back, or until the server gives up on them.
+                let name = names.0.get(&id).map_or_else(|| format!("Player {}", id), |(name, _)| name.clone());
+                println!("{} dropped out, waiting up to {} seconds for them.", name, grace);
+                *banner = BannerMessage { text: format!("Waiting for {}", name), timer: Timer::from_seconds(grace, false) };
+            },
             ServerMessages::PlayerDisconnected { id } => {
                 // Simply relay player disconnected to the console for debugging.
                 println!("Player {} disconnected.", id);
+                names.0.remove(&id);
             },
-            ServerMessages::PlayerCheck => {
-                // Server wants to check that we are still here. Send an appropriate response.
-                let message = bincode::serialize(&ClientMessages::PlayerCheckResponse { id: client.client_id() }).unwrap();
-                client.send_message(2, message);
+            ServerMessages::Ping { .. } => {
+                // Pings come on their own channel, see client_heartbeat.
             },
             ServerMessages::PlayerIsSide { side } => {
-                cside.0 = side;
+                *role = ClientRole::Player(side);
+            },
+            ServerMessages::PlayerIsSpectator => {
+                // We've been moved to a different room to watch, so the names and snapshots we had are for the wrong players.
+                names.0.clear();
+                snapshots.clear();
+                pending_events.0.clear();
+                commands.remove_resource::<RollbackSession>();
+            },
+            ServerMessages::QueuePosition { position } => {
+                *role = ClientRole::Queued { position };
+            },
+            ServerMessages::MatchFound { opponent } => {
+                match opponent {
+                    Some(name) => println!("Match found against {}.", name),
+                    None => println!("Match found, waiting for an opponent."),
+                }
+                // The roster for our new room comes right after this.
+                names.0.clear();
+                snapshots.clear();
+                pending_events.0.clear();
+                countdown.0 = None;
+                // It's a new paddle, so the server starts counting our inputs from scratch.
+                *history = InputHistory::default();
+                commands.remove_resource::<RollbackSession>();
+            },
+            ServerMessages::MatchStarting { countdown: secs } => {
+                countdown.0 = Some(Timer::from_seconds(secs, false));
+            },
+            ServerMessages::GameEvent { event } => {
+                pending_events.0.push(event);
             },
         }
     }
 
+    // Rollback matches, which we simulate ourselves from our opponent's inputs. See rollback_system.
+    // A new match replaces the old one once this system's done, so inputs that came in right behind it go to the new one.
+    let mut started = None;
+    while let Some(message) = client.receive_message(ROLLBACK_CHANNEL) {
+        let rollback_message = match bincode::deserialize(&message) {
+            Ok(rollback_message) => rollback_message,
+            Err(e) => {
+                println!("Dropped a rollback message from the server we couldn't read: {}", e);
+                continue;
+            }
+        };
+        match rollback_message {
+            RollbackMessage::Start { round, seed, side } => {
+                println!("Starting a rollback match as {:?} with seed {}.", side, seed);
+                *role = ClientRole::Player(side);
+                started = Some(RollbackSession::new(side, round, seed, settings.input_delay_frames));
+            }
+            RollbackMessage::Input { round, frame, input } => {
+                if let Some(session) = started.as_mut().or(rollback.as_deref_mut()) {
+                    if session.round == round {
+                        session.add_remote(frame, input);
+                    }
+                }
+            }
+            RollbackMessage::MatchOver { .. } => (),
+        }
+    }
+    if let Some(session) = started {
+        commands.insert_resource(session);
+    }
+
     // This is where we recieve information pertaining to the actual state of the game.
-    // The information is contained within the GameState struct, 
-    // and the logic to use that information is in common_game.rs
+    // The information is contained within the GameState struct, sent as a delta against one we've acknowledged (see common_wire.rs).
+    // It goes into the snapshot buffer, and interpolate_snapshots shows it a little later.
+    let mut recieved = false;
+    let mut decoded = false;
     while let Some(message) = client.receive_message(1) {
-        let gamestate: GameState = bincode::deserialize(&message).unwrap();
-        set_gamestate(&mut ball, &mut paddles, &mut scoreboard, &mut playing, gamestate);
+        let gamestate = match decoder.decode(&message) {
+            Ok((_, gamestate)) => gamestate,
+            Err(e) => {
+                // The server will send the next one against something we do have once it hears what we've got.
+                println!("Dropped a GameState: {}", e);
+                continue;
+            }
+        };
+        decoded = true;
+        recieved |= snapshots.insert(gamestate, time.seconds_since_startup());
+    }
+    if decoded {
+        if let Some(sequence) = decoder.newest() {
+            client.send_message(1, bincode::serialize(&SnapshotAck { sequence }).unwrap());
+        }
+    }
+
+    // Our own paddle doesn't wait though. Take where the server last had it and replay whatever the server hasn't seen yet on top.
+    let side = match *role {
+        ClientRole::Player(side) => side,
+        _ => return,
+    };
+    let newest = match snapshots.newest() {
+        Some(newest) if recieved && newest.last_input >= history.acknowledged => newest,
+        _ => return,
+    };
+    history.acknowledge(newest.last_input);
+    let server_y = match side {
+        PlayerSide::Left => newest.paddle_l_loc.y,
+        PlayerSide::Right => newest.paddle_r_loc.y,
+    };
+    for (mut transform, paddle_side) in paddles.iter_mut() {
+        if paddle_side.0 == side {
+            transform.translation.y = history.replay(server_y);
+        }
+    }
+}
+
+/// Puts the ball and the other paddle where the snapshots say they were, interp_delay_ms ago.
+/// Our own paddle is left where prediction put it.
+#[allow(clippy::too_many_arguments, clippy::type_complexity)]
+fn interpolate_snapshots(
+    time: Res<Time>,
+    snapshots: Res<SnapshotBuffer>,
+    settings: Res<ClientSettings>,
+    role: Res<ClientRole>,
+    rollback: Option<Res<RollbackSession>>,
+    mut ball: Query<(&mut Transform, &mut Velocity), (With<Ball>,Without<Paddle>)>, 
+    mut paddles: Query<(&mut Transform,&PaddleSide), With<Paddle>>, 
+    mut room: Query<(&mut Scoreboard, &mut Playing), With<Room>>,
+) {
+    // We're simulating the room ourselves, there's nothing to show from the server.
+    if rollback.is_some() {
+        return;
+    }
+    let delay = settings.interp_delay_ms as f64 / 1000.0;
+    let render_time = match snapshots.render_time(time.seconds_since_startup(), delay) {
+        Some(render_time) => render_time,
+        None => return,
+    };
+    let gamestate = match snapshots.sample(render_time, settings.max_extrapolation_ms as f64 / 1000.0) {
+        Some(gamestate) => gamestate,
+        None => return,
+    };
+
+    let own_paddle = match *role {
+        ClientRole::Player(side) => paddles.iter().find(|(_, paddle_side)| paddle_side.0 == side).map(|(transform, _)| (side, transform.translation.y)),
+        _ => None,
+    };
+    let (mut scoreboard, mut playing) = room.single_mut();
+    set_gamestate(&mut ball, &mut paddles, &mut scoreboard, &mut playing, gamestate);
+    if let Some((side, y)) = own_paddle {
+        for (mut transform, paddle_side) in paddles.iter_mut() {
+            if paddle_side.0 == side {
+                transform.translation.y = y;
+            }
+        }
+    }
+}
+
+/// Plays out the server's GameEvents once the snapshots we're showing reach the tick they happened on,
+/// so a bounce is heard when the ball is seen to bounce rather than when the message turned up.
+fn play_game_events(
+    time: Res<Time>,
+    settings: Res<ClientSettings>,
+    snapshots: Res<SnapshotBuffer>,
+    names: Res<PlayerNames>,
+    mut pending: ResMut<PendingEvents>,
+    mut banner: ResMut<BannerMessage>,
+    mut collision_events: EventWriter<CollisionEvent>,
+) {
+    let render_time = snapshots.render_time(time.seconds_since_startup(), settings.interp_delay_ms as f64 / 1000.0);
+    let name_of = |side: PlayerSide| match names.0.values().find(|(_, s)| *s == side) {
+        Some((name, _)) => name.clone(),
+        None => format!("{:?}", side),
+    };
+    let mut i = 0;
+    while i < pending.0.len() {
+        let event = pending.0[i];
+        // Without any snapshots there's nothing to line them up with, so just play them.
+        let due = match (render_time, snapshots.time_of_tick(event.tick())) {
+            (Some(render_time), Some(event_time)) => event_time <= render_time,
+            _ => true,
+        };
+        if !due {
+            i += 1;
+            continue;
+        }
+        pending.0.remove(i);
+        match event {
+            GameEvent::PaddleHit { .. } | GameEvent::WallBounce { .. } => collision_events.send_default(),
+            GameEvent::GoalScored { side, .. } => {
+                collision_events.send_default();
+                *banner = BannerMessage { text: format!("Point to {}", name_of(side)), timer: Timer::from_seconds(GOAL_BANNER_SECONDS, false) };
+            }
+            GameEvent::MatchOver { winner, .. } => {
+                *banner = BannerMessage { text: format!("{} wins!", name_of(winner)), timer: Timer::from_seconds(WINNER_BANNER_SECONDS, false) };
+            }
+            GameEvent::Serve { .. } => (),
+        }
+    }
+}
+
+/// Spawns the text used to announce goals and winners. It starts out empty.
+fn setup_event_banner(mut commands: Commands, asset_server: Res<AssetServer>) {
+    commands.spawn_bundle(
+        TextBundle::from_section(
+            "",
+            TextStyle {
+                font: asset_server.load("fonts/FiraSans-Bold.ttf"),
+                font_size: BANNER_FONT_SIZE,
+                color: BANNER_COLOR,
+            },
+        )
+        .with_style(Style {
+            position_type: PositionType::Absolute,
+            position: UiRect {
+                top: Val::Percent(30.0),
+                left: Val::Percent(35.0),
+                ..default()
+            },
+            ..default()
+        }),
+    ).insert(EventBanner);
+}
+
+fn update_event_banner(time: Res<Time>, mut banner: ResMut<BannerMessage>, mut text: Query<&mut Text, With<EventBanner>>) {
+    if banner.timer.tick(time.delta()).just_finished() {
+        banner.text.clear();
+    }
+    for mut text in text.iter_mut() {
+        if text.sections[0].value != banner.text {
+            text.sections[0].value = banner.text.clone();
+        }
+    }
+}
+
+/// Answers the server's pings, and gives up on the connection if it stops sending them.
+fn client_heartbeat(
+    mut client: ResMut<RenetClient>,
+    mut link: ResMut<ServerLink>,
+    mut lost: EventWriter<ConnectionLost>,
+    settings: Res<ClientSettings>,
+    time: Res<Time>,
+) {
+    let now = time.seconds_since_startup();
+    let link = link.0.get_or_insert_with(|| LinkStats::new(now));
+    while let Some(message) = client.receive_message(HEARTBEAT_CHANNEL) {
+        link.heard(now);
+        // Anything we can't read still tells us the server's there, which is all we need it for.
+        if let Ok(ServerMessages::Ping { sequence, sent_at }) = bincode::deserialize::<ServerMessages>(&message) {
+            let view_delay_ms = settings.interp_delay_ms as u32;
+            let message = bincode::serialize(&ClientMessages::Pong { sequence, sent_at, view_delay_ms }).unwrap();
+            client.send_message(HEARTBEAT_CHANNEL, message);
+        }
+    }
+    if link.idle_for(now) > settings.idle_timeout_secs as f64 {
+        println!("Haven't heard from the server in {} seconds.", settings.idle_timeout_secs);
+        client.disconnect();
+        lost.send(ConnectionLost);
+    }
+}
+
+/// Labels each score with the name of the player on that side, once we know it.
+fn update_name_labels(names: Res<PlayerNames>, mut query: Query<(&mut Text, &ScoreSide)>) {
+    if !names.is_changed() {
+        return;
+    }
+    for (mut text, score_side) in query.iter_mut() {
+        let (side, default_label) = match score_side.0 {
+            ScoringSide::Left => (PlayerSide::Left, "Score p1: "),
+            ScoringSide::Right => (PlayerSide::Right, "Score p2: "),
+        };
+        text.sections[0].value = match names.0.values().find(|(_, s)| *s == side) {
+            Some((name, _)) => format!("{}: ", name),
+            None => default_label.to_string(),
+        };
+    }
+}
+
+/// Spawns the text that tells the player what they're doing. It starts out empty.
+fn setup_role_hud(mut commands: Commands, asset_server: Res<AssetServer>) {
+    commands.spawn_bundle(
+        TextBundle::from_section(
+            "",
+            TextStyle {
+                font: asset_server.load("fonts/FiraSans-Bold.ttf"),
+                font_size: HUD_FONT_SIZE,
+                color: HUD_COLOR,
+            },
+        )
+        .with_style(Style {
+            position_type: PositionType::Absolute,
+            position: UiRect {
+                bottom: Val::Percent(5.0),
+                left: Val::Percent(5.0),
+                ..default()
+            },
+            ..default()
+        }),
+    ).insert(RoleHud);
+}
+
+fn tick_match_countdown(time: Res<Time>, mut countdown: ResMut<MatchCountdown>) {
+    let finished = match countdown.0.as_mut() {
+        Some(timer) => timer.tick(time.delta()).finished(),
+        None => return,
+    };
+    if finished {
+        countdown.0 = None;
+    }
+}
+
+fn update_role_hud(
+    role: Res<ClientRole>,
+    countdown: Res<MatchCountdown>,
+    reconnect: Res<Reconnect>,
+    state: Res<CurrentState<ConnectionState>>,
+    mut hud: Query<&mut Text, With<RoleHud>>,
+) {
+    if !role.is_changed() && !countdown.is_changed() && !reconnect.is_changed() && !state.is_changed() {
+        return;
+    }
+    let message = match (*role, &countdown.0) {
+        // The menu says it all.
+        _ if state.0 == ConnectionState::Menu => String::new(),
+        // Waiting to try again after a failed attempt.
+        (ClientRole::Connecting, _) if state.0 == ConnectionState::Connecting && reconnect.pending.is_none() && reconnect.attempt > 0 => {
+            let left = (reconnect.timer.duration() - reconnect.timer.elapsed()).as_secs_f32().ceil();
+            format!("Connection lost: {}. Retrying in {}", reconnect.reason, left)
+        }
+        (ClientRole::Connecting, _) => "Connecting...".to_string(),
+        (ClientRole::Queued { position }, _) => format!("Waiting for a match - #{} in the queue", position),
+        (ClientRole::Player(_), Some(timer)) => format!("Match starting in {}", (timer.duration() - timer.elapsed()).as_secs_f32().ceil()),
+        (ClientRole::Player(_), None) => String::new(),
+    };
+    for mut text in hud.iter_mut() {
+        text.sections[0].value = message.clone();
     }
 }
 
@@ -136,62 +800,232 @@ fn player_input(keyboard_input: Res<Input<KeyCode>>, mut player_input: ResMut<Pl
     player_input.down = keyboard_input.pressed(KeyCode::S) || keyboard_input.pressed(KeyCode::Down);
 }
 
-/// We send our input and the server moves us. 
-/// Makes things easier since the client does not need to keep track of which paddle it represents.
-/// Has potential for issues if packet loss is high.
+/// We send our input and the server moves us, but we don't wait for it.
+/// Each input is numbered and applied to our paddle straight away, the same way the server will apply it.
+/// When the server's positions come back, client_sync_players replays anything it hasn't seen yet.
+#[allow(clippy::too_many_arguments)]
 fn client_send_input(
     player_input: Res<PlayerInput>, 
     mut client: ResMut<RenetClient>,
-    mut serv_input: ResMut<ServInput>,
+    mut history: ResMut<InputHistory>,
+    mut paddles: Query<(&mut Transform, &PaddleSide), With<Paddle>>,
+    role: Res<ClientRole>,
+    rollback: Option<Res<RollbackSession>>,
     time:Res<Time>, 
     mut timer: ResMut<SendTimer>,
 ) {
-    if timer.0.tick(time.delta()).just_finished() {
-        let input_message = bincode::serialize(&*player_input).unwrap();
+    // In rollback matches our inputs go to the opponent instead, see rollback_system.
+    if rollback.is_some() {
+        return;
+    }
+    // Spectators have nothing to steer.
+    let side = match *role {
+        ClientRole::Player(side) => side,
+        _ => return,
+    };
+    // Every input only moves us for POLL_RATE, so below 60 FPS we send one for each step that went by this frame, or we'd slow down.
+    // Not more than the server lets a paddle catch up on though, anything past that would only queue up there and lag us behind.
+    let steps = timer.0.tick(time.delta()).times_finished_this_tick().min((MAX_INPUT_CATCHUP / POLL_RATE) as u32);
+    for _ in 0..steps {
+        let input_message = history.record(*player_input);
+        client.send_message(0, bincode::serialize(&input_message).unwrap());
 
-        client.send_message(0, input_message);
-        serv_input.0 = *player_input;
+        // Predict where that input puts us.
+        for (mut transform, paddle_side) in paddles.iter_mut() {
+            if paddle_side.0 == side {
+                transform.translation.y = move_paddle(transform.translation.y, &input_message.input, POLL_RATE);
+            }
+        }
     }
 }
 
-fn local_move(
-    serv_input: Res<ServInput>,
-    side: Res<ClientSide>,
+/// Runs a frame of our rollback match, see common_rollback.rs, then shows the match as it now stands.
+#[allow(clippy::type_complexity)]
+fn rollback_system(
+    session: Option<ResMut<RollbackSession>>,
+    input: Res<PlayerInput>,
+    client: Option<ResMut<RenetClient>>,
+    mut pending_events: ResMut<PendingEvents>,
+    mut ball: Query<(&mut Transform, &mut Velocity), (With<Ball>,Without<Paddle>)>,
     mut paddles: Query<(&mut Transform,&PaddleSide), With<Paddle>>,
-    time:Res<Time>,
+    mut rooms: Query<(&mut Scoreboard, &mut Playing), With<Room>>,
 ) {
-    //Input mimics what the server would see, only updating when we send packets.
-    let input = &serv_input.0;
+    let mut session = match session {
+        Some(session) => session,
+        None => return,
+    };
+    pending_events.0.extend(session.update(*input));
 
-    //Get transform of our paddle
-    let mut iter = paddles.iter_mut();
-    let mut transform = loop {
-        let (paddle, paddle_side) = iter.next().unwrap();
-        if paddle_side.0 == side.0 {
-            break paddle;
+    let outgoing = session.take_outgoing();
+    if let Some(mut client) = client {
+        for message in outgoing {
+            client.send_message(ROLLBACK_CHANNEL, bincode::serialize(&message).unwrap());
         }
-    };
-    let y = (input.up as i8 - input.down as i8) as f32;
-    let bottom_bound = BOTTOM_WALL + WALL_THICKNESS / 2.0 + PADDLE_SIZE.y / 2.0 + PADDLE_PADDING;
-    let top_bound = TOP_WALL - WALL_THICKNESS / 2.0 - PADDLE_SIZE.y / 2.0 - PADDLE_PADDING;
-    let new_position = transform.translation.y + y * PADDLE_SPEED * time.delta().as_secs_f32();
-    transform.translation.y = new_position.clamp(bottom_bound,top_bound);
+    }
+
+    if let Ok((mut scoreboard, mut playing)) = rooms.get_single_mut() {
+        set_gamestate(&mut ball, &mut paddles, &mut scoreboard, &mut playing, session.sim().gamestate());
+    }
 }
 
-/// If any error is found we just panic. This could definitely be improved for more robustness.
-fn panic_on_error_system(mut renet_error: EventReader<RenetError>) {
+/// Any error from renet means the connection is gone, unless we're the ones closing it.
+/// Drops the RenetClient and everything we knew through it, and starts trying to get back in.
+fn detect_connection_loss(
+    mut commands: Commands,
+    mut renet_error: EventReader<RenetError>,
+    mut lost: EventReader<ConnectionLost>,
+    mut reconnect: ResMut<Reconnect>,
+    client: Option<Res<RenetClient>>,
+    session: Res<Session>,
+) {
+    // Once we're properly connected the attempts start counting from scratch again.
+    if client.is_some_and(|client| client.is_connected()) && reconnect.attempt != 0 {
+        reconnect.attempt = 0;
+    }
+    let mut gone = lost.iter().count() > 0;
     for e in renet_error.iter() {
-        panic!("{:?}",e);
-        //println!("{:?}",e);
+        println!("{:?}", e);
+        gone = true;
+    }
+    if !gone || session.quitting {
+        return;
+    }
+    println!("Lost the connection to the server, trying to get back in.");
+    reconnect.reason = "lost the connection to the server".to_string();
+    commands.remove_resource::<RenetClient>();
+    commands.insert_resource(ClientRole::Connecting);
+    commands.insert_resource(InputHistory::default());
+    commands.insert_resource(SnapshotBuffer::default());
+    commands.insert_resource(DeltaDecoder::default());
+    commands.insert_resource(PendingEvents::default());
+    commands.insert_resource(ServerLink::default());
+    commands.insert_resource(MatchCountdown::default());
+    commands.insert_resource(PlayerNames::default());
+    commands.remove_resource::<RollbackSession>();
+    let delay = reconnect_delay(reconnect.attempt);
+    reconnect.timer = Timer::from_seconds(delay, false);
+    commands.insert_resource(NextState(ConnectionState::Connecting));
+}
+
+/// Asks the token service for a connect token whenever the timer runs out, backing off after every failure.
+/// Tries to resume our old match first, if we had one.
+fn reconnect_system(
+    mut commands: Commands,
+    mut reconnect: ResMut<Reconnect>,
+    mut session: ResMut<Session>,
+    settings: Res<ClientSettings>,
+    time: Res<Time>,
+) {
+    let result = match &reconnect.pending {
+        Some(pending) => match pending.lock().unwrap().try_recv() {
+            Ok(result) => result,
+            Err(TryRecvError::Empty) => return,
+            Err(TryRecvError::Disconnected) => Err(TokenRequestError::Io(std::io::Error::other("the token request stopped"))),
+        },
+        None => {
+            if !reconnect.timer.tick(time.delta()).finished() {
+                return;
+            }
+            if reconnect.attempt >= settings.reconnect_attempts {
+                commands.insert_resource(NextState(ConnectionState::Menu));
+                return;
+            }
+            reconnect.attempt += 1;
+            reconnect.resuming = session.resume_token.is_some();
+            reconnect.pending = Some(Mutex::new(start_token_request(&settings, session.instance, session.resume_token)));
+            return;
+        }
+    };
+    reconnect.pending = None;
+
+    // A token we can't make a connection for is no better than none, so that's a failed attempt too.
+    let result = result.and_then(|Granted { token, resume_token, net_sim }| {
+        let client = new_renet_client(token).map_err(TokenRequestError::Client)?;
+        Ok((client, resume_token, net_sim))
+    });
+    match result {
+        Ok((client, resume_token, net_sim)) => {
+            session.resume_token = Some(resume_token);
+            // Replacing the last connection's simulator stops it.
+            match net_sim {
+                Some(proxy) => commands.insert_resource(proxy),
+                None => commands.remove_resource::<NetSimProxy>(),
+            }
+            commands.insert_resource(client);
+            commands.insert_resource(NextState(ConnectionState::Online));
+        }
+        Err(TokenRequestError::Rejected(reason)) if reconnect.resuming => {
+            // Our match is gone, so join the queue like anyone else. That doesn't count as a failed attempt.
+            println!("Could not resume our match: {}", reason);
+            session.resume_token = None;
+            reconnect.attempt -= 1;
+            reconnect.timer = Timer::from_seconds(0.0, false);
+        }
+        Err(e) => {
+            println!("Could not connect: {}", e);
+            reconnect.reason = e.to_string();
+            if e.is_permanent() {
+                commands.insert_resource(NextState(ConnectionState::Menu));
+                return;
+            }
+            let delay = reconnect_delay(reconnect.attempt);
+            reconnect.timer = Timer::from_seconds(delay, false);
+        }
+    }
+}
+
+/// Tells the player why we couldn't connect, and what they can do about it.
+fn setup_menu(mut commands: Commands, asset_server: Res<AssetServer>, reconnect: Res<Reconnect>) {
+    commands.spawn_bundle(
+        TextBundle::from_section(
+            format!("Could not connect: {}\nPress Enter to try again, or Escape to quit.", reconnect.reason),
+            TextStyle {
+                font: asset_server.load("fonts/FiraSans-Bold.ttf"),
+                font_size: HUD_FONT_SIZE,
+                color: HUD_COLOR,
+            },
+        )
+        .with_style(Style {
+            position_type: PositionType::Absolute,
+            position: UiRect {
+                top: Val::Percent(40.0),
+                left: Val::Percent(5.0),
+                ..default()
+            },
+            ..default()
+        }),
+    ).insert(MenuText);
+}
+
+fn menu_input(
+    mut commands: Commands,
+    keyboard_input: Res<Input<KeyCode>>,
+    mut reconnect: ResMut<Reconnect>,
+    mut exit: EventWriter<AppExit>,
+) {
+    if keyboard_input.just_pressed(KeyCode::Return) {
+        *reconnect = Reconnect::default();
+        commands.insert_resource(NextState(ConnectionState::Connecting));
+    } else if keyboard_input.just_pressed(KeyCode::Escape) {
+        exit.send(AppExit);
+    }
+}
+
+fn despawn_menu(mut commands: Commands, menu: Query<Entity, With<MenuText>>) {
+    for entity in menu.iter() {
+        commands.entity(entity).despawn_recursive();
     }
 }
 
 /// Checks if user tried to close window, then cleans up and actually closes it once cleanup is finished.
-fn on_exit(window_closed: EventReader<bevy::window::WindowCloseRequested>, mut client: ResMut<RenetClient>, mut windows: ResMut<Windows>){
+fn on_exit(window_closed: EventReader<bevy::window::WindowCloseRequested>, client: Option<ResMut<RenetClient>>, mut session: ResMut<Session>, mut windows: ResMut<Windows>){
     // User tried to close window. Cleanup first, then actually close it.
     if !window_closed.is_empty(){
-        //Disconnect first.
-        client.disconnect();
+        //Disconnect first, if there's anything to disconnect from.
+        session.quitting = true;
+        if let Some(mut client) = client {
+            client.disconnect();
+        }
         //Then close the window. App will exit shortly after this.
         windows.primary_mut().close();
     }
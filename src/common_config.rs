@@ -0,0 +1,603 @@
+//! Runtime configuration for both the server and the client.
+//! Settings are layered, with each layer overriding the one before it:
+//! built in defaults, then the TOML config file, then environment variables, then command line flags.
+//!
+//! Every setting has one name which is used in all of the layers.
+//! For example `udp_port` is `udp_port` in the config file, `PONG_UDP_PORT` in the environment and `--udp-port` on the command line.
+
+use std::{
+    env,
+    fmt,
+    fs,
+    net::{IpAddr, Ipv4Addr, SocketAddr, ToSocketAddrs},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::common_netsim::NetConditions;
+
+/// Config file that is read if it exists and no other file was asked for.
+pub const DEFAULT_CONFIG_FILE: &str = "pong.toml";
+/// Environment variable which can point at a config file.
+pub const CONFIG_FILE_ENV: &str = "PONG_CONFIG";
+/// Every environment variable we read starts with this.
+pub const ENV_PREFIX: &str = "PONG_";
+
+/// The protocol id both sides must agree on. Renet refuses connections with a different one.
+pub const DEFAULT_PROTOCOL_ID: u64 = 7;
+const DEFAULT_PORT: u16 = 5000;
+const DEFAULT_PUBLIC_IP: IpAddr = IpAddr::V4(Ipv4Addr::new(45, 33, 33, 109));
+
+/// Everything that can go wrong while loading the configuration.
+#[derive(Debug)]
+pub enum ConfigError {
+    /// The config file could not be read.
+    Io { path: PathBuf, error: std::io::Error },
+    /// The config file was read but isn't valid TOML, or has values of the wrong type.
+    Toml { path: PathBuf, error: toml::de::Error },
+    /// A flag or environment variable was given a value we can't use.
+    InvalidValue { key: String, value: String, reason: String },
+    /// A flag we don't know about was passed.
+    UnknownFlag(String),
+    /// A flag which needs a value was passed without one.
+    MissingValue(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io { path, error } => write!(f, "could not read config file {}: {}", path.display(), error),
+            ConfigError::Toml { path, error } => write!(f, "invalid config file {}: {}", path.display(), error),
+            ConfigError::InvalidValue { key, value, reason } => write!(f, "invalid value {:?} for {}: {}", value, key, reason),
+            ConfigError::UnknownFlag(flag) => write!(f, "unknown flag {}", flag),
+            ConfigError::MissingValue(flag) => write!(f, "flag {} needs a value", flag),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// The layout of the config file.
+/// Server and client settings live in their own tables, so one file can be shared by both.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct ConfigFile {
+    pub server: ServerSettings,
+    pub client: ClientSettings,
+}
+
+/// What the server does when a client sends something it shouldn't, or too much of anything.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AbusePolicy {
+    /// Throw the message away and say nothing.
+    Drop,
+    /// Throw it away and log it.
+    Warn,
+    /// Throw it away, log it, and disconnect clients that keep at it.
+    Kick,
+}
+
+impl FromStr for AbusePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "drop" => Ok(AbusePolicy::Drop),
+            "warn" => Ok(AbusePolicy::Warn),
+            "kick" => Ok(AbusePolicy::Kick),
+            _ => Err("expected drop, warn or kick".to_string()),
+        }
+    }
+}
+
+/// Who simulates matches.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NetcodeMode {
+    /// The server does, and sends everyone the result. Players predict their own paddle and see the rest a little in the past.
+    Server,
+    /// Both players do, from each other's inputs, going back and fixing things up when they guessed the other's input wrong.
+    /// See common_rollback.rs.
+    Rollback,
+}
+
+impl FromStr for NetcodeMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "server" => Ok(NetcodeMode::Server),
+            "rollback" => Ok(NetcodeMode::Rollback),
+            _ => Err("expected server or rollback".to_string()),
+        }
+    }
+}
+
+/// A group of settings which can be loaded through all of the layers.
+pub trait Layered: Sized {
+    /// Every key this group understands, used for the environment and for the help text.
+    const KEYS: &'static [&'static str];
+
+    /// Takes our section out of the config file.
+    fn from_file(file: ConfigFile) -> Self;
+
+    /// Overrides a single setting from its string form, as given by an environment variable or flag.
+    fn set(&mut self, key: &str, value: &str) -> Result<(), ConfigError>;
+
+    /// Checks settings that are only wrong together, once every layer is in.
+    fn validate(&self) -> Result<(), ConfigError> {
+        Ok(())
+    }
+}
+
+/// Settings for the server binary.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ServerSettings {
+    /// Address both the UDP game socket and the TCP token listener bind to.
+    pub bind_ip: IpAddr,
+    /// Address clients use to reach us. This is written into every connect token.
+    pub public_ip: IpAddr,
+    /// Port for the game traffic. With key rotation on, the port after it is used as well, see key_rotation_secs.
+    pub udp_port: u16,
+    /// Port for the token service.
+    pub tcp_port: u16,
+    /// How many clients renet will allow at once.
+    pub max_clients: usize,
+    /// How many matches can run at the same time.
+    pub max_rooms: usize,
+    pub protocol_id: u64,
+    /// File holding the private key connect tokens are signed with.
+    /// Without one a fresh key is made on every start, which invalidates every token handed out before.
+    pub key_file: Option<PathBuf>,
+    /// How often to replace the private key, in seconds. 0 turns rotation off.
+    /// Renet only checks one key per game server, so every key gets its own, and they take turns between udp_port and the port after it.
+    pub key_rotation_secs: u64,
+    /// How long the previous key's game server stays up after the last token signed with it was handed out, in seconds,
+    /// so those tokens can still be used. It also stays up for as long as anyone who connected with one is still playing.
+    pub key_grace_secs: u64,
+    /// How often every client gets pinged, in milliseconds.
+    pub heartbeat_interval_ms: u64,
+    /// Clients we haven't heard anything from in this many seconds are disconnected.
+    pub idle_timeout_secs: u64,
+    /// How long a player who drops out of a match has to come back before their paddle is given away, in seconds. 0 turns resuming off.
+    pub resume_grace_secs: u64,
+    /// Runs all game traffic through the network simulator, see common_netsim.rs. A preset name or `key=value` pairs.
+    pub net_sim: Option<NetConditions>,
+    /// How many messages a second a client may send us, across every channel. Anything over is dropped.
+    pub max_messages_per_sec: u32,
+    /// What to do about clients that send malformed messages, nonsense inputs or too many messages.
+    pub abuse_policy: AbusePolicy,
+    /// With the kick policy, how many offences a client gets away with before being disconnected. One is forgiven every second.
+    pub kick_after_offences: u32,
+    /// Paddle hits are judged on the ball as the player saw it, half their round trip plus their interpolation delay ago,
+    /// but never further back than this. 0 turns lag compensation off.
+    pub max_rewind_ms: u64,
+    /// Whether the server simulates matches, or the players do. Spectators can't watch rollback matches.
+    pub netcode: NetcodeMode,
+    /// Seeds every match's serves with this instead of a random number, so matches play out the same given the same inputs.
+    /// For debugging and tests. Every match's seed is logged when it starts.
+    pub seed: Option<u64>,
+}
+
+impl Default for ServerSettings {
+    fn default() -> Self {
+        ServerSettings {
+            bind_ip: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            public_ip: DEFAULT_PUBLIC_IP,
+            udp_port: DEFAULT_PORT,
+            tcp_port: DEFAULT_PORT,
+            max_clients: 64,
+            max_rooms: 32,
+            protocol_id: DEFAULT_PROTOCOL_ID,
+            key_file: None,
+            key_rotation_secs: 0,
+            key_grace_secs: 120,
+            heartbeat_interval_ms: 250,
+            idle_timeout_secs: 10,
+            resume_grace_secs: 30,
+            net_sim: None,
+            max_messages_per_sec: 240,
+            abuse_policy: AbusePolicy::Warn,
+            kick_after_offences: 20,
+            max_rewind_ms: 250,
+            netcode: NetcodeMode::Server,
+            seed: None,
+        }
+    }
+}
+
+impl ServerSettings {
+    /// Loads the server settings from the file, the environment and the process arguments.
+    pub fn load() -> Result<Self, ConfigError> {
+        load(env::args().skip(1))
+    }
+
+    /// The address written into connect tokens.
+    pub fn public_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.public_ip, self.udp_port)
+    }
+
+    /// The game ports keys take turns on. Only the first is used without key rotation.
+    /// With it on, validate makes sure there is a port after udp_port.
+    pub fn udp_ports(&self) -> [u16; 2] {
+        [self.udp_port, self.udp_port.wrapping_add(1)]
+    }
+
+    pub fn udp_bind_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.bind_ip, self.udp_port)
+    }
+
+    pub fn tcp_bind_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.bind_ip, self.tcp_port)
+    }
+}
+
+impl Layered for ServerSettings {
+    const KEYS: &'static [&'static str] = &[
+        "bind_ip",
+        "public_ip",
+        "udp_port",
+        "tcp_port",
+        "max_clients",
+        "max_rooms",
+        "protocol_id",
+        "key_file",
+        "key_rotation_secs",
+        "key_grace_secs",
+        "heartbeat_interval_ms",
+        "idle_timeout_secs",
+        "resume_grace_secs",
+        "net_sim",
+        "max_messages_per_sec",
+        "abuse_policy",
+        "kick_after_offences",
+        "max_rewind_ms",
+        "netcode",
+        "seed",
+    ];
+
+    fn from_file(file: ConfigFile) -> Self {
+        file.server
+    }
+
+    fn set(&mut self, key: &str, value: &str) -> Result<(), ConfigError> {
+        match key {
+            "bind_ip" => self.bind_ip = parse(key, value)?,
+            "public_ip" => self.public_ip = parse(key, value)?,
+            "udp_port" => self.udp_port = parse(key, value)?,
+            "tcp_port" => self.tcp_port = parse(key, value)?,
+            "max_clients" => self.max_clients = parse(key, value)?,
+            "max_rooms" => self.max_rooms = parse(key, value)?,
+            "protocol_id" => self.protocol_id = parse(key, value)?,
+            // An empty value turns a key file set by a lower layer back off.
+            "key_file" => self.key_file = Some(PathBuf::from(value)).filter(|path| !path.as_os_str().is_empty()),
+            "key_rotation_secs" => self.key_rotation_secs = parse(key, value)?,
+            "key_grace_secs" => self.key_grace_secs = parse(key, value)?,
+            "heartbeat_interval_ms" => self.heartbeat_interval_ms = parse(key, value)?,
+            "idle_timeout_secs" => self.idle_timeout_secs = parse(key, value)?,
+            "resume_grace_secs" => self.resume_grace_secs = parse(key, value)?,
+            "net_sim" => self.net_sim = parse_net_sim(key, value)?,
+            "max_messages_per_sec" => self.max_messages_per_sec = parse(key, value)?,
+            "abuse_policy" => self.abuse_policy = parse(key, value)?,
+            "kick_after_offences" => self.kick_after_offences = parse(key, value)?,
+            "max_rewind_ms" => self.max_rewind_ms = parse(key, value)?,
+            "netcode" => self.netcode = parse(key, value)?,
+            // An empty value goes back to random seeds.
+            "seed" => self.seed = if value.trim().is_empty() { None } else { Some(parse(key, value)?) },
+            _ => return Err(ConfigError::UnknownFlag(flag_name(key))),
+        }
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        if self.key_rotation_secs > 0 && self.udp_port == u16::MAX {
+            return Err(ConfigError::InvalidValue {
+                key: "udp_port".to_string(),
+                value: self.udp_port.to_string(),
+                reason: "key rotation also needs the port after it".to_string(),
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Settings for the client binary.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ClientSettings {
+    /// Host name or IP of the server.
+    pub server_host: String,
+    /// Port of the server's token service.
+    /// The game port doesn't need to be configured here, the server puts it in our connect token.
+    pub tcp_port: u16,
+    pub username: String,
+    /// How far in the past the ball and the other paddle are shown, so there's usually a snapshot on either side to blend between.
+    /// Raise it on a jittery connection, lower it if the game feels laggy.
+    pub interp_delay_ms: u64,
+    /// How long the ball keeps moving on its own when snapshots stop arriving, before it freezes and waits for the server.
+    pub max_extrapolation_ms: u64,
+    /// Give up on the server if we haven't heard anything from it in this many seconds.
+    pub idle_timeout_secs: u64,
+    /// How many times in a row we try to get back to the server after losing it, before going back to the menu.
+    pub reconnect_attempts: u32,
+    /// Runs our game traffic through the network simulator, see common_netsim.rs. A preset name or `key=value` pairs.
+    pub net_sim: Option<NetConditions>,
+    /// In rollback matches, how many frames after it's pressed our input takes effect.
+    /// More means fewer corrections on a slow connection, but the paddle feels heavier.
+    pub input_delay_frames: u32,
+}
+
+impl Default for ClientSettings {
+    fn default() -> Self {
+        ClientSettings {
+            server_host: DEFAULT_PUBLIC_IP.to_string(),
+            tcp_port: DEFAULT_PORT,
+            username: "TestUsername".to_string(),
+            interp_delay_ms: 100,
+            max_extrapolation_ms: 250,
+            idle_timeout_secs: 10,
+            reconnect_attempts: 5,
+            net_sim: None,
+            input_delay_frames: 3,
+        }
+    }
+}
+
+impl ClientSettings {
+    /// Loads the client settings from the file, the environment and the process arguments.
+    pub fn load() -> Result<Self, ConfigError> {
+        load(env::args().skip(1))
+    }
+
+    /// Resolves the token service address. Host names are allowed, so this can hit DNS.
+    pub fn token_service_addr(&self) -> std::io::Result<SocketAddr> {
+        (self.server_host.as_str(), self.tcp_port)
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, format!("{} did not resolve to any address", self.server_host)))
+    }
+}
+
+impl Layered for ClientSettings {
+    const KEYS: &'static [&'static str] = &[
+        "server_host",
+        "tcp_port",
+        "username",
+        "interp_delay_ms",
+        "max_extrapolation_ms",
+        "idle_timeout_secs",
+        "reconnect_attempts",
+        "net_sim",
+        "input_delay_frames",
+    ];
+
+    fn from_file(file: ConfigFile) -> Self {
+        file.client
+    }
+
+    fn set(&mut self, key: &str, value: &str) -> Result<(), ConfigError> {
+        match key {
+            "server_host" => self.server_host = value.to_string(),
+            "tcp_port" => self.tcp_port = parse(key, value)?,
+            "username" => self.username = value.to_string(),
+            "interp_delay_ms" => self.interp_delay_ms = parse(key, value)?,
+            "max_extrapolation_ms" => self.max_extrapolation_ms = parse(key, value)?,
+            "idle_timeout_secs" => self.idle_timeout_secs = parse(key, value)?,
+            "reconnect_attempts" => self.reconnect_attempts = parse(key, value)?,
+            "net_sim" => self.net_sim = parse_net_sim(key, value)?,
+            "input_delay_frames" => self.input_delay_frames = parse(key, value)?,
+            _ => return Err(ConfigError::UnknownFlag(flag_name(key))),
+        }
+        Ok(())
+    }
+}
+
+/// Runs through every layer and returns the final settings, reading the config file and environment of this process.
+/// `args` should not include the program name.
+pub fn load<T: Layered>(args: impl IntoIterator<Item = String>) -> Result<T, ConfigError> {
+    load_from(args, |name| env::var(name).ok(), |path| match path {
+        Some(path) => read_config_file(path),
+        None if Path::new(DEFAULT_CONFIG_FILE).exists() => read_config_file(Path::new(DEFAULT_CONFIG_FILE)),
+        None => Ok(ConfigFile::default()),
+    })
+}
+
+/// Runs through every layer like `load`, with the environment variables coming from `env`,
+/// and the config file from `read_file`, which is given the path asked for by a flag or the environment, if one was.
+pub fn load_from<T: Layered>(
+    args: impl IntoIterator<Item = String>,
+    env: impl Fn(&str) -> Option<String>,
+    read_file: impl FnOnce(Option<&Path>) -> Result<ConfigFile, ConfigError>,
+) -> Result<T, ConfigError> {
+    let flags = parse_flags(args)?;
+
+    // Find the config file first, since it's the lowest layer but can be chosen by the higher ones.
+    let explicit_path = flags.iter()
+        .find(|(key, _)| key == "config")
+        .map(|(_, value)| PathBuf::from(value))
+        .or_else(|| env(CONFIG_FILE_ENV).map(PathBuf::from));
+    let mut settings = T::from_file(read_file(explicit_path.as_deref())?);
+
+    for key in T::KEYS {
+        if let Some(value) = env(&env_name(key)) {
+            settings.set(key, &value)?;
+        }
+    }
+
+    for (key, value) in flags.iter().filter(|(key, _)| key != "config") {
+        settings.set(key, value)?;
+    }
+
+    settings.validate()?;
+    Ok(settings)
+}
+
+/// Reads and parses a config file.
+pub fn read_config_file(path: &Path) -> Result<ConfigFile, ConfigError> {
+    let contents = fs::read_to_string(path).map_err(|error| ConfigError::Io { path: path.to_path_buf(), error })?;
+    toml::from_str(&contents).map_err(|error| ConfigError::Toml { path: path.to_path_buf(), error })
+}
+
+/// Turns `--some-flag value` and `--some-flag=value` into `("some_flag", "value")` pairs.
+/// A flag with no value after it is treated as `true`, which is how switches are passed.
+fn parse_flags(args: impl IntoIterator<Item = String>) -> Result<Vec<(String, String)>, ConfigError> {
+    let mut flags = Vec::new();
+    let mut args = args.into_iter().peekable();
+    while let Some(arg) = args.next() {
+        let flag = match arg.strip_prefix("--") {
+            Some(flag) if !flag.is_empty() => flag,
+            _ => return Err(ConfigError::UnknownFlag(arg)),
+        };
+        let (name, value) = match flag.split_once('=') {
+            Some((name, value)) => (name.to_string(), value.to_string()),
+            None => match args.peek() {
+                Some(next) if !next.starts_with("--") => (flag.to_string(), args.next().unwrap()),
+                _ if flag == "config" => return Err(ConfigError::MissingValue(arg)),
+                _ => (flag.to_string(), "true".to_string()),
+            },
+        };
+        flags.push((name.replace('-', "_"), value));
+    }
+    Ok(flags)
+}
+
+/// Parses a single value, turning the parse error into a ConfigError that says which setting was wrong.
+pub fn parse<T>(key: &str, value: &str) -> Result<T, ConfigError>
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    value.trim().parse().map_err(|e: T::Err| ConfigError::InvalidValue {
+        key: key.to_string(),
+        value: value.to_string(),
+        reason: e.to_string(),
+    })
+}
+
+/// Network conditions to simulate. `off` or an empty value turns the simulator back off.
+fn parse_net_sim(key: &str, value: &str) -> Result<Option<NetConditions>, ConfigError> {
+    match value.trim() {
+        "" | "off" => Ok(None),
+        _ => parse(key, value).map(Some),
+    }
+}
+
+/// `udp_port` -> `PONG_UDP_PORT`
+pub fn env_name(key: &str) -> String {
+    format!("{}{}", ENV_PREFIX, key.to_uppercase())
+}
+
+/// `udp_port` -> `--udp-port`
+pub fn flag_name(key: &str) -> String {
+    format!("--{}", key.replace('_', "-"))
+}
+
+/// A short description of every way to set the given settings, printed for `--help`.
+pub fn usage<T: Layered>() -> String {
+    let mut text = format!(
+        "Settings are read from {} (or the file given by --config / {}), then the environment, then flags.\n",
+        DEFAULT_CONFIG_FILE, CONFIG_FILE_ENV
+    );
+    for key in T::KEYS {
+        text.push_str(&format!("  {:<24} {:<28} {}\n", flag_name(key), env_name(key), key));
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    /// Loads server settings from `toml` as the config file, `env` as the environment and `args` as the flags.
+    fn load_server(toml: &str, env: &[(&str, &str)], args: &[&str]) -> Result<ServerSettings, ConfigError> {
+        let env: HashMap<String, String> = env.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect();
+        let args = args.iter().map(|arg| arg.to_string());
+        load_from(args, |name| env.get(name).cloned(), |_| {
+            toml::from_str(toml).map_err(|error| ConfigError::Toml { path: PathBuf::from("pong.toml"), error })
+        })
+    }
+
+    #[test]
+    fn every_layer_overrides_the_one_before() {
+        let toml = "[server]\nudp_port = 6000\ntcp_port = 6001\nmax_rooms = 8\n";
+        let env = [("PONG_TCP_PORT", "7001"), ("PONG_MAX_ROOMS", "9")];
+        let args = ["--max-rooms", "10"];
+
+        let settings = load_server("", &[], &[]).unwrap();
+        assert_eq!((settings.udp_port, settings.tcp_port, settings.max_rooms), (DEFAULT_PORT, DEFAULT_PORT, 32));
+        let settings = load_server(toml, &[], &[]).unwrap();
+        assert_eq!((settings.udp_port, settings.tcp_port, settings.max_rooms), (6000, 6001, 8));
+        let settings = load_server(toml, &env, &[]).unwrap();
+        assert_eq!((settings.udp_port, settings.tcp_port, settings.max_rooms), (6000, 7001, 9));
+        let settings = load_server(toml, &env, &args).unwrap();
+        assert_eq!((settings.udp_port, settings.tcp_port, settings.max_rooms), (6000, 7001, 10));
+        // Anything a layer doesn't mention is left as the layer before had it.
+        assert_eq!(settings.max_clients, 64);
+    }
+
+    #[test]
+    fn flags_take_their_value_after_a_space_or_an_equals_sign() {
+        let settings = load_server("", &[], &["--udp-port=6000", "--netcode", "rollback", "--abuse-policy", "kick"]).unwrap();
+        assert_eq!(settings.udp_port, 6000);
+        assert_eq!(settings.netcode, NetcodeMode::Rollback);
+        assert_eq!(settings.abuse_policy, AbusePolicy::Kick);
+        // An empty value turns a setting from a lower layer back off.
+        let settings = load_server("[server]\nseed = 5\nkey_file = \"server.key\"\n", &[], &["--seed=", "--key-file="]).unwrap();
+        assert_eq!((settings.seed, settings.key_file), (None, None));
+    }
+
+    #[test]
+    fn the_config_file_can_be_chosen_by_flag_or_environment() {
+        let asked_for = |env: &[(&str, &str)], args: &[&str]| {
+            let env: HashMap<String, String> = env.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect();
+            let mut asked = None;
+            load_from::<ServerSettings>(args.iter().map(|arg| arg.to_string()), |name| env.get(name).cloned(), |path| {
+                asked = path.map(Path::to_path_buf);
+                Ok(ConfigFile::default())
+            }).unwrap();
+            asked
+        };
+        assert_eq!(asked_for(&[], &[]), None);
+        assert_eq!(asked_for(&[("PONG_CONFIG", "env.toml")], &[]), Some(PathBuf::from("env.toml")));
+        assert_eq!(asked_for(&[("PONG_CONFIG", "env.toml")], &["--config", "flag.toml"]), Some(PathBuf::from("flag.toml")));
+        assert!(matches!(load_server("", &[], &["--config"]), Err(ConfigError::MissingValue(flag)) if flag == "--config"));
+    }
+
+    #[test]
+    fn unknown_flags_are_refused() {
+        assert!(matches!(load_server("", &[], &["--udp-prot", "6000"]), Err(ConfigError::UnknownFlag(flag)) if flag == "--udp-prot"));
+        assert!(matches!(load_server("", &[], &["6000"]), Err(ConfigError::UnknownFlag(flag)) if flag == "6000"));
+        assert!(matches!(load_server("", &[], &["--"]), Err(ConfigError::UnknownFlag(flag)) if flag == "--"));
+        // The client's settings aren't the server's.
+        assert!(matches!(load_server("", &[], &["--username", "paddle"]), Err(ConfigError::UnknownFlag(_))));
+        // Environment variables we don't know are someone else's, and left alone.
+        assert!(load_server("", &[("PONG_UDP_PROT", "6000")], &[]).is_ok());
+    }
+
+    #[test]
+    fn bad_values_say_which_setting_they_were_for() {
+        let error = load_server("", &[], &["--udp-port", "70000"]).unwrap_err();
+        assert!(matches!(&error, ConfigError::InvalidValue { key, value, .. } if key == "udp_port" && value == "70000"));
+        assert!(error.to_string().contains("udp_port"));
+        let error = load_server("", &[("PONG_ABUSE_POLICY", "ban")], &[]).unwrap_err();
+        assert!(matches!(&error, ConfigError::InvalidValue { key, reason, .. } if key == "abuse_policy" && reason.contains("drop, warn or kick")));
+        assert!(matches!(load_server("", &[], &["--net-sim", "latency=fast"]), Err(ConfigError::InvalidValue { .. })));
+        // The file is checked by serde instead.
+        assert!(matches!(load_server("[server]\nudp_port = \"high\"\n", &[], &[]), Err(ConfigError::Toml { .. })));
+        assert!(matches!(load_server("[server\n", &[], &[]), Err(ConfigError::Toml { .. })));
+    }
+
+    #[test]
+    fn key_rotation_needs_a_port_after_udp_port() {
+        assert!(load_server("", &[], &["--udp-port", "65535"]).is_ok());
+        // Checked once all the layers are in, whichever of them set each one.
+        let error = load_server("[server]\nudp_port = 65535\n", &[], &["--key-rotation-secs", "3600"]).unwrap_err();
+        assert!(matches!(&error, ConfigError::InvalidValue { key, value, .. } if key == "udp_port" && value == "65535"));
+        assert!(load_server("[server]\nudp_port = 65535\n", &[], &["--key-rotation-secs", "3600", "--udp-port", "65534"]).is_ok());
+    }
+}
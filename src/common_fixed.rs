@@ -0,0 +1,133 @@
+//! A fixed-point number, which the simulation runs on with the `fixed-point` feature. See Real in common_sim.rs.
+//! f32 maths can come out slightly differently on different machines and with different compilers,
+//! which doesn't matter until two players are simulating the same match themselves, like in rollback matches.
+//! Integer maths comes out the same everywhere, so a Fixed match plays out identically on every machine.
+
+use std::ops::{Add, AddAssign, Div, Mul, Neg, Sub, SubAssign};
+
+use serde::{Deserialize, Serialize};
+
+/// A number with 32 bits before the point and 32 after, stored as a whole number of 1/2^32ths.
+/// Anything that would overflow stops at the largest or smallest value instead, and dividing by zero gives one of those too.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct Fixed(i64);
+
+impl Fixed {
+    pub const FRACTION_BITS: u32 = 32;
+    pub const ZERO: Fixed = Fixed(0);
+    pub const ONE: Fixed = Fixed(1 << Self::FRACTION_BITS);
+    pub const MAX: Fixed = Fixed(i64::MAX);
+    pub const MIN: Fixed = Fixed(i64::MIN);
+
+    pub const fn from_bits(bits: i64) -> Self {
+        Fixed(bits)
+    }
+
+    pub const fn to_bits(self) -> i64 {
+        self.0
+    }
+
+    /// The nearest Fixed to `value`. Every f32 converts the same way everywhere, so constants can be written as f32s.
+    pub fn from_f32(value: f32) -> Self {
+        // Both of these are exact in an f64, only the rounding loses anything.
+        Fixed((value as f64 * (1u64 << Self::FRACTION_BITS) as f64).round() as i64)
+    }
+
+    pub fn to_f32(self) -> f32 {
+        (self.0 as f64 / (1u64 << Self::FRACTION_BITS) as f64) as f32
+    }
+
+    pub fn abs(self) -> Self {
+        Fixed(self.0.saturating_abs())
+    }
+
+    /// -1 for negative numbers, otherwise 1. Like f32::signum, 0 counts as positive.
+    pub fn signum(self) -> Self {
+        if self.0 < 0 {
+            -Self::ONE
+        } else {
+            Self::ONE
+        }
+    }
+
+    /// The sine and cosine of an angle in radians, from their Taylor series.
+    /// Only accurate near 0, the error grows past about a quarter turn either way.
+    pub fn sin_cos(self) -> (Self, Self) {
+        let x = self;
+        let x2 = x * x;
+        // Each term is the last one times -x^2 / ((n + 1)(n + 2)).
+        let mut sin = x;
+        let mut cos = Self::ONE;
+        let mut sin_term = x;
+        let mut cos_term = Self::ONE;
+        for n in (1..=6).map(|n| n * 2) {
+            cos_term = -(cos_term * x2) / Fixed::from_int((n - 1) * n);
+            sin_term = -(sin_term * x2) / Fixed::from_int(n * (n + 1));
+            cos += cos_term;
+            sin += sin_term;
+        }
+        (sin, cos)
+    }
+
+    fn from_int(value: i64) -> Self {
+        Fixed(value.saturating_mul(1 << Self::FRACTION_BITS))
+    }
+
+    fn saturate(wide: i128) -> Self {
+        Fixed(wide.clamp(i64::MIN as i128, i64::MAX as i128) as i64)
+    }
+}
+
+impl Add for Fixed {
+    type Output = Fixed;
+    fn add(self, other: Fixed) -> Fixed {
+        Fixed(self.0.saturating_add(other.0))
+    }
+}
+
+impl Sub for Fixed {
+    type Output = Fixed;
+    fn sub(self, other: Fixed) -> Fixed {
+        Fixed(self.0.saturating_sub(other.0))
+    }
+}
+
+impl Mul for Fixed {
+    type Output = Fixed;
+    fn mul(self, other: Fixed) -> Fixed {
+        Fixed::saturate((self.0 as i128 * other.0 as i128) >> Self::FRACTION_BITS)
+    }
+}
+
+impl Div for Fixed {
+    type Output = Fixed;
+    fn div(self, other: Fixed) -> Fixed {
+        if other.0 == 0 {
+            return match self.0 {
+                0 => Fixed::ZERO,
+                n if n < 0 => Fixed::MIN,
+                _ => Fixed::MAX,
+            };
+        }
+        Fixed::saturate(((self.0 as i128) << Self::FRACTION_BITS) / other.0 as i128)
+    }
+}
+
+impl Neg for Fixed {
+    type Output = Fixed;
+    fn neg(self) -> Fixed {
+        Fixed(self.0.saturating_neg())
+    }
+}
+
+impl AddAssign for Fixed {
+    fn add_assign(&mut self, other: Fixed) {
+        *self = *self + other;
+    }
+}
+
+impl SubAssign for Fixed {
+    fn sub_assign(&mut self, other: Fixed) {
+        *self = *self - other;
+    }
+}
@@ -1,52 +1,56 @@
 //! The actual game code which both server and client use.
 //! Currently it is Pong.
 
-use num::signum;
-
-use rand::prelude::random;
 use serde::{Deserialize,Serialize};
 
 use std::time::Duration;
 
-use bevy::{
-    prelude::*,
-    sprite::{collide_aabb::{collide, Collision}}, render::view::RenderLayers,
-};
+use bevy::prelude::*;
 
 use iyes_loopless::prelude::*;
 
 use bevy_crt::plugin::Crt2dPlugin;
 
-use crate::common_net::GameState;
+use crate::common_net::{GameState, InputBuffer, PlayerInput, SnapshotHistory, TickSnapshot, POLL_RATE, PROTOCOL_VERSION};
+use crate::common_sim::{float, real, step_paddle, PongSim, SimVec};
+
+use std::collections::HashMap;
 
 // Defines the amount of time that should elapse between each physics step.
-const TIME_STEP: f32 = 1.0 / 120.0;
+pub const TIME_STEP: f32 = 1.0 / 120.0;
 
-const DEG_TO_RAD: f32 = std::f32::consts::PI / 180.0;
+// How much of the past the server keeps in its SnapshotHistory.
+pub const SNAPSHOT_HISTORY_SECONDS: f32 = 2.0;
+
+pub const DEG_TO_RAD: f32 = std::f32::consts::PI / 180.0;
 
 // These constants are defined in `Transform` units.
 // Using the default 2D camera they correspond 1:1 with screen pixels.
 pub const PADDLE_SIZE: Vec3 = Vec3::new(20.0, 120.0, 0.0);
-const GAP_BETWEEN_PADDLE_AND_WALL: f32 = 60.0;
+pub const GAP_BETWEEN_PADDLE_AND_WALL: f32 = 60.0;
 pub const PADDLE_SPEED: f32 = 500.0;
 // How close can the paddle get to the wall
 pub const PADDLE_PADDING: f32 = 10.0;
 
 // We set the z-value of the ball to 1 so it renders on top in the case of overlapping sprites.
 pub const BALL_STARTING_POSITION: Vec3 = Vec3::new(0.0, -50.0, 1.0);
-const BALL_SIZE: Vec3 = Vec3::new(30.0, 30.0, 0.0);
-const BALL_SPEED: f32 = 400.0;
+pub const BALL_SIZE: Vec3 = Vec3::new(30.0, 30.0, 0.0);
+pub const BALL_SPEED: f32 = 400.0;
 const INITIAL_BALL_DIRECTION: Vec2 = Vec2::new(0.5, -0.5);
-const BALL_SPEED_INCREASE: f32 = 1.1;
-const MAX_BALL_SPEED: f32 = 5000.0;
+pub const BALL_SPEED_INCREASE: f32 = 1.1;
+pub const MAX_BALL_SPEED: f32 = 5000.0;
+// How long the ball waits in the middle after a point is scored.
+pub const RESPAWN_DELAY: f32 = 3.0;
+// First to this many points wins the match.
+pub const WINNING_SCORE: usize = 11;
 
 const TRAIL_DECAY_MS: i32 = 500;
 const TRAIL_MAX_ALPHA: f32 = 0.5;
 
 pub const WALL_THICKNESS: f32 = 10.0;
 // x coordinates
-const LEFT_WALL: f32 = -450.;
-const RIGHT_WALL: f32 = 450.;
+pub const LEFT_WALL: f32 = -450.;
+pub const RIGHT_WALL: f32 = 450.;
 // y coordinates
 pub const BOTTOM_WALL: f32 = -300.;
 pub const TOP_WALL: f32 = 300.;
@@ -62,60 +66,107 @@ const WALL_COLOR: Color = Color::rgb(0.8, 0.8, 0.8);
 const TEXT_COLOR: Color = Color::rgb(0.5, 0.5, 1.0);
 const SCORE_COLOR: Color = Color::rgb(1.0, 0.5, 0.5);
 
-const FIXED_RES: Vec2 = Vec2::new(1280.0, 720.0);
+/// A hash of every constant that changes how the game plays, and the PROTOCOL_VERSION for changes to how it plays that aren't constants.
+/// The client and server have to agree on this, otherwise the client's view of the game drifts away from the server's.
+/// Anything added to the rules above should be added here too.
+pub fn rules_hash() -> u64 {
+    let rules: [f32; 24] = [
+        TIME_STEP,
+        POLL_RATE,
+        PADDLE_SIZE.x,
+        PADDLE_SIZE.y,
+        GAP_BETWEEN_PADDLE_AND_WALL,
+        PADDLE_SPEED,
+        PADDLE_PADDING,
+        BALL_STARTING_POSITION.x,
+        BALL_STARTING_POSITION.y,
+        BALL_SIZE.x,
+        BALL_SIZE.y,
+        BALL_SPEED,
+        INITIAL_BALL_DIRECTION.x,
+        INITIAL_BALL_DIRECTION.y,
+        BALL_SPEED_INCREASE,
+        MAX_BALL_SPEED,
+        RESPAWN_DELAY,
+        WINNING_SCORE as f32,
+        WALL_THICKNESS,
+        LEFT_WALL,
+        RIGHT_WALL,
+        BOTTOM_WALL,
+        TOP_WALL,
+        // f32 and fixed-point games drift apart, see common_fixed.rs.
+        cfg!(feature = "fixed-point") as u8 as f32,
+    ];
+    let rules = rules.iter().flat_map(|rule| rule.to_bits().to_le_bytes());
+    fnv1a(PROTOCOL_VERSION.to_le_bytes().into_iter().chain(rules))
+}
 
-//Tells systems whether to run or not.
-pub fn is_game_active(playing: Res<Playing>) -> bool {
-    playing.0
+/// 64 bit FNV-1a. Used instead of the standard library hasher because that one isn't guaranteed to be the same between builds.
+pub fn fnv1a(bytes: impl IntoIterator<Item = u8>) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in bytes {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
 }
 
 /// Add game resources and systems to the client.
 pub fn add_to_app_client(mut app: App) -> App {
-    let fixed_update_stage = SystemStage::parallel()
-    .with_system(check_for_collisions.run_if(is_game_active).label("Collision check"))
-    .with_system(apply_velocity.run_if(is_game_active).before("Collision check"))
-    .with_system(play_collision_sound.run_if(is_game_active).after("Collision check"));
-    
-        
+    // The client doesn't simulate the ball any more, it's placed from the server's snapshots instead.
+    // Running the physics here as well just fought with them. Sounds and effects come from the server's GameEvents instead.
     app.add_plugin(Crt2dPlugin)
-        .insert_resource(Scoreboard { scoreleft: 0, scoreright: 0 })
-        .insert_resource(Playing(false))
         .insert_resource(ClearColor(BACKGROUND_COLOR))
-        .insert_resource(RespawnTimer(Timer::from_seconds(3.0,false)))
         .add_startup_system(setup_client.after(bevy_crt::plugin::setup_post_2d))
         .add_event::<CollisionEvent>()
-        .add_stage(
-            "fixed_update",
-            FixedTimestepStage::new(Duration::from_secs_f32(TIME_STEP))
-                .with_stage(fixed_update_stage)
-        )
+        .add_system(play_collision_sound)
         .add_system(update_scoreboard)
         .add_system(handle_trails)
         .add_system(bevy::window::close_on_esc);
         //.add_system(respawn_ball); Removing respawn system from the client as it's inherently random and could lead to desync.
         // Let the server handle respawning and update the client.
-    return app;
+    app
 }
 
-
 /// Adds game resources and systems to the server, excluding the systems only the client needs.
 pub fn add_to_app_server(mut app: App) -> App {
+    // The tick goes up first, so everything else in the step, and the snapshot recorded at the end, belong to the new tick.
     let fixed_update_stage = SystemStage::parallel()
-    .with_system(check_for_collisions.run_if(is_game_active).label("Collision check"))
-    .with_system(apply_velocity.run_if(is_game_active).before("Collision check"));
-
-    app.insert_resource(Scoreboard { scoreleft: 0, scoreright: 0 })
-        .insert_resource(Playing(false))
-        .insert_resource(RespawnTimer(Timer::from_seconds(3.0,false)))
-        .add_startup_system(setup_server)
-        .add_event::<CollisionEvent>()
+    .with_system(advance_tick.label("Tick"))
+    .with_system(step_rooms.label("Step").after("Tick"))
+    .with_system(record_snapshots.after("Step"));
+
+    // Rooms are spawned by the server as players show up, see spawn_room_server. PongSim has the walls.
+    app.insert_resource(SimulationTick::default())
+        .insert_resource(SnapshotHistory::new((SNAPSHOT_HISTORY_SECONDS / TIME_STEP) as usize))
+        .insert_resource(LagCompensation::default())
+        .add_event::<RoomEvent>()
         .add_stage(
             "fixed_update",
             FixedTimestepStage::new(Duration::from_secs_f32(TIME_STEP))
                 .with_stage(fixed_update_stage)
-        )
-        .add_system(respawn_ball);
-    return app;
+        );
+    app
+}
+
+/// How many ticks late each paddle's player sees the ball, so the ball can be judged the way they saw it.
+/// The server fills it in from each player's round trip time and interpolation delay. Paddles without an entry are judged on the ball as it is now.
+#[derive(Debug, Default)]
+pub struct LagCompensation(pub HashMap<Entity, u64>);
+
+/// Where a paddle's player saw the ball go during `tick`, from and to, or None if they aren't behind or the history doesn't go back that far.
+fn ball_seen(
+    paddle: Entity,
+    room: Entity,
+    tick: u64,
+    lag: &LagCompensation,
+    history: &SnapshotHistory,
+) -> Option<(SimVec, SimVec)> {
+    let rewind = lag.0.get(&paddle).copied().filter(|&rewind| rewind > 0)?;
+    let seen_tick = tick.checked_sub(rewind)?;
+    let from = history.room_at(seen_tick.checked_sub(1)?, room)?;
+    let to = history.room_at(seen_tick, room)?;
+    Some((SimVec::from_vec2(from.ball_loc), SimVec::from_vec2(to.ball_loc)))
 }
 
 #[derive(Component)]
@@ -142,18 +193,59 @@ pub enum PlayerSide {
     Right,
 }
 
+/// How many fixed updates the server has run. Every room moves on the same tick, and it never goes backwards.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct SimulationTick(pub u64);
+
+/// Whether the ball in a room is in play. Lives on the Room entity.
 #[derive(Component)]
 pub struct Playing(pub bool);
 
-/// Ball component.
+/// Marks the entity holding the state of one match: its Scoreboard and Playing, and on the server its RoomSim.
+/// The server runs as many of these as it has matches, the client only ever has one.
 #[derive(Component)]
-pub struct Ball{
-    /// Keeps track of which side scored last to decide which way the ball will go.
-    pub lastpointleft: bool
+pub struct Room;
+
+/// Points a ball or paddle at the Room it belongs to.
+/// Walls don't have one, every room shares the same walls.
+#[derive(Component, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InRoom(pub Entity);
+
+/// Every entity that makes up a single room.
+#[derive(Debug, Clone, Copy)]
+pub struct RoomEntities {
+    pub room: Entity,
+    pub ball: Entity,
+    pub paddle_left: Entity,
+    pub paddle_right: Entity,
 }
 
-/// Keeps track of how long we need to wait to let the ball start moving again.
-pub struct RespawnTimer(pub Timer);
+impl RoomEntities {
+    /// The paddle for the given side.
+    pub fn paddle(&self, side: PlayerSide) -> Entity {
+        match side {
+            PlayerSide::Left => self.paddle_left,
+            PlayerSide::Right => self.paddle_right,
+        }
+    }
+
+    /// Removes the whole room from the world.
+    pub fn despawn(&self, commands: &mut Commands) {
+        for entity in [self.ball, self.paddle_left, self.paddle_right, self.room] {
+            commands.entity(entity).despawn();
+        }
+    }
+}
+
+/// Ball component.
+#[derive(Component)]
+pub struct Ball;
+
+/// The match being played in a room. Lives on the Room entity, only on the server.
+/// This is the real state of the match, the room's Scoreboard and Playing and its ball's and paddles' Transforms are copied out of it
+/// after every step so they can be sent to players. Anything that changes the match, like starting or pausing it, changes this.
+#[derive(Component, Deref, DerefMut)]
+pub struct RoomSim(pub PongSim);
 
 /// Velocity just stores a Vec2, used to calculate movement.
 #[derive(Component, Deref, DerefMut)]
@@ -162,9 +254,44 @@ pub struct Velocity(pub Vec2);
 #[derive(Component)]
 pub struct Collider;
 
+/// Tells the client to play the collision sound.
 #[derive(Default)]
 pub struct CollisionEvent;
 
+/// Something that happened in a match which players should see or hear.
+/// Only the server's systems produce these, the client is told about them in ServerMessages::GameEvent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum GameEvent {
+    /// `side` is the player who got the point.
+    GoalScored { side: PlayerSide, tick: u64 },
+    PaddleHit { side: PlayerSide, tick: u64 },
+    /// The ball bounced off the top or bottom wall.
+    WallBounce { tick: u64 },
+    /// The ball started moving again after a point or a reset.
+    Serve { tick: u64 },
+    MatchOver { winner: PlayerSide, tick: u64 },
+}
+
+impl GameEvent {
+    /// The SimulationTick the event happened on.
+    pub fn tick(&self) -> u64 {
+        match *self {
+            GameEvent::GoalScored { tick, .. }
+            | GameEvent::PaddleHit { tick, .. }
+            | GameEvent::WallBounce { tick }
+            | GameEvent::Serve { tick }
+            | GameEvent::MatchOver { tick, .. } => tick,
+        }
+    }
+}
+
+/// A GameEvent, along with the room it happened in.
+#[derive(Debug, Clone, Copy)]
+pub struct RoomEvent {
+    pub room: Entity,
+    pub event: GameEvent,
+}
+
 #[derive(Component)]
 pub struct Trail{
     timeleft: i32,
@@ -196,15 +323,8 @@ pub struct WallBundle {
     pub location: WallLoc,
 }
 
-/// The same bundle that defines a wall, but it has no sprite, so it can be used on the server.
-#[derive(Bundle)]
-pub struct WallBundleServer {
-    pub transform: Transform,
-    pub collider: Collider,
-    pub location: WallLoc,
-}
-
 /// Which side of the arena is this wall located on?
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum WallLocation {
     Left,
     Right,
@@ -242,27 +362,6 @@ impl WallLocation {
     }
 }
 
-impl WallBundleServer {
-    /// This "builder method" allows us to reuse logic across our wall entities,
-    /// making our code easier to read and less prone to bugs when we change the logic
-    pub fn new(location: WallLocation) -> WallBundleServer {
-        WallBundleServer {
-            transform: Transform {
-                // We need to convert our Vec2 into a Vec3, by giving it a z-coordinate
-                // This is used to determine the order of our sprites
-                translation: location.position().extend(0.0),
-                // The z-scale of 2D objects must always be 1.0,
-                // or their ordering will be affected in surprising ways.
-                // See https://github.com/bevyengine/bevy/issues/4149
-                scale: location.size().extend(1.0),
-                ..default()
-            },
-            collider: Collider,
-            location: WallLoc(location),
-        }
-    }
-}
-
 impl WallBundle {
     /// This "builder method" allows us to reuse logic across our wall entities,
     /// making our code easier to read and less prone to bugs when we change the logic
@@ -291,7 +390,8 @@ impl WallBundle {
     }
 }
 
-/// This resource tracks the game's score
+/// This component tracks a room's score
+#[derive(Component)]
 pub struct Scoreboard {
     pub scoreleft: usize,
     pub scoreright: usize,
@@ -340,18 +440,17 @@ fn handle_trails(
         });
 }
 
-/// This takes information from all of the parts of the game that change over time and puts it into a struct
+/// This takes information from all of the parts of a room that change over time and puts it into a struct
 /// Which is easier to send over network and read.
-pub fn get_gamestate(
-    ball: Query<(&Transform, &Velocity), With<Ball>>, 
-    paddles: Query<(&Transform,&PaddleSide), With<Paddle>>, 
-    scoreboard: Res<Scoreboard>,
-    playing: Res<Playing>
+pub fn get_gamestate<'a>(
+    ball: (&Transform, &Velocity), 
+    paddles: impl IntoIterator<Item = (&'a Transform, &'a PaddleSide)>, 
+    scoreboard: &Scoreboard,
+    playing: &Playing,
 ) -> GameState {
-    let ball = ball.single();
     let mut paddle_l = Vec2::new(LEFT_WALL + GAP_BETWEEN_PADDLE_AND_WALL,0.0);
     let mut paddle_r = Vec2::new(RIGHT_WALL - GAP_BETWEEN_PADDLE_AND_WALL,0.0);
-    for (paddle, paddleside) in paddles.iter() {
+    for (paddle, paddleside) in paddles {
         match paddleside.0 {
             PlayerSide::Left => {
                 paddle_l.x = paddle.translation.x;
@@ -371,16 +470,46 @@ pub fn get_gamestate(
         score_l: scoreboard.scoreleft as i32,
         score_r: scoreboard.scoreright as i32,
         playing: playing.0,
+        last_input: 0,
+        server_time: 0.0,
+        tick: 0,
     }
 }
 
+/// Gets the GameState of every room on the server, keyed by its Room entity.
+pub fn get_room_gamestates(
+    rooms: &Query<(&Scoreboard, &Playing), With<Room>>,
+    balls: &Query<(&Transform, &Velocity, &InRoom), With<Ball>>,
+    paddles: &Query<(&Transform, &PaddleSide, &InRoom), With<Paddle>>,
+) -> HashMap<Entity, GameState> {
+    let mut gamestates = HashMap::new();
+    for (ball_transform, ball_velocity, in_room) in balls.iter() {
+        let (scoreboard, playing) = match rooms.get(in_room.0) {
+            Ok(state) => state,
+            Err(_) => continue,
+        };
+        let room_paddles = paddles.iter()
+            .filter(|(_, _, paddle_room)| *paddle_room == in_room)
+            .map(|(transform, side, _)| (transform, side));
+        gamestates.insert(in_room.0, get_gamestate((ball_transform, ball_velocity), room_paddles, scoreboard, playing));
+    }
+    gamestates
+}
+
+/// Moves a paddle by one step of a player's input, without letting it into the walls.
+/// The server and the client's prediction both use this, so they always agree on where an input leaves the paddle.
+pub fn move_paddle(y: f32, input: &PlayerInput, delta: f32) -> f32 {
+    float(step_paddle(real(y), input, real(delta)))
+}
+
 /// Takes the GameState struct and actually applies it to the various changing objects throughout the game.
 /// Used to update the client with information from the server.
+#[allow(clippy::type_complexity)]
 pub fn set_gamestate(
     ball: &mut Query<(&mut Transform, &mut Velocity), (With<Ball>,Without<Paddle>)>,
     paddles: &mut Query<(&mut Transform,&PaddleSide), With<Paddle>>, 
-    scoreboard: &mut ResMut<Scoreboard>,
-    playing: &mut ResMut<Playing>,
+    scoreboard: &mut Scoreboard,
+    playing: &mut Playing,
     gamestate: GameState) {
     let (mut ball_loc, mut ball_vel) = ball.single_mut();
     ball_loc.translation.x = gamestate.ball_loc.x;
@@ -416,6 +545,9 @@ fn setup_client(mut commands: Commands, asset_server: Res<AssetServer>) {
     let ball_collision_sound = asset_server.load("sounds/breakout_collision.ogg");
     commands.insert_resource(CollisionSound(ball_collision_sound));
 
+    // The client only ever sees one room, the one the server put us in.
+    let room = spawn_room_state(&mut commands);
+
     // Paddle
     let paddle_x_left = LEFT_WALL + GAP_BETWEEN_PADDLE_AND_WALL;
     let paddle_x_right = RIGHT_WALL - GAP_BETWEEN_PADDLE_AND_WALL;
@@ -425,6 +557,7 @@ fn setup_client(mut commands: Commands, asset_server: Res<AssetServer>) {
         .insert(Paddle)
         .insert(PaddleSide(PlayerSide::Left))
         .insert(Movable)
+        .insert(InRoom(room))
         .insert_bundle(SpriteBundle {
             transform: Transform {
                 translation: Vec3::new(paddle_x_left, 0.0, 0.0),
@@ -444,6 +577,7 @@ fn setup_client(mut commands: Commands, asset_server: Res<AssetServer>) {
         .insert(Paddle)
         .insert(PaddleSide(PlayerSide::Right))
         .insert(Movable)
+        .insert(InRoom(room))
         .insert_bundle(SpriteBundle {
             transform: Transform {
                 translation: Vec3::new(paddle_x_right, 0.0, 0.0),
@@ -461,8 +595,9 @@ fn setup_client(mut commands: Commands, asset_server: Res<AssetServer>) {
     // Ball
     commands
         .spawn()
-        .insert(Ball{lastpointleft: false})
+        .insert(Ball)
         .insert(Movable)
+        .insert(InRoom(room))
         .insert_bundle(SpriteBundle {
             transform: Transform {
                 scale: BALL_SIZE,
@@ -539,123 +674,148 @@ fn setup_client(mut commands: Commands, asset_server: Res<AssetServer>) {
     commands.spawn_bundle(WallBundle::new(WallLocation::Top)).insert(Wall);
 }
 
-/// Adds the game's entities to the world.
+/// Spawns the entity which holds a room's score and state, shared by the client and server setup.
+fn spawn_room_state(commands: &mut Commands) -> Entity {
+    commands
+        .spawn()
+        .insert(Room)
+        .insert(Scoreboard { scoreleft: 0, scoreright: 0 })
+        .insert(Playing(false))
+        .id()
+}
+
+/// Adds a new room with its own ball and paddles to the world.
 /// Specific to the server as it strips all of the sprites and assets used in the client setup.
-fn setup_server(mut commands: Commands) {
+pub fn spawn_room_server(commands: &mut Commands) -> RoomEntities {
+    let room = spawn_room_state(commands);
+    // It gets its seed when a match starts.
+    commands.entity(room).insert(RoomSim(PongSim::new(0)));
 
     // Paddle
     let paddle_x_left = LEFT_WALL + GAP_BETWEEN_PADDLE_AND_WALL;
     let paddle_x_right = RIGHT_WALL - GAP_BETWEEN_PADDLE_AND_WALL;
 
-    commands
+    let paddle_left = commands
         .spawn()
         .insert(Paddle)
         .insert(PaddleSide(PlayerSide::Left))
         .insert(Movable)
+        .insert(InRoom(room))
         .insert(Transform {
             translation: Vec3::new(paddle_x_left, 0.0, 0.0),
             scale: PADDLE_SIZE,
             ..default()
         })
-        .insert(Collider);
+        .id();
 
-    commands
+    let paddle_right = commands
         .spawn()
         .insert(Paddle)
         .insert(PaddleSide(PlayerSide::Right))
         .insert(Movable)
+        .insert(InRoom(room))
         .insert(Transform {
             translation: Vec3::new(paddle_x_right, 0.0, 0.0),
             scale: PADDLE_SIZE,
             ..default()
         })
-        .insert(Collider);
+        .id();
 
     // Ball
-    commands
+    let ball = commands
         .spawn()
-        .insert(Ball{lastpointleft: false})
+        .insert(Ball)
         .insert(Movable)
+        .insert(InRoom(room))
         .insert(Transform {
             scale: BALL_SIZE,
             translation: BALL_STARTING_POSITION,
             ..default()
         })
-        .insert(Velocity(INITIAL_BALL_DIRECTION.normalize() * BALL_SPEED));
+        .insert(Velocity(INITIAL_BALL_DIRECTION.normalize() * BALL_SPEED))
+        .id();
 
-    // Walls
-    commands.spawn_bundle(WallBundleServer::new(WallLocation::Left)).insert(Wall);
-    commands.spawn_bundle(WallBundleServer::new(WallLocation::Right)).insert(Wall);
-    commands.spawn_bundle(WallBundleServer::new(WallLocation::Bottom)).insert(Wall);
-    commands.spawn_bundle(WallBundleServer::new(WallLocation::Top)).insert(Wall);
+    RoomEntities { room, ball, paddle_left, paddle_right }
 }
 
-/// Applies velocity and makes sure we aren't passing through any objects.
-fn apply_velocity(
-    mut query: Query<(&mut Transform, &Velocity), (Without<Paddle>,Without<Wall>)>, 
-    query_paddles: Query<&Transform, With<Paddle>>, 
-    query_walls: Query<(&Transform, &WallLoc), With<Wall>>
+/// Moves every room's match on by one PongSim step.
+/// Players' paddles are moved first by whichever of their inputs are due, then the ball is moved, judged the way the players saw it.
+/// Everything is then copied out of the PongSim onto the room's components, which is what gets sent to players.
+#[allow(clippy::type_complexity)]
+fn step_rooms(
+    mut rooms: Query<(Entity, &mut RoomSim, &mut Scoreboard, &mut Playing), With<Room>>,
+    mut balls: Query<(&mut Transform, &mut Velocity, &InRoom), (With<Ball>, Without<Paddle>)>,
+    mut paddles: Query<(Entity, &mut Transform, &PaddleSide, &InRoom, Option<&mut InputBuffer>), With<Paddle>>,
+    tick: Res<SimulationTick>,
+    lag: Res<LagCompensation>,
+    history: Res<SnapshotHistory>,
+    mut room_events: EventWriter<RoomEvent>,
 ) {
-    for (mut transform, velocity) in &mut query {
-        let (pastx, pasty) = (transform.translation.x,transform.translation.y);
-        let opp_dir_x = -1.0 * signum(velocity.x);
-        let opp_dir_y = -1.0 * signum(velocity.y);
-        transform.translation.x += velocity.x * TIME_STEP;
-        transform.translation.y += velocity.y * TIME_STEP;
-        
-        // Check if paddles are between here and our next position.
-        for tr in query_paddles.iter() {
-            let towardsy = (2 * ((signum(transform.translation.y - pasty) == signum(velocity.y)) as i32) - 1) as f32;
-            if (pasty - tr.translation.y).abs() < towardsy*velocity.y*TIME_STEP + PADDLE_SIZE.y/2.0 
-            && tr.translation.x < pastx.max(transform.translation.x)
-            && tr.translation.x > pastx.min(transform.translation.x) 
-            {
-                // They are. Set our position so that it just collides with the paddle instead of going through.
-                // Collision system should pick it up from here.
-                transform.translation.x = tr.translation.x + (BALL_SIZE.x * 0.5 * opp_dir_x);
-                let distx = transform.translation.x - pastx;
-                let dist_t = distx / velocity.x;
-                transform.translation.y = pasty + velocity.y * dist_t;
+    for (room, mut sim, mut scoreboard, mut playing) in &mut rooms {
+        sim.tick = tick.0;
+        let mut seen = [None; 2];
+        for (paddle, _, side, in_room, buffer) in &mut paddles {
+            if in_room.0 != room {
+                continue;
             }
-        }
-        // Check if walls are between here and our next position.
-        for (tr, wl) in query_walls.iter() {
-            let is_horizontal = match wl.0 {
-                WallLocation::Left => false,
-                WallLocation::Right => false,
-                _ => true
-            };
-
-            // Check Y against top and bottom walls.
-            if is_horizontal
-            && tr.translation.y < pasty.max(transform.translation.y)
-            && tr.translation.y > pasty.min(transform.translation.y) 
-            {
-                // Prevent ball from passing through the wall by setting it to just collide with wall. 
-                // Collision system should pick it up from here.
-                transform.translation.y = tr.translation.y + (BALL_SIZE.y * 0.5 * opp_dir_y);
-                let disty = transform.translation.y - pasty;
-                let dist_t = disty / velocity.y;
-                transform.translation.x = (pastx + velocity.x * dist_t).clamp(LEFT_WALL,RIGHT_WALL);
+            if let Some(mut buffer) = buffer {
+                buffer.advance(TIME_STEP);
+                while let Some(message) = buffer.next_due() {
+                    sim.play_input(side.0, &message.input);
+                }
             }
+            seen[side.0 as usize] = ball_seen(paddle, room, tick.0, &lag, &history);
+        }
+
+        for event in sim.step_compensated([PlayerInput::default(); 2], seen) {
+            room_events.send(RoomEvent { room, event });
+        }
 
-            // Check X against left and right walls.
-            if !is_horizontal
-            && tr.translation.x < pastx.max(transform.translation.x)
-            && tr.translation.x > pastx.min(transform.translation.x) 
-            {
-                // Prevent ball from passing through the wall by setting it to just collide with wall. 
-                // Collision system should pick it up from here.
-                transform.translation.x = tr.translation.x + (BALL_SIZE.x * 0.5 * opp_dir_x);
-                let distx = transform.translation.x - pastx;
-                let dist_t = distx / velocity.x;
-                transform.translation.y = (pasty + velocity.y * dist_t).clamp(TOP_WALL,BOTTOM_WALL);
+        for (_, mut transform, side, in_room, _) in &mut paddles {
+            if in_room.0 == room {
+                transform.translation.y = float(sim.paddles[side.0 as usize]);
             }
         }
+        for (mut transform, mut velocity, in_room) in &mut balls {
+            if in_room.0 == room {
+                transform.translation.x = float(sim.ball.x);
+                transform.translation.y = float(sim.ball.y);
+                velocity.0 = sim.ball_velocity.to_vec2();
+            }
+        }
+        scoreboard.scoreleft = sim.score[0];
+        scoreboard.scoreright = sim.score[1];
+        playing.0 = sim.playing;
+    }
+}
+
+fn advance_tick(mut tick: ResMut<SimulationTick>) {
+    tick.0 += 1;
+}
+
+/// Saves what every room looked like at the end of this tick.
+fn record_snapshots(
+    tick: Res<SimulationTick>,
+    time: Res<Time>,
+    mut history: ResMut<SnapshotHistory>,
+    rooms: Query<(&Scoreboard, &Playing), With<Room>>,
+    balls: Query<(&Transform, &Velocity, &InRoom), With<Ball>>,
+    paddles: Query<(&Transform, &PaddleSide, &InRoom), With<Paddle>>,
+) {
+    let server_time = time.seconds_since_startup();
+    let mut gamestates = get_room_gamestates(&rooms, &balls, &paddles);
+    for gamestate in gamestates.values_mut() {
+        gamestate.tick = tick.0;
+        gamestate.server_time = server_time;
     }
+    history.push(TickSnapshot { tick: tick.0, server_time, rooms: gamestates });
 }
 
-fn update_scoreboard(scoreboard: Res<Scoreboard>, mut query: Query<(&mut Text, &ScoreSide)>) {
+fn update_scoreboard(rooms: Query<&Scoreboard, With<Room>>, mut query: Query<(&mut Text, &ScoreSide)>) {
+    let scoreboard = match rooms.get_single() {
+        Ok(scoreboard) => scoreboard,
+        Err(_) => return,
+    };
     for (mut text, score_side) in query.iter_mut(){
         match score_side.0 {
             ScoringSide::Left => {
@@ -668,109 +828,6 @@ fn update_scoreboard(scoreboard: Res<Scoreboard>, mut query: Query<(&mut Text, &
     }
 }
 
-fn check_for_collisions(
-    mut scoreboard: ResMut<Scoreboard>,
-    mut ball_query: Query<(&mut Velocity, &mut Transform, &mut Ball), With<Ball>>,
-    collider_query: Query<(Entity, &Transform, Option<&Paddle>), (With<Collider>,Without<Ball>)>,
-    mut collision_events: EventWriter<CollisionEvent>,
-    mut timer: ResMut<RespawnTimer>,
-) {
-    let (mut ball_velocity, mut ball_transform, mut ball) = ball_query.single_mut();
-    let ball_size = ball_transform.scale.truncate();
-
-    // check collision with walls
-    for (_, transform, maybe_paddle) in &collider_query {
-        let collision = collide(
-            ball_transform.translation,
-            ball_size,
-            transform.translation,
-            transform.scale.truncate(),
-        );
-        if let Some(collision) = collision {
-            // Sends a collision event so that other systems can react to the collision
-            collision_events.send_default();
-
-            let mut is_wall = true;
-
-            // Did we collide with a paddle?
-            if maybe_paddle.is_some() {
-                // If we collided with a paddle, we didn't collide with a wall.
-                is_wall = false;
-                // Increase the ball velocity by 1.1x
-                // This is to apply pressure to the players and prevent drawn out matches.
-                // Also clamp it below our max speed, otherwise it can become unplayable.
-                ball_velocity.x = (ball_velocity.x*BALL_SPEED_INCREASE).clamp(-MAX_BALL_SPEED,MAX_BALL_SPEED);
-                // Set the Y velocity proportionally to how far from the center of the paddle we hit.
-                // This is to give the player more control over where the ball goes.
-                ball_velocity.y = signum(ball_velocity.y)*(ball_velocity.x * (ball_transform.translation.y - transform.translation.y) / (PADDLE_SIZE.y/3.0)).abs();
-            }
-
-            // reflect the ball when it collides
-            let mut reflect_x = false;
-            let mut reflect_y = false;
-
-            // despawn when we hit the bottom wall
-            // doesn't actually despawn, just resets it.
-            let mut despawn = false;
-
-            // only reflect if the ball's velocity is going in the opposite direction of the
-            // collision
-            match (collision, is_wall) {
-                (Collision::Left, true) => {
-                    scoreboard.scoreleft += 1;
-                    ball.lastpointleft = false;
-                    despawn = true;
-                },
-                (Collision::Right, true) => {
-                    scoreboard.scoreright += 1;
-                    ball.lastpointleft = true;
-                    despawn = true;
-                },
-                (Collision::Left, false) => reflect_x = ball_velocity.x > 0.0,
-                (Collision::Right, false) => reflect_x = ball_velocity.x < 0.0,
-                (Collision::Top, _) => reflect_y = ball_velocity.y < 0.0,
-                (Collision::Bottom, _) => reflect_y = ball_velocity.y > 0.0,
-                (Collision::Inside, _) => { /* do nothing */ }
-            }
-
-            // If we need to despawn, set our speed to 0 and reset our position.
-            if despawn {
-                ball_velocity.x = 0.0;
-                ball_velocity.y = 0.0;
-                ball_transform.translation.x = BALL_STARTING_POSITION.x;
-                ball_transform.translation.y = BALL_STARTING_POSITION.x;
-                timer.0.reset();
-            }
-
-            // reflect velocity on the x-axis if we hit something on the x-axis
-            if reflect_x {
-                ball_velocity.x = -ball_velocity.x;
-            }
-
-            // reflect velocity on the y-axis if we hit something on the y-axis
-            if reflect_y {
-                ball_velocity.y = -ball_velocity.y;
-            }
-        }
-    }
-}
-
-/// Simply checks if the ball should respawn yet.
-fn respawn_ball(time: Res<Time>, mut timer: ResMut<RespawnTimer>, mut ball_query: Query<(&mut Velocity, &Ball), With<Ball>>) {
-    if timer.0.tick(time.delta()).just_finished() {
-        let (mut ball_velocity, ball )= ball_query.single_mut();
-        // Choose an angle that is in a 60 degree triangle of whoever was scored on last.
-        let init_angle = random::<f32>() * 60.0 - 30.0 + (180 * ball.lastpointleft as i32) as f32;
-        // Convert to cartesian coordinates representative of our angle.
-        let init_dir = Vec2::from_angle(init_angle * DEG_TO_RAD);
-        // Give it the starting speed in the direction we specified previously.
-        let ball_velocity_default = Velocity(init_dir * BALL_SPEED);
-        // Actually set the velocity now.
-        ball_velocity.x = ball_velocity_default.x;
-        ball_velocity.y = ball_velocity_default.y;
-    }
-}
-
 fn play_collision_sound(
     collision_events: EventReader<CollisionEvent>,
     audio: Res<Audio>,
@@ -2,7 +2,12 @@ use bevy::{
     prelude::*, 
     time::Timer
 };
-use std::collections::HashMap;
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    io::{Read, Write},
+    time::Duration,
+};
 
 use bevy_renet::{
     renet::{
@@ -18,7 +23,29 @@ use crate::common_game::*;
 /// Controls how often the server and client update each other.
 pub const POLL_RATE: f32 = 1.0 / 60.0;
 
-use serde::{Deserialize, Serialize};
+/// Version of this build. The client and server have to be running the same one,
+/// as the messages and GameState layout can change between versions.
+pub const GAME_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Goes up by one with every change to the messages, how GameStates are encoded or how the game plays,
+/// since the package version doesn't go up for every one of those. It's part of `rules_hash()`, so builds on different ones won't play together.
+pub const PROTOCOL_VERSION: u32 = 3;
+
+/// How long either side of the TCP token exchange waits on the other before giving up.
+pub const TCP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Largest message we accept over TCP. Stops a bogus length prefix from making us allocate gigabytes.
+pub const TCP_MESSAGE_LIMIT: u64 = 4096;
+
+use bincode::Options;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+/// Pings and pongs go on their own unreliable channel, a resent ping would make the round trip look longer than it is.
+pub const HEARTBEAT_CHANNEL: u8 = 3;
+
+/// Rollback matches swap RollbackMessages on this reliable channel, see common_rollback.rs.
+/// Every input has to arrive, and in order, or the players' games drift apart.
+pub const ROLLBACK_CHANNEL: u8 = 2;
 
 /// Default connection config used for both server and client.
 pub fn connection_config() -> RenetConnectionConfig {
@@ -35,7 +62,11 @@ pub fn connection_config() -> RenetConnectionConfig {
                 ..default()
             }),
             ChannelConfig::Reliable(ReliableChannelConfig{
-                channel_id: 2,
+                channel_id: ROLLBACK_CHANNEL,
+                ..default()
+            }),
+            ChannelConfig::Unreliable(UnreliableChannelConfig{
+                channel_id: HEARTBEAT_CHANNEL,
                 ..default()
             }),],
         receive_channels_config: vec![
@@ -50,7 +81,11 @@ pub fn connection_config() -> RenetConnectionConfig {
                     ..default()
                 }),
                 ChannelConfig::Reliable(ReliableChannelConfig{
-                    channel_id: 2,
+                    channel_id: ROLLBACK_CHANNEL,
+                    ..default()
+                }),
+                ChannelConfig::Unreliable(UnreliableChannelConfig{
+                    channel_id: HEARTBEAT_CHANNEL,
                     ..default()
                 }),],
         ..default()
@@ -62,7 +97,7 @@ pub fn connection_config() -> RenetConnectionConfig {
 pub struct SendTimer(pub Timer);
 
 /// Struct represents player inputs.
-#[derive(Debug, Default, Serialize, Deserialize, Component, Clone, Copy)]
+#[derive(Debug, Default, Serialize, Deserialize, Component, Clone, Copy, PartialEq, Eq)]
 pub struct PlayerInput {
     pub up: bool,
     pub down: bool,
@@ -70,9 +105,49 @@ pub struct PlayerInput {
     pub right: bool,
 }
 
+/// A player's input along with its place in the order they were sent.
+/// The server applies each of these for exactly one POLL_RATE step, so the client can predict exactly where it ends up.
+#[derive(Debug, Default, Serialize, Deserialize, Clone, Copy)]
+pub struct InputMessage {
+    /// Counts up from 1 with every input a client sends.
+    pub sequence: u32,
+    pub input: PlayerInput,
+}
+
+/// Inputs the server has recieved for a paddle but not applied yet, and the newest one it has applied.
+#[derive(Debug, Default, Component)]
+pub struct InputBuffer {
+    pub pending: VecDeque<InputMessage>,
+    pub last_applied: u32,
+    /// Seconds of play that haven't had an input applied for them yet. Each input uses up POLL_RATE of it.
+    pub owed: f32,
+}
+
+/// How much time a paddle can bank while no inputs are coming in, so the ones held up by a lag spike can catch up afterwards.
+pub const MAX_INPUT_CATCHUP: f32 = 0.1;
+
+impl InputBuffer {
+    /// Lets `elapsed` seconds of play go by, which makes room for that much more input.
+    pub fn advance(&mut self, elapsed: f32) {
+        self.owed = (self.owed + elapsed).min(MAX_INPUT_CATCHUP);
+    }
+
+    /// The next input there's been time for, if there is one. Anything sent faster than that waits its turn,
+    /// so sending more inputs doesn't make a paddle any faster.
+    pub fn next_due(&mut self) -> Option<InputMessage> {
+        if self.owed < POLL_RATE {
+            return None;
+        }
+        let message = self.pending.pop_front()?;
+        self.owed -= POLL_RATE;
+        self.last_applied = message.sequence;
+        Some(message)
+    }
+}
+
 /// Struct containing all of the information about the game which can change over time.
 /// Used for updating the client with information from the server.
-#[derive(Debug, Default, Serialize, Deserialize)]
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
 pub struct GameState{
     pub ball_loc: Vec2,
     pub ball_velocity: Vec2,
@@ -81,34 +156,516 @@ pub struct GameState{
     pub score_l: i32,
     pub score_r: i32,
     pub playing: bool,
+    /// Sequence of the newest input from the reciever that's included in this state, so they know which inputs to replay.
+    /// Always 0 for spectators.
+    pub last_input: u32,
+    /// Seconds since the server started when this state was taken. Used to put snapshots in order and blend between them.
+    pub server_time: f64,
+    /// The server's SimulationTick when this state was taken.
+    pub tick: u64,
+}
+
+/// What every room on the server looked like at the end of one tick.
+#[derive(Debug, Clone)]
+pub struct TickSnapshot {
+    pub tick: u64,
+    pub server_time: f64,
+    pub rooms: HashMap<Entity, GameState>,
+}
+
+/// The server's record of the last few seconds of ticks, oldest first.
+/// Lag compensation reads from here, see `ball_seen` in common_game.rs.
+#[derive(Debug)]
+pub struct SnapshotHistory {
+    snapshots: VecDeque<TickSnapshot>,
+    capacity: usize,
+}
+
+impl SnapshotHistory {
+    /// Keeps at most `capacity` ticks, forgetting the oldest as new ones come in.
+    pub fn new(capacity: usize) -> Self {
+        SnapshotHistory { snapshots: VecDeque::with_capacity(capacity), capacity: capacity.max(1) }
+    }
+
+    /// Adds the snapshot for the next tick. Ticks have to be pushed in order.
+    pub fn push(&mut self, snapshot: TickSnapshot) {
+        debug_assert!(self.snapshots.back().is_none_or(|newest| newest.tick < snapshot.tick));
+        if self.snapshots.len() >= self.capacity {
+            self.snapshots.pop_front();
+        }
+        self.snapshots.push_back(snapshot);
+    }
+
+    /// The snapshot for an exact tick, if we still have it.
+    pub fn get(&self, tick: u64) -> Option<&TickSnapshot> {
+        // Ticks go up by one each time, so the tick is also the index.
+        let oldest = self.snapshots.front()?.tick;
+        let snapshot = self.snapshots.get(usize::try_from(tick.checked_sub(oldest)?).ok()?)?;
+        if snapshot.tick == tick {
+            Some(snapshot)
+        } else {
+            None
+        }
+    }
+
+    /// One room's state at an exact tick.
+    pub fn room_at(&self, tick: u64, room: Entity) -> Option<&GameState> {
+        self.get(tick)?.rooms.get(&room)
+    }
+}
+
+/// How many snapshots the client holds on to. At 60 a second this is about half a second, well past any sensible interpolation delay.
+const SNAPSHOT_BUFFER_LEN: usize = 32;
+
+/// The last few GameStates from the server, kept in order of server time.
+/// The client shows remote entities a little in the past, blending between the snapshots either side,
+/// so late or missing packets don't make things jump around.
+#[derive(Debug, Default)]
+pub struct SnapshotBuffer {
+    snapshots: VecDeque<GameState>,
+    /// Best guess at the server's clock minus ours.
+    clock_offset: Option<f64>,
+}
+
+impl SnapshotBuffer {
+    /// Adds a snapshot recieved at `local_time`.
+    /// Returns false if it was a duplicate, or too old to fit in an already full buffer, in which case it's thrown away.
+    pub fn insert(&mut self, snapshot: GameState, local_time: f64) -> bool {
+        // Packets can only ever be late, so the snapshot that makes the server look furthest ahead is the closest to the truth.
+        // The estimate still drifts down slowly so a single lucky packet can't skew it forever.
+        let offset = snapshot.server_time - local_time;
+        self.clock_offset = Some(match self.clock_offset {
+            Some(previous) if offset < previous => previous + (offset - previous) * 0.01,
+            _ => offset,
+        });
+
+        let full = self.snapshots.len() >= SNAPSHOT_BUFFER_LEN;
+        if full && self.snapshots.front().is_some_and(|oldest| snapshot.server_time <= oldest.server_time) {
+            return false;
+        }
+        let index = self.snapshots.iter().position(|s| s.server_time >= snapshot.server_time).unwrap_or(self.snapshots.len());
+        if self.snapshots.get(index).is_some_and(|s| s.server_time == snapshot.server_time) {
+            return false;
+        }
+        self.snapshots.insert(index, snapshot);
+        while self.snapshots.len() > SNAPSHOT_BUFFER_LEN {
+            self.snapshots.pop_front();
+        }
+        true
+    }
+
+    pub fn newest(&self) -> Option<&GameState> {
+        self.snapshots.back()
+    }
+
+    /// Forgets every snapshot, for when we start watching a different room.
+    /// The clock offset is kept since it's still the same server.
+    pub fn clear(&mut self) {
+        self.snapshots.clear();
+    }
+
+    /// Roughly when the server was on `tick`, worked out from the newest snapshot.
+    pub fn time_of_tick(&self, tick: u64) -> Option<f64> {
+        let newest = self.snapshots.back()?;
+        Some(newest.server_time + (tick as f64 - newest.tick as f64) * TIME_STEP as f64)
+    }
+
+    /// The server time to show things at, `delay` seconds behind where the server is now.
+    pub fn render_time(&self, local_time: f64, delay: f64) -> Option<f64> {
+        self.clock_offset.map(|offset| local_time + offset - delay)
+    }
+
+    /// Works out what the game looked like at `time`.
+    /// Between two snapshots the ball and paddles are blended. Past the newest one the ball keeps going along its velocity,
+    /// for at most `max_extrapolation` seconds, and the paddles stay put.
+    pub fn sample(&self, time: f64, max_extrapolation: f64) -> Option<GameState> {
+        let newest = self.snapshots.back()?;
+        if time >= newest.server_time {
+            let mut state = newest.clone();
+            if state.playing {
+                let ahead = (time - newest.server_time).min(max_extrapolation) as f32;
+                state.ball_loc += state.ball_velocity * ahead;
+            }
+            return Some(state);
+        }
+        let after = self.snapshots.iter().position(|s| s.server_time > time)?;
+        // Further back than anything we have, so the oldest snapshot is as close as we can get.
+        if after == 0 {
+            return self.snapshots.front().cloned();
+        }
+        let (from, to) = (&self.snapshots[after - 1], &self.snapshots[after]);
+        let t = ((time - from.server_time) / (to.server_time - from.server_time)) as f32;
+        let mut state = from.clone();
+        state.ball_loc = from.ball_loc.lerp(to.ball_loc, t);
+        state.paddle_l_loc = from.paddle_l_loc.lerp(to.paddle_l_loc, t);
+        state.paddle_r_loc = from.paddle_r_loc.lerp(to.paddle_r_loc, t);
+        Some(state)
+    }
 }
 
 /// Possible messages the server could send to the player.
 #[derive(Debug, Serialize, Deserialize, Component)]
 pub enum ServerMessages {
-    PlayerConnected { id: u64 },
+    /// Someone joined our room. Side is None for spectators.
+    PlayerConnected { id: u64, username: String, side: Option<PlayerSide> },
     PlayerIsSide { side: PlayerSide},
+    /// We're watching a room while we wait in the queue. Anything we knew about the last room we watched is stale.
+    PlayerIsSpectator,
+    /// Where we are in the matchmaking queue, 1 being next in line.
+    QueuePosition { position: usize },
+    /// We've been given a paddle. The room we're about to play in is sent after this, so forget the old one.
+    MatchFound { opponent: Option<String> },
+    /// Both paddles are taken, and the ball gets served after the countdown.
+    MatchStarting { countdown: f32 },
+    /// Something happened in our room. Sent reliably, so effects don't get lost along with a GameState.
+    GameEvent { event: GameEvent },
     PlayerDisconnected { id: u64 },
-    PlayerCheck,
+    /// Someone in our room dropped out. The match is paused and their paddle kept for them for `grace` seconds.
+    PlayerReconnecting { id: u64, grace: f32 },
+    /// Checks we're still here. Answered straight away with ClientMessages::Pong, sent on HEARTBEAT_CHANNEL.
+    Ping { sequence: u32, sent_at: f64 },
+}
+
+/// Sent back by the client on the unreliable channel for every GameState it decodes,
+/// so the server knows which states it can encode the next ones against. See common_wire.rs.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
+pub struct SnapshotAck {
+    pub sequence: u16,
+}
+
+/// What goes back and forth in a rollback match, on ROLLBACK_CHANNEL.
+/// Players send Input and MatchOver to the server, which passes the inputs on to their opponent unchanged.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum RollbackMessage {
+    /// From the server: both paddles are taken, so start simulating a new match from the kickoff.
+    /// Sent on the same channel as the inputs so none of the new match's inputs can turn up before it.
+    Start { round: u32, seed: u64, side: PlayerSide },
+    /// What a player is pressing on a frame of the match numbered `round`.
+    Input { round: u32, frame: u32, input: PlayerInput },
+    /// A player's game says someone won, and every input that depended on has arrived, so it can't change.
+    MatchOver { round: u32, winner: PlayerSide },
 }
 
 /// Possible messages the client could send to the server.
 #[derive(Debug, Serialize, Deserialize, Component)]
 pub enum ClientMessages {
-    PlayerCheckResponse { id: u64 },
+    /// Answers ServerMessages::Ping, echoing it back. Sent on HEARTBEAT_CHANNEL.
+    /// `view_delay_ms` is how far behind the GameStates it gets the client draws the game, its interp_delay_ms, for lag compensation.
+    Pong { sequence: u32, sent_at: f64, view_delay_ms: u32 },
+}
+
+/// How many pings the loss estimate is based on.
+const LOSS_WINDOW: usize = 32;
+/// A ping that hasn't been answered in this long is counted as lost.
+const PING_LOST_AFTER: f64 = 1.0;
+
+/// What we know about the connection to the other end, worked out from pings and pongs.
+/// Times are in seconds, from whatever clock the owner uses, as long as it's always the same one.
+#[derive(Debug)]
+pub struct LinkStats {
+    next_sequence: u32,
+    /// Recent pings, oldest first: their sequence, when they were sent and whether they've been answered.
+    pings: VecDeque<(u32, f64, bool)>,
+    /// Smoothed round trip time, None until the first pong.
+    rtt: Option<f64>,
+    /// How far behind what it's sent the other end draws the game, from its last pong.
+    view_delay: f64,
+    last_heard: f64,
+}
+
+impl LinkStats {
+    pub fn new(now: f64) -> Self {
+        LinkStats { next_sequence: 0, pings: VecDeque::with_capacity(LOSS_WINDOW), rtt: None, view_delay: 0.0, last_heard: now }
+    }
+
+    /// Makes the next ping to send.
+    pub fn ping(&mut self, now: f64) -> ServerMessages {
+        let sequence = self.next_sequence;
+        self.next_sequence = self.next_sequence.wrapping_add(1);
+        if self.pings.len() >= LOSS_WINDOW {
+            self.pings.pop_front();
+        }
+        self.pings.push_back((sequence, now, false));
+        ServerMessages::Ping { sequence, sent_at: now }
+    }
+
+    /// Records a pong. Only pongs for pings we actually sent count, and only once each.
+    pub fn pong(&mut self, sequence: u32, sent_at: f64, view_delay_ms: u32, now: f64) {
+        let ping = match self.pings.iter_mut().find(|(s, t, answered)| *s == sequence && *t == sent_at && !answered) {
+            Some(ping) => ping,
+            None => return,
+        };
+        ping.2 = true;
+        self.heard(now);
+        self.view_delay = view_delay_ms as f64 / 1000.0;
+        // Smoothed the same way TCP does it, so one slow packet doesn't throw the estimate off.
+        let sample = now - sent_at;
+        self.rtt = Some(match self.rtt {
+            Some(rtt) => rtt + (sample - rtt) / 8.0,
+            None => sample,
+        });
+    }
+
+    /// Notes that something arrived from the other end, which shows it's still there even if pongs are getting lost.
+    pub fn heard(&mut self, now: f64) {
+        self.last_heard = self.last_heard.max(now);
+    }
+
+    pub fn rtt(&self) -> Option<f64> {
+        self.rtt
+    }
+
+    /// How far behind what it's sent the other end draws the game, in seconds. 0 until the first pong.
+    pub fn view_delay(&self) -> f64 {
+        self.view_delay
+    }
+
+    /// Fraction of recent pings that went unanswered, between 0 and 1.
+    /// Pings still young enough to be on their way don't count either way.
+    pub fn loss(&self, now: f64) -> f32 {
+        let settled = self.pings.iter().filter(|(_, sent_at, _)| now - sent_at > PING_LOST_AFTER);
+        let (total, lost) = settled.fold((0, 0), |(total, lost), (_, _, answered)| (total + 1, lost + !answered as usize));
+        if total == 0 {
+            return 0.0;
+        }
+        lost as f32 / total as f32
+    }
+
+    /// How long since we last heard anything.
+    pub fn idle_for(&self, now: f64) -> f64 {
+        now - self.last_heard
+    }
 }
 
+/// Lets a client that dropped out of a match take its paddle back. Handed out by the token service with every connect token.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct ResumeToken(pub [u8; 16]);
+
 /// Possible messages the client could to the TCP server.
 #[derive(Debug, Serialize, Deserialize, Component)]
 pub enum ClientMessagesTcp {
     AuthenticationRequest { 
         id: u64,
         username: String,
+        /// Picked at random when the client starts, and sent with every request it makes.
+        /// Client ids change with every attempt, this doesn't, so the token service can tell the same client trying again from someone else after the same name.
+        instance: u64,
+        /// The client's GAME_VERSION.
+        version: String,
+        /// The client's `rules_hash()`.
+        rules_hash: u64,
+    },
+    /// Asks for a connect token to get back into the match we dropped out of. `id` is the new client id to connect with.
+    ResumeRequest {
+        id: u64,
+        resume_token: ResumeToken,
+        version: String,
+        rules_hash: u64,
     },
 }
 
+/// Possible replies the TCP server could send back to the client.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ServerMessagesTcp {
+    /// We're allowed in. The token is a ConnectToken as written by `ConnectToken::write`.
+    /// Keep the resume token in case we drop out and want our paddle back.
+    TokenGranted { token: Vec<u8>, resume_token: ResumeToken },
+    /// The request was refused, the reason is meant to be shown to the player.
+    Rejected { reason: String },
+    /// There's no room for another player right now.
+    ServerFull,
+    /// Someone else has the name, or was just given a token for it. Worth trying again in case they never turn up.
+    NameTaken { username: String },
+    /// The client and server can't play together, one of them needs updating.
+    VersionMismatch { server_version: String, server_rules_hash: u64 },
+}
+
+/// Bincode settings for the TCP messages.
+/// Same encoding as `bincode::serialize`, but with a size limit so we never trust a length prefix blindly.
+fn tcp_bincode() -> impl Options {
+    bincode::options()
+        .with_fixint_encoding()
+        .allow_trailing_bytes()
+        .with_limit(TCP_MESSAGE_LIMIT)
+}
+
+/// Reads a single message from the TCP stream.
+pub fn read_tcp_message<T: DeserializeOwned, R: Read>(reader: R) -> bincode::Result<T> {
+    tcp_bincode().deserialize_from(reader)
+}
+
+/// Writes a single message to the TCP stream.
+pub fn write_tcp_message<T: Serialize, W: Write>(writer: W, message: &T) -> bincode::Result<()> {
+    tcp_bincode().serialize_into(writer, message)
+}
+
+/// Nothing a client sends over renet comes anywhere near this.
+pub const CLIENT_MESSAGE_LIMIT: u64 = 256;
+
+/// Decodes a message a client sent over renet, without trusting it.
+/// Same encoding as `bincode::serialize`, but it has to be exactly one message of the expected type and not suspiciously big.
+pub fn decode_client_message<T: DeserializeOwned>(bytes: &[u8]) -> bincode::Result<T> {
+    bincode::options()
+        .with_fixint_encoding()
+        .reject_trailing_bytes()
+        .with_limit(CLIENT_MESSAGE_LIMIT)
+        .deserialize(bytes)
+}
+
+/// Size of the user data carried in a connect token. This is fixed by netcode.
+pub const USER_DATA_BYTES: usize = 256;
+
+/// Usernames are counted in characters after trimming.
+pub const USERNAME_MIN_LEN: usize = 3;
+pub const USERNAME_MAX_LEN: usize = 16;
+
+/// Why a username was refused.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UsernameError {
+    TooShort,
+    TooLong,
+    InvalidCharacter(char),
+}
+
+impl fmt::Display for UsernameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UsernameError::TooShort => write!(f, "names need at least {} characters", USERNAME_MIN_LEN),
+            UsernameError::TooLong => write!(f, "names can't be longer than {} characters", USERNAME_MAX_LEN),
+            UsernameError::InvalidCharacter(c) => write!(f, "names can't contain {:?}, only letters, digits, spaces, '-' and '_'", c),
+        }
+    }
+}
+
+/// Checks a username and returns the cleaned up version of it.
+/// Both sides run this, the client so it can complain early and the server because it can't trust the client.
+pub fn validate_username(username: &str) -> Result<String, UsernameError> {
+    let username = username.trim();
+    // Only ASCII is allowed, so a name always fits in the token's user data and can't pretend to be someone else with lookalike letters.
+    if let Some(c) = username.chars().find(|c| !(c.is_ascii_alphanumeric() || matches!(c, ' ' | '-' | '_'))) {
+        return Err(UsernameError::InvalidCharacter(c));
+    }
+    if username.len() < USERNAME_MIN_LEN {
+        return Err(UsernameError::TooShort);
+    }
+    if username.len() > USERNAME_MAX_LEN {
+        return Err(UsernameError::TooLong);
+    }
+    Ok(username.to_string())
+}
+
+/// Packs a validated username into connect token user data. The rest of the array is left as zeroes.
+pub fn username_to_user_data(username: &str) -> [u8; USER_DATA_BYTES] {
+    let mut data = [0u8; USER_DATA_BYTES];
+    let bytes = username.as_bytes();
+    let len = bytes.len().min(USER_DATA_BYTES);
+    data[..len].copy_from_slice(&bytes[..len]);
+    data
+}
+
+/// Reads the username back out of connect token user data.
+/// Returns None if it isn't a valid username, which should only happen if the token wasn't made by us.
+pub fn username_from_user_data(data: &[u8]) -> Option<String> {
+    let len = data.iter().position(|&b| b == 0).unwrap_or(data.len());
+    let username = std::str::from_utf8(&data[..len]).ok()?;
+    validate_username(username).ok()
+}
+
+/// Who a connected player is, taken from their connect token.
+#[derive(Debug, Clone, Component)]
+pub struct PlayerProfile {
+    pub username: String,
+}
+
 /// Contains a list of the players and their respective entity.
 #[derive(Debug, Default)]
 pub struct Lobby {
     pub players: HashMap<u64, Entity>,
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A snapshot for `tick` with one room in it, whose ball is at x = `tick`.
+    fn snapshot(tick: u64, room: Entity) -> TickSnapshot {
+        let gamestate = GameState { ball_loc: Vec2::new(tick as f32, 0.0), tick, ..default() };
+        TickSnapshot { tick, server_time: tick as f64 * POLL_RATE as f64, rooms: HashMap::from([(room, gamestate)]) }
+    }
+
+    #[test]
+    fn snapshot_history_finds_rooms_by_tick() {
+        let room = Entity::from_raw(1);
+        let mut history = SnapshotHistory::new(4);
+        assert!(history.room_at(0, room).is_none());
+        for tick in 10..13 {
+            history.push(snapshot(tick, room));
+        }
+        assert_eq!(history.room_at(11, room).unwrap().ball_loc.x, 11.0);
+        assert_eq!(history.get(12).unwrap().tick, 12);
+        // Nothing from before we started or after the newest tick, and nothing for rooms that weren't there.
+        assert!(history.get(9).is_none());
+        assert!(history.get(13).is_none());
+        assert!(history.room_at(11, Entity::from_raw(2)).is_none());
+    }
+
+    #[test]
+    fn snapshot_history_forgets_the_oldest_ticks() {
+        let room = Entity::from_raw(1);
+        let mut history = SnapshotHistory::new(4);
+        for tick in 0..10 {
+            history.push(snapshot(tick, room));
+        }
+        assert!(history.get(5).is_none());
+        for tick in 6..10 {
+            assert_eq!(history.room_at(tick, room).unwrap().ball_loc.x, tick as f32);
+        }
+    }
+
+    #[test]
+    fn usernames_have_to_be_the_right_length() {
+        assert_eq!(validate_username("ab"), Err(UsernameError::TooShort));
+        assert_eq!(validate_username("abc"), Ok("abc".to_string()));
+        assert_eq!(validate_username("abcdefghijklmnop"), Ok("abcdefghijklmnop".to_string()));
+        assert_eq!(validate_username("abcdefghijklmnopq"), Err(UsernameError::TooLong));
+        assert_eq!(validate_username(""), Err(UsernameError::TooShort));
+    }
+
+    #[test]
+    fn usernames_only_use_plain_characters() {
+        assert_eq!(validate_username("Player-1_b 2"), Ok("Player-1_b 2".to_string()));
+        assert_eq!(validate_username("bad!name"), Err(UsernameError::InvalidCharacter('!')));
+        assert_eq!(validate_username("tab\there"), Err(UsernameError::InvalidCharacter('\t')));
+        // A Cyrillic а, which looks just like the Latin one.
+        assert_eq!(validate_username("p\u{430}ddle"), Err(UsernameError::InvalidCharacter('\u{430}')));
+        assert_eq!(validate_username("nul\0l"), Err(UsernameError::InvalidCharacter('\0')));
+    }
+
+    #[test]
+    fn usernames_are_trimmed_before_counting() {
+        assert_eq!(validate_username("  paddle  "), Ok("paddle".to_string()));
+        assert_eq!(validate_username("  ab  "), Err(UsernameError::TooShort));
+        assert_eq!(validate_username(" abcdefghijklmnop "), Ok("abcdefghijklmnop".to_string()));
+        // Spaces inside the name still count.
+        assert_eq!(validate_username("a b"), Ok("a b".to_string()));
+    }
+
+    #[test]
+    fn usernames_round_trip_through_user_data() {
+        for username in ["abc", "Player-1_b 2", "abcdefghijklmnop"] {
+            let data = username_to_user_data(username);
+            assert!(data[username.len()..].iter().all(|&b| b == 0));
+            assert_eq!(username_from_user_data(&data), Some(username.to_string()));
+        }
+    }
+
+    #[test]
+    fn user_data_from_elsewhere_is_refused() {
+        assert_eq!(username_from_user_data(&[0; USER_DATA_BYTES]), None);
+        assert_eq!(username_from_user_data(&username_to_user_data("bad!name")), None);
+        let mut data = username_to_user_data("abc");
+        data[1] = 0xff;
+        assert_eq!(username_from_user_data(&data), None);
+        // No terminating zero, so the whole array is read as the name, which is far too long.
+        assert_eq!(username_from_user_data(&[b'a'; USER_DATA_BYTES]), None);
+    }
+}
@@ -0,0 +1,349 @@
+//! A UDP proxy which makes a good network behave like a bad one, for trying out prediction and interpolation without one.
+//! It sits between renet and the other side and holds back, drops, duplicates and reorders packets going either way.
+//!
+//! Renet owns its socket, so the proxy can't wrap it. Instead:
+//! - the server binds its game socket on loopback and the proxy takes the game port, passing everything through.
+//! - the client points its connect token at a proxy on loopback, which passes everything on to the real server.
+//!   Only the copy of the server's address the client reads is changed, see NetSimProxy::in_front_of_token.
+
+use std::{
+    cmp::Reverse,
+    collections::{hash_map::Entry, BinaryHeap, HashMap},
+    fmt,
+    io::{self, ErrorKind},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use bevy::log::error;
+use rand::{thread_rng, Rng};
+use serde::{Deserialize, Serialize};
+
+/// What happens to every packet, in each direction. Latency is one way, so the round trip is twice that.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct NetConditions {
+    pub latency_ms: f32,
+    /// Each packet's latency is moved by up to this much either way.
+    pub jitter_ms: f32,
+    /// Percent of packets that never arrive.
+    pub loss: f32,
+    /// Percent of packets that arrive twice.
+    pub duplicate: f32,
+    /// Percent of packets that are held back long enough to arrive after the ones sent after them.
+    pub reorder: f32,
+}
+
+/// Named sets of conditions, so nobody has to remember what a bad connection looks like.
+pub const PRESETS: &[(&str, NetConditions)] = &[
+    ("lan", NetConditions { latency_ms: 1.0, jitter_ms: 0.5, loss: 0.0, duplicate: 0.0, reorder: 0.0 }),
+    ("mobile", NetConditions { latency_ms: 40.0, jitter_ms: 15.0, loss: 1.0, duplicate: 0.5, reorder: 1.0 }),
+    ("bad-wifi", NetConditions { latency_ms: 60.0, jitter_ms: 40.0, loss: 5.0, duplicate: 1.0, reorder: 3.0 }),
+];
+
+/// How much longer than usual a reordered packet is held back.
+const REORDER_DELAY_MS: f32 = 30.0;
+
+/// A client we haven't passed anything for, either way, in this long is gone. Its socket facing upstream is closed.
+/// Well past renet's own timeout, so it's only ever clients that already gave up.
+const LINK_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Where the server addresses the client reads start in a written connect token.
+/// They come after the version info, protocol id, both timestamps, the nonce, the encrypted private part and the timeout.
+const TOKEN_ADDRESSES_AT: usize = 13 + 8 + 8 + 8 + 24 + 1024 + 4;
+
+/// How netcode marks the kind of each address in a connect token.
+const TOKEN_ADDRESS_IPV4: u8 = 1;
+const TOKEN_ADDRESS_IPV6: u8 = 2;
+
+impl NetConditions {
+    pub fn preset(name: &str) -> Option<NetConditions> {
+        PRESETS.iter().find(|(preset, _)| *preset == name).map(|(_, conditions)| *conditions)
+    }
+
+    /// When a packet recieved now should be passed on, or None if it's lost.
+    fn delivery_time(&self, now: Instant, rng: &mut impl Rng) -> Option<Instant> {
+        if rng.gen::<f32>() * 100.0 < self.loss {
+            return None;
+        }
+        let mut delay = self.latency_ms + rng.gen_range(-1.0..=1.0) * self.jitter_ms;
+        if rng.gen::<f32>() * 100.0 < self.reorder {
+            delay += REORDER_DELAY_MS + self.jitter_ms;
+        }
+        Some(now + Duration::from_secs_f32(delay.max(0.0) / 1000.0))
+    }
+}
+
+impl fmt::Display for NetConditions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "latency={},jitter={},loss={},duplicate={},reorder={}",
+            self.latency_ms, self.jitter_ms, self.loss, self.duplicate, self.reorder
+        )
+    }
+}
+
+/// Reads a preset name, `key=value` pairs, or a preset followed by pairs to change, like `mobile,loss=10`.
+impl FromStr for NetConditions {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split(',').map(str::trim).filter(|part| !part.is_empty()).peekable();
+        let mut conditions = match parts.peek().and_then(|first| NetConditions::preset(first)) {
+            Some(preset) => {
+                parts.next();
+                preset
+            }
+            None => NetConditions { latency_ms: 0.0, jitter_ms: 0.0, loss: 0.0, duplicate: 0.0, reorder: 0.0 },
+        };
+        for part in parts {
+            let (key, value) = part.split_once('=').ok_or_else(|| {
+                let presets: Vec<&str> = PRESETS.iter().map(|(name, _)| *name).collect();
+                format!("{:?} is neither a preset ({}) nor a key=value pair", part, presets.join(", "))
+            })?;
+            let value: f32 = value.trim().parse().map_err(|e| format!("{}: {}", key, e))?;
+            if value < 0.0 {
+                return Err(format!("{} can't be negative", key));
+            }
+            match key.trim() {
+                "latency" => conditions.latency_ms = value,
+                "jitter" => conditions.jitter_ms = value,
+                "loss" => conditions.loss = value,
+                "duplicate" => conditions.duplicate = value,
+                "reorder" => conditions.reorder = value,
+                other => return Err(format!("unknown condition {:?}, expected latency, jitter, loss, duplicate or reorder", other)),
+            }
+        }
+        Ok(conditions)
+    }
+}
+
+impl TryFrom<String> for NetConditions {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<NetConditions> for String {
+    fn from(conditions: NetConditions) -> Self {
+        conditions.to_string()
+    }
+}
+
+/// Which way a held back packet is going. Both carry the address of the client it's to or from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Route {
+    ToUpstream(SocketAddr),
+    ToClient(SocketAddr),
+}
+
+/// A packet waiting to be passed on. Ordered by when it's due, then by when it came in.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct Delayed {
+    at: Instant,
+    order: u64,
+    route: Route,
+    bytes: Vec<u8>,
+}
+
+/// A running proxy. It stops when this is dropped.
+pub struct NetSimProxy {
+    local_addr: SocketAddr,
+    stop: Arc<AtomicBool>,
+}
+
+impl NetSimProxy {
+    /// Listens on `listen` and passes everything on to `upstream`, and the replies back, under the given conditions.
+    /// Every client gets its own socket facing upstream, so the other side can still tell them apart.
+    pub fn start(listen: SocketAddr, upstream: SocketAddr, conditions: NetConditions) -> std::io::Result<NetSimProxy> {
+        let front = UdpSocket::bind(listen)?;
+        front.set_nonblocking(true)?;
+        let local_addr = front.local_addr()?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = stop.clone();
+        thread::spawn(move || run(front, upstream, conditions, thread_stop));
+        Ok(NetSimProxy { local_addr, stop })
+    }
+
+    /// Starts a proxy on loopback in front of the server a written connect token is for, and points the token at it instead.
+    /// The server only checks the copy of its address in the encrypted part of the token, which is left alone.
+    /// Renet's client only ever uses the first address, so that's the one that gets replaced.
+    pub fn in_front_of_token(token: &mut [u8], conditions: NetConditions) -> io::Result<NetSimProxy> {
+        let (at, server) = token_server_addr(token)?;
+        // Same kind of address as the server's, so it takes the same space in the token.
+        let loopback: IpAddr = match server {
+            SocketAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+            SocketAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+        };
+        let proxy = NetSimProxy::start(SocketAddr::new(loopback, 0), server, conditions)?;
+        let ip = match proxy.local_addr.ip() {
+            IpAddr::V4(ip) => ip.octets().to_vec(),
+            IpAddr::V6(ip) => ip.octets().to_vec(),
+        };
+        token[at..at + ip.len()].copy_from_slice(&ip);
+        token[at + ip.len()..at + ip.len() + 2].copy_from_slice(&proxy.local_addr.port().to_le_bytes());
+        Ok(proxy)
+    }
+
+    /// Where to send packets to go through the proxy.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+}
+
+/// Reads the first server address out of a written connect token, along with where its ip starts.
+fn token_server_addr(token: &[u8]) -> io::Result<(usize, SocketAddr)> {
+    let invalid = || io::Error::new(ErrorKind::InvalidData, "not a connect token with a server address");
+    let count = token.get(TOKEN_ADDRESSES_AT..TOKEN_ADDRESSES_AT + 4).ok_or_else(invalid)?;
+    if count.iter().all(|&b| b == 0) {
+        return Err(invalid());
+    }
+    let at = TOKEN_ADDRESSES_AT + 5;
+    let ip: IpAddr = match token.get(TOKEN_ADDRESSES_AT + 4) {
+        Some(&TOKEN_ADDRESS_IPV4) => <[u8; 4]>::try_from(token.get(at..at + 4).ok_or_else(invalid)?).unwrap().into(),
+        Some(&TOKEN_ADDRESS_IPV6) => <[u8; 16]>::try_from(token.get(at..at + 16).ok_or_else(invalid)?).unwrap().into(),
+        _ => return Err(invalid()),
+    };
+    let port_at = at + if ip.is_ipv4() { 4 } else { 16 };
+    let port = token.get(port_at..port_at + 2).ok_or_else(invalid)?;
+    Ok((at, SocketAddr::new(ip, u16::from_le_bytes([port[0], port[1]]))))
+}
+
+/// The socket we talk to upstream on for one client, and when we last passed anything on for them.
+struct Link {
+    socket: UdpSocket,
+    last_active: Instant,
+}
+
+impl Drop for NetSimProxy {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+fn run(front: UdpSocket, upstream: SocketAddr, conditions: NetConditions, stop: Arc<AtomicBool>) {
+    let mut rng = thread_rng();
+    let mut buffer = [0; 2048];
+    let mut queue = BinaryHeap::new();
+    let mut order = 0;
+    let mut links: HashMap<SocketAddr, Link> = HashMap::new();
+    let mut last_eviction = Instant::now();
+    let unspecified: SocketAddr = if upstream.is_ipv4() { "0.0.0.0:0".parse().unwrap() } else { "[::]:0".parse().unwrap() };
+
+    while !stop.load(Ordering::Relaxed) {
+        let now = Instant::now();
+        let mut recieved = Vec::new();
+
+        // From the clients.
+        loop {
+            match front.recv_from(&mut buffer) {
+                Ok((len, from)) => {
+                    match links.entry(from) {
+                        Entry::Occupied(mut entry) => entry.get_mut().last_active = now,
+                        Entry::Vacant(entry) => {
+                            let socket = match UdpSocket::bind(unspecified).and_then(|socket| socket.set_nonblocking(true).map(|_| socket)) {
+                                Ok(socket) => socket,
+                                Err(e) => {
+                                    error!("Network simulator could not open a socket for {}: {}", from, e);
+                                    continue;
+                                }
+                            };
+                            entry.insert(Link { socket, last_active: now });
+                        }
+                    }
+                    recieved.push((Route::ToUpstream(from), buffer[..len].to_vec()));
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                // Usually an ICMP error from a client that went away. Nothing to do about it.
+                Err(_) => break,
+            }
+        }
+        // From upstream, back to each client.
+        for (&client, link) in links.iter_mut() {
+            while let Ok((len, _)) = link.socket.recv_from(&mut buffer) {
+                link.last_active = now;
+                recieved.push((Route::ToClient(client), buffer[..len].to_vec()));
+            }
+        }
+        if now.duration_since(last_eviction) >= Duration::from_secs(1) {
+            links.retain(|_, link| now.duration_since(link.last_active) < LINK_IDLE_TIMEOUT);
+            last_eviction = now;
+        }
+
+        let idle = recieved.is_empty();
+        for (route, bytes) in recieved {
+            let copies = if rng.gen::<f32>() * 100.0 < conditions.duplicate { 2 } else { 1 };
+            for _ in 0..copies {
+                if let Some(at) = conditions.delivery_time(now, &mut rng) {
+                    queue.push(Reverse(Delayed { at, order, route, bytes: bytes.clone() }));
+                    order += 1;
+                }
+            }
+        }
+
+        // Pass on everything that's due.
+        while queue.peek().is_some_and(|Reverse(next)| next.at <= now) {
+            let Reverse(packet) = queue.pop().unwrap();
+            // It's UDP, a failed send is just one more lost packet. So is one for a client whose link was closed in the meantime.
+            let _ = match packet.route {
+                Route::ToUpstream(client) => match links.get(&client) {
+                    Some(link) => link.socket.send_to(&packet.bytes, upstream),
+                    None => continue,
+                },
+                Route::ToClient(client) => front.send_to(&packet.bytes, client),
+            };
+        }
+
+        if idle {
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bevy_renet::renet::ConnectToken;
+
+    fn written_token(server: SocketAddr) -> Vec<u8> {
+        let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap();
+        let token = ConnectToken::generate(now, 7, 300, 1, 15, vec![server], None, &[3; 32]).unwrap();
+        let mut bytes = Vec::new();
+        token.write(&mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn reads_the_server_address_out_of_a_token() {
+        for server in ["203.0.113.5:5001", "[2001:db8::5]:5001"] {
+            let server: SocketAddr = server.parse().unwrap();
+            assert_eq!(token_server_addr(&written_token(server)).unwrap().1, server);
+        }
+        assert!(token_server_addr(&[0; 100]).is_err());
+    }
+
+    #[test]
+    fn points_tokens_at_the_proxy() {
+        let conditions = NetConditions::preset("lan").unwrap();
+        let server: SocketAddr = "203.0.113.5:5001".parse().unwrap();
+        let mut token = written_token(server);
+        let untouched = token.clone();
+        let proxy = NetSimProxy::in_front_of_token(&mut token, conditions).unwrap();
+        assert!(proxy.local_addr().ip().is_loopback());
+        assert_eq!(token_server_addr(&token).unwrap().1, proxy.local_addr());
+        // Everything but the address is the same, and renet still reads it.
+        let (at, _) = token_server_addr(&token).unwrap();
+        assert_eq!(token[..at], untouched[..at]);
+        assert_eq!(token[at + 6..], untouched[at + 6..]);
+        assert!(ConnectToken::read(&mut token.as_slice()).is_ok());
+    }
+}
@@ -0,0 +1,300 @@
+//! Rollback netcode, the other way of playing a match. See NetcodeMode.
+//! Instead of the server simulating the match and sending everyone the result, both players simulate it themselves,
+//! from the same starting state and the same inputs, and the server just passes the inputs between them.
+//!
+//! Our own inputs are used a few frames after they're pressed (input delay), which gives them time to reach the opponent.
+//! The opponent's inputs usually turn up late anyway, so we guess they're still pressing whatever they were last time and carry on.
+//! When their real input arrives and the guess was wrong, we go back to the frame it was for and simulate everything since again.
+
+use std::collections::BTreeMap;
+
+use crate::common_game::{GameEvent, PlayerSide};
+use crate::common_net::{PlayerInput, RollbackMessage};
+use crate::common_sim::{PongSim, SimEvent};
+
+/// How far we'll run ahead of the last frame we have the opponent's input for. Past this we wait for them rather than keep guessing.
+pub const MAX_PREDICTION_FRAMES: u32 = 60;
+
+/// Our side of one rollback match: the match as we have it now, the inputs so far, the guesses we made, and the states we might have to go back to.
+pub struct RollbackSession {
+    pub side: PlayerSide,
+    /// Which of the room's matches this is. Inputs from earlier ones are thrown away.
+    pub round: u32,
+    /// Serve angles come from this, so both players serve the same way.
+    pub seed: u64,
+    input_delay: u32,
+    /// The match at the start of `frame`.
+    sim: PongSim,
+    /// The next frame to simulate.
+    frame: u32,
+    /// Every opponent input before this frame has arrived, so nothing before it can change any more.
+    confirmed: u32,
+    local: BTreeMap<u32, PlayerInput>,
+    remote: BTreeMap<u32, PlayerInput>,
+    /// What we guessed the opponent pressed on frames we simulated without their input.
+    predicted: BTreeMap<u32, PlayerInput>,
+    /// The earliest frame we guessed wrong on since we last went back.
+    rollback_to: Option<u32>,
+    /// The state at the start of every frame that might still have to be simulated again.
+    saved: BTreeMap<u32, PongSim>,
+    /// Messages waiting to be sent to the server.
+    outbox: Vec<RollbackMessage>,
+    /// Who our game says won, and on which frame, until we're sure of it and have told the server.
+    result: Option<(PlayerSide, u32)>,
+    reported: bool,
+}
+
+impl RollbackSession {
+    /// Starts a match, waiting for the first serve. Our inputs are used `input_delay` frames after they're pressed.
+    pub fn new(side: PlayerSide, round: u32, seed: u64, input_delay: u32) -> Self {
+        let mut sim = PongSim::new(seed);
+        sim.restart();
+        let mut session = RollbackSession {
+            side,
+            round,
+            seed,
+            input_delay,
+            sim,
+            frame: 0,
+            confirmed: 0,
+            local: BTreeMap::new(),
+            remote: BTreeMap::new(),
+            predicted: BTreeMap::new(),
+            rollback_to: None,
+            saved: BTreeMap::new(),
+            outbox: Vec::new(),
+            result: None,
+            reported: false,
+        };
+        // Nothing is pressed on the frames before our first input takes effect. The opponent is told so, since their delay could be shorter.
+        for frame in 0..input_delay {
+            session.push_local(frame, PlayerInput::default());
+        }
+        session
+    }
+
+    pub fn frame(&self) -> u32 {
+        self.frame
+    }
+
+    /// The match as it stands, our best guess at it anyway.
+    pub fn sim(&self) -> &PongSim {
+        &self.sim
+    }
+
+    /// Runs one frame of the match, with `input` being what we're pressing now.
+    /// First goes back and simulates again from wherever we guessed the opponent's input wrong, then simulates the next frame.
+    /// Gives back what happened on the new frame. Going back over old frames is silent, their events were given out the first time.
+    pub fn update(&mut self, input: PlayerInput) -> Vec<SimEvent> {
+        // Too far ahead of the opponent. Wait for them to catch up rather than guess any further.
+        let advance = self.can_advance();
+        if advance {
+            self.push_local(self.frame + self.input_delay, input);
+        }
+
+        if let Some(from) = self.rollback_to.take() {
+            // Whatever happened after that might not happen this time.
+            if self.result.is_some_and(|(_, result_frame)| result_frame >= from) {
+                self.result = None;
+            }
+            if let Some(state) = self.saved.get(&from) {
+                self.sim = state.clone();
+                for frame in from..self.frame {
+                    self.simulate(frame);
+                }
+            }
+        }
+
+        if !advance {
+            return Vec::new();
+        }
+        let events = self.simulate(self.frame);
+        self.frame += 1;
+        // Forget anything about frames that can't change any more.
+        let keep = self.confirmed.min(self.frame);
+        self.saved = self.saved.split_off(&keep);
+        self.predicted = self.predicted.split_off(&keep);
+        self.local = self.local.split_off(&keep);
+        // The last input before that is still needed to guess from.
+        self.remote = self.remote.split_off(&keep.saturating_sub(1));
+        events
+    }
+
+    /// False while we're too far ahead of the opponent, see MAX_PREDICTION_FRAMES.
+    fn can_advance(&self) -> bool {
+        self.frame < self.confirmed + MAX_PREDICTION_FRAMES
+    }
+
+    fn push_local(&mut self, frame: u32, input: PlayerInput) {
+        self.local.insert(frame, input);
+        self.outbox.push(RollbackMessage::Input { round: self.round, frame, input });
+    }
+
+    /// Records an input from the opponent. If we already simulated that frame with a different guess, we'll have to go back to it.
+    pub fn add_remote(&mut self, frame: u32, input: PlayerInput) {
+        if frame < self.confirmed {
+            return;
+        }
+        self.remote.insert(frame, input);
+        if self.predicted.remove(&frame).is_some_and(|guess| guess != input) {
+            self.rollback_to = Some(self.rollback_to.map_or(frame, |earliest| earliest.min(frame)));
+        }
+        while self.remote.contains_key(&self.confirmed) {
+            self.confirmed += 1;
+        }
+    }
+
+    /// Simulates `frame` with whatever inputs we have for it, saving the state it started from in case we have to come back.
+    fn simulate(&mut self, frame: u32) -> Vec<SimEvent> {
+        self.saved.insert(frame, self.sim.clone());
+        let local = self.local.get(&frame).copied().unwrap_or_default();
+        let remote = match self.remote.get(&frame) {
+            Some(input) => *input,
+            None => {
+                // Guess they're still pressing whatever they were last time we heard.
+                let guess = self.remote.range(..frame).next_back().map(|(_, input)| *input).unwrap_or_default();
+                self.predicted.insert(frame, guess);
+                guess
+            }
+        };
+        let inputs = match self.side {
+            PlayerSide::Left => [local, remote],
+            PlayerSide::Right => [remote, local],
+        };
+        let events = self.sim.step(inputs);
+        for event in &events {
+            if let GameEvent::MatchOver { winner, .. } = *event {
+                if !self.reported {
+                    self.result = Some((winner, frame));
+                }
+            }
+        }
+        events
+    }
+
+    /// Everything waiting to be sent to the server. The result is only included once every input it depended on is in,
+    /// since until then it could still be rolled back.
+    pub fn take_outgoing(&mut self) -> Vec<RollbackMessage> {
+        if let Some((winner, frame)) = self.result {
+            if self.confirmed > frame && !self.reported {
+                self.reported = true;
+                self.outbox.push(RollbackMessage::MatchOver { round: self.round, winner });
+            }
+        }
+        std::mem::take(&mut self.outbox)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Something for a player to press on every frame, changing often enough that guesses are usually wrong.
+    fn script(side: PlayerSide, frame: u32) -> PlayerInput {
+        let phase = (frame / 23 + side as u32) % 3;
+        PlayerInput { up: phase == 0, down: phase == 1, ..Default::default() }
+    }
+
+    /// The match played straight through with everyone's real inputs, no prediction involved.
+    fn played_straight(seed: u64, frames: u32) -> PongSim {
+        let mut sim = PongSim::new(seed);
+        sim.restart();
+        for frame in 0..frames {
+            sim.step([script(PlayerSide::Left, frame), script(PlayerSide::Right, frame)]);
+        }
+        sim
+    }
+
+    /// Passes on the inputs in `messages` as if they came from the other player.
+    fn deliver(messages: Vec<RollbackMessage>, to: &mut RollbackSession) {
+        for message in messages {
+            if let RollbackMessage::Input { frame, input, .. } = message {
+                to.add_remote(frame, input);
+            }
+        }
+    }
+
+    #[test]
+    fn a_wrong_guess_is_played_again_with_the_real_input() {
+        let mut session = RollbackSession::new(PlayerSide::Left, 0, 5, 0);
+        let mut rolled_back = false;
+        for frame in 0..600 {
+            // The opponent's inputs turn up in bunches, 10 frames late.
+            if frame % 10 == 0 && frame >= 10 {
+                for late in frame - 10..frame {
+                    session.add_remote(late, script(PlayerSide::Right, late));
+                }
+                rolled_back |= session.rollback_to.is_some();
+            }
+            session.update(script(PlayerSide::Left, frame));
+        }
+        assert!(rolled_back, "the guesses were never wrong, so nothing was tested");
+        assert_ne!(session.sim(), &played_straight(5, 600));
+
+        for late in 590..=600 {
+            session.add_remote(late, script(PlayerSide::Right, late));
+        }
+        session.update(script(PlayerSide::Left, 600));
+        assert_eq!(session.sim(), &played_straight(5, 601));
+    }
+
+    #[test]
+    fn our_input_waits_for_the_input_delay() {
+        let delay = 3;
+        let mut session = RollbackSession::new(PlayerSide::Right, 0, 5, delay);
+        let up = PlayerInput { up: true, ..Default::default() };
+        let start = session.sim().paddles[1];
+        for frame in 0..delay {
+            session.update(up);
+            session.add_remote(frame, PlayerInput::default());
+            assert_eq!(session.sim().paddles[1], start, "moved on frame {}", frame);
+        }
+        session.update(up);
+        assert_ne!(session.sim().paddles[1], start);
+
+        // The opponent was told nothing was pressed before the delay, and when our first press takes effect.
+        let sent: Vec<(u32, PlayerInput)> = session.take_outgoing().into_iter()
+            .filter_map(|message| match message {
+                RollbackMessage::Input { frame, input, .. } => Some((frame, input)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(sent[..delay as usize], [(0, PlayerInput::default()), (1, PlayerInput::default()), (2, PlayerInput::default())]);
+        assert_eq!(sent[delay as usize], (delay, up));
+    }
+
+    #[test]
+    fn two_players_end_up_with_the_same_match() {
+        let mut left = RollbackSession::new(PlayerSide::Left, 0, 9, 2);
+        let mut right = RollbackSession::new(PlayerSide::Right, 0, 9, 2);
+        // Each side's messages take 6 frames to reach the other.
+        let mut in_flight: Vec<(u32, Vec<RollbackMessage>, Vec<RollbackMessage>)> = Vec::new();
+        for frame in 0..900 {
+            left.update(script(PlayerSide::Left, frame));
+            right.update(script(PlayerSide::Right, frame));
+            in_flight.push((frame + 6, left.take_outgoing(), right.take_outgoing()));
+            while in_flight.first().is_some_and(|(arrives, _, _)| *arrives <= frame) {
+                let (_, from_left, from_right) = in_flight.remove(0);
+                deliver(from_left, &mut right);
+                deliver(from_right, &mut left);
+            }
+        }
+        for (_, from_left, from_right) in in_flight {
+            deliver(from_left, &mut right);
+            deliver(from_right, &mut left);
+        }
+        left.update(script(PlayerSide::Left, 900));
+        right.update(script(PlayerSide::Right, 900));
+
+        assert_eq!(left.frame(), right.frame());
+        assert_eq!(left.sim(), right.sim());
+        // Our inputs are two frames behind the script, so the first two frames had nothing pressed.
+        let mut expected = PongSim::new(9);
+        expected.restart();
+        for frame in 0..=900 {
+            let input = |side| if frame < 2 { PlayerInput::default() } else { script(side, frame - 2) };
+            expected.step([input(PlayerSide::Left), input(PlayerSide::Right)]);
+        }
+        assert_eq!(left.sim(), &expected);
+    }
+}
@@ -0,0 +1,656 @@
+//! The rules of Pong on their own, with no Bevy in sight.
+//! A PongSim is everything about one match that changes, and `step` moves it on by one fixed update.
+//! The server's systems, rollback matches, tests and tools all drive the game through this, so they can't disagree on how it plays.
+//!
+//! Nothing in here reads the clock or the thread's random numbers. The same state and the same inputs always give the same result.
+//! On the same machine anyway. With the `fixed-point` feature it runs on Fixed instead of f32, and then it's the same on every machine too.
+
+use std::ops::{Add, Div, Mul, Sub};
+
+use bevy::math::Vec2;
+use serde::{Deserialize, Serialize};
+
+use crate::common_game::{
+    fnv1a, GameEvent, PlayerSide, WallLocation, BALL_SIZE, BALL_SPEED, BALL_SPEED_INCREASE, BALL_STARTING_POSITION,
+    BOTTOM_WALL, DEG_TO_RAD, GAP_BETWEEN_PADDLE_AND_WALL, LEFT_WALL, MAX_BALL_SPEED, PADDLE_PADDING, PADDLE_SIZE, PADDLE_SPEED,
+    RESPAWN_DELAY, RIGHT_WALL, TIME_STEP, TOP_WALL, WALL_THICKNESS, WINNING_SCORE,
+};
+#[cfg(feature = "fixed-point")]
+use crate::common_fixed::Fixed;
+use crate::common_net::{GameState, PlayerInput, POLL_RATE};
+
+/// The kind of number positions, velocities and everything worked out from them are kept in.
+#[cfg(not(feature = "fixed-point"))]
+pub type Real = f32;
+/// The kind of number positions, velocities and everything worked out from them are kept in.
+#[cfg(feature = "fixed-point")]
+pub type Real = Fixed;
+
+/// Turns a constant, or anything else from outside the simulation, into a Real.
+#[cfg(not(feature = "fixed-point"))]
+pub fn real(value: f32) -> Real {
+    value
+}
+/// Turns a constant, or anything else from outside the simulation, into a Real.
+#[cfg(feature = "fixed-point")]
+pub fn real(value: f32) -> Real {
+    Fixed::from_f32(value)
+}
+
+/// Turns a Real back into an f32, for Bevy and the network.
+#[cfg(not(feature = "fixed-point"))]
+pub fn float(value: Real) -> f32 {
+    value
+}
+/// Turns a Real back into an f32, for Bevy and the network.
+#[cfg(feature = "fixed-point")]
+pub fn float(value: Real) -> f32 {
+    value.to_f32()
+}
+
+/// A position or velocity in the simulation. Vec2, but made of Reals.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct SimVec {
+    pub x: Real,
+    pub y: Real,
+}
+
+impl SimVec {
+    pub fn new(x: Real, y: Real) -> Self {
+        SimVec { x, y }
+    }
+
+    pub fn from_vec2(vec: Vec2) -> Self {
+        SimVec::new(real(vec.x), real(vec.y))
+    }
+
+    pub fn to_vec2(self) -> Vec2 {
+        Vec2::new(float(self.x), float(self.y))
+    }
+}
+
+impl Add for SimVec {
+    type Output = SimVec;
+    fn add(self, other: SimVec) -> SimVec {
+        SimVec::new(self.x + other.x, self.y + other.y)
+    }
+}
+
+impl Sub for SimVec {
+    type Output = SimVec;
+    fn sub(self, other: SimVec) -> SimVec {
+        SimVec::new(self.x - other.x, self.y - other.y)
+    }
+}
+
+impl Mul<Real> for SimVec {
+    type Output = SimVec;
+    fn mul(self, scale: Real) -> SimVec {
+        SimVec::new(self.x * scale, self.y * scale)
+    }
+}
+
+impl Div<Real> for SimVec {
+    type Output = SimVec;
+    fn div(self, scale: Real) -> SimVec {
+        SimVec::new(self.x / scale, self.y / scale)
+    }
+}
+
+/// What a step can report. These are the same GameEvents the server passes on to players.
+pub type SimEvent = GameEvent;
+
+/// How many steps the ball waits in the middle after a point is scored, RESPAWN_DELAY in fixed updates.
+pub fn serve_delay_steps() -> u32 {
+    (RESPAWN_DELAY / TIME_STEP).round() as u32
+}
+
+/// Where a side's paddle sits across the arena. Paddles only ever move up and down.
+pub fn paddle_x(side: PlayerSide) -> f32 {
+    match side {
+        PlayerSide::Left => LEFT_WALL + GAP_BETWEEN_PADDLE_AND_WALL,
+        PlayerSide::Right => RIGHT_WALL - GAP_BETWEEN_PADDLE_AND_WALL,
+    }
+}
+
+/// Moves a paddle by one step of a player's input, without letting it into the walls.
+/// common_game's move_paddle is this, for paddles that live on Transforms.
+pub fn step_paddle(y: Real, input: &PlayerInput, delta: Real) -> Real {
+    let direction = real((input.up as i8 - input.down as i8) as f32);
+    let bottom_bound = real(BOTTOM_WALL) + real(WALL_THICKNESS) / real(2.0) + real(PADDLE_SIZE.y) / real(2.0) + real(PADDLE_PADDING);
+    let top_bound = real(TOP_WALL) - real(WALL_THICKNESS) / real(2.0) - real(PADDLE_SIZE.y) / real(2.0) - real(PADDLE_PADDING);
+    (y + direction * real(PADDLE_SPEED) * delta).clamp(bottom_bound, top_bound)
+}
+
+/// Which way a serve at `degrees` goes. Vec2::from_angle, which uses the platform's sin and cos.
+#[cfg(not(feature = "fixed-point"))]
+fn serve_direction(degrees: Real) -> SimVec {
+    SimVec::from_vec2(Vec2::from_angle(degrees * DEG_TO_RAD))
+}
+/// Which way a serve at `degrees` goes, using Fixed's sin and cos.
+#[cfg(feature = "fixed-point")]
+fn serve_direction(degrees: Real) -> SimVec {
+    // Fixed::sin_cos is only good near 0, so serves to the left are worked out as serves to the right turned around.
+    if degrees > real(90.0) {
+        let (sin, cos) = ((degrees - real(180.0)) * real(DEG_TO_RAD)).sin_cos();
+        SimVec::new(-cos, -sin)
+    } else {
+        let (sin, cos) = (degrees * real(DEG_TO_RAD)).sin_cos();
+        SimVec::new(cos, sin)
+    }
+}
+
+/// A small random number generator whose whole state is one number, so it can be saved and sent along with the rest of the match.
+/// This is SplitMix64, which gives the same numbers on every platform.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SimRng(pub u64);
+
+impl SimRng {
+    pub fn new(seed: u64) -> Self {
+        SimRng(seed)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    /// Anywhere from 0 up to, but not including, 1.
+    pub fn next_f32(&mut self) -> f32 {
+        // The top 24 bits are as many as an f32 holds exactly.
+        (self.next_u64() >> 40) as f32 / (1u64 << 24) as f32
+    }
+}
+
+/// The most times the ball can bounce in one step. Past this it stops where it is until the next step.
+/// Only a ball wedged into a corner would ever get near it, but it makes sure a step always finishes.
+const MAX_BOUNCES_PER_STEP: usize = 4;
+
+/// Something the ball can run into.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Obstacle {
+    Wall(WallLocation),
+    Paddle(PlayerSide),
+}
+
+/// The box the ball's centre can't get into without touching something at `position` of `size`.
+/// Growing everything by half the ball means we only have to follow the ball's centre.
+fn grown_box(position: SimVec, size: SimVec) -> (SimVec, SimVec) {
+    let half_ball = SimVec::from_vec2(BALL_SIZE.truncate()) / real(2.0);
+    (position - size / real(2.0) - half_ball, position + size / real(2.0) + half_ball)
+}
+
+/// Which way along x the ball goes to get to a side's goal.
+fn towards(side: PlayerSide) -> Real {
+    match side {
+        PlayerSide::Left => real(-1.0),
+        PlayerSide::Right => real(1.0),
+    }
+}
+
+/// When a point moving along `travel` enters the slab between `min` and `max`, and when it leaves again,
+/// as fractions of `travel`. None if it's never between them.
+fn slab(point: Real, travel: Real, min: Real, max: Real) -> Option<(Real, Real)> {
+    if travel == real(0.0) {
+        // Not moving on this axis, so it's either between them the whole time or never.
+        return if min < point && point < max { Some((Real::MIN, Real::MAX)) } else { None };
+    }
+    let to_min = (min - point) / travel;
+    let to_max = (max - point) / travel;
+    Some((to_min.min(to_max), to_min.max(to_max)))
+}
+
+/// Whether a point is inside the box from `min` to `max`. Being on its edge doesn't count.
+fn inside(point: SimVec, min: SimVec, max: SimVec) -> bool {
+    min.x < point.x && point.x < max.x && min.y < point.y && point.y < max.y
+}
+
+/// How far along `travel` a point first touches the box from `min` to `max`, and the normal of the side it touches.
+/// Hitting exactly on a corner gives a normal with both x and y set, so it bounces off both.
+/// A point that starts inside the box never touches it. That only happens when a paddle moves on top of the ball,
+/// and `PongSim::push_out_of_paddles` gets it out before it's swept.
+fn time_of_impact(point: SimVec, travel: SimVec, min: SimVec, max: SimVec) -> Option<(Real, SimVec)> {
+    if inside(point, min, max) {
+        return None;
+    }
+
+    let (x_entry, x_exit) = slab(point.x, travel.x, min.x, max.x)?;
+    let (y_entry, y_exit) = slab(point.y, travel.y, min.y, max.y)?;
+    let entry = x_entry.max(y_entry);
+    let exit = x_exit.min(y_exit);
+    // It has to get in before it gets out, and do so during this step. Only grazing an edge doesn't count.
+    if entry >= exit || entry < real(0.0) || entry > real(1.0) {
+        return None;
+    }
+    // It came in through whichever side it reached last. Reaching both at once is a corner.
+    let mut normal = SimVec::default();
+    if x_entry >= y_entry {
+        normal.x = -travel.x.signum();
+    }
+    if y_entry >= x_entry {
+        normal.y = -travel.y.signum();
+    }
+    Some((entry, normal))
+}
+
+/// Everything about one match that changes as it's played.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PongSim {
+    pub ball: SimVec,
+    pub ball_velocity: SimVec,
+    /// Which side scored last, the ball is served away from them.
+    pub lastpointleft: bool,
+    /// Heights of the left and right paddle.
+    pub paddles: [Real; 2],
+    /// Left and right score.
+    pub score: [usize; 2],
+    /// Whether the ball is in play. Nothing happens while it isn't.
+    pub playing: bool,
+    /// Steps until the ball is served. 0 once it has been.
+    pub serve_in: u32,
+    /// The step that runs next. Events from a step carry its tick.
+    pub tick: u64,
+    /// Where serve angles come from.
+    pub rng: SimRng,
+    /// A goal against this side that's waiting for its player to see the ball get there, see `step_compensated`.
+    /// The ball stays where it is until then.
+    pub goal_waiting: Option<PlayerSide>,
+}
+
+impl PongSim {
+    /// A room waiting for a match to start: ball in the middle and not moving, paddles centred, nobody scored.
+    pub fn new(seed: u64) -> Self {
+        PongSim {
+            ball: SimVec::from_vec2(BALL_STARTING_POSITION.truncate()),
+            ball_velocity: SimVec::default(),
+            lastpointleft: false,
+            paddles: [real(0.0); 2],
+            score: [0; 2],
+            playing: false,
+            serve_in: serve_delay_steps(),
+            tick: 0,
+            rng: SimRng::new(seed),
+            goal_waiting: None,
+        }
+    }
+
+    /// Puts the ball and paddles back in the middle, and starts the match again from the serve. The score is kept.
+    pub fn restart(&mut self) {
+        self.ball = SimVec::from_vec2(BALL_STARTING_POSITION.truncate());
+        self.ball_velocity = SimVec::default();
+        self.paddles = [real(0.0); 2];
+        self.serve_in = serve_delay_steps();
+        self.playing = true;
+        self.goal_waiting = None;
+    }
+
+    /// Who won, once someone has.
+    pub fn winner(&self) -> Option<PlayerSide> {
+        if self.score[0] >= WINNING_SCORE {
+            Some(PlayerSide::Left)
+        } else if self.score[1] >= WINNING_SCORE {
+            Some(PlayerSide::Right)
+        } else {
+            None
+        }
+    }
+
+    /// The match as it goes over the network. Only the fields a PongSim knows about are filled in.
+    pub fn gamestate(&self) -> GameState {
+        GameState {
+            ball_loc: self.ball.to_vec2(),
+            ball_velocity: self.ball_velocity.to_vec2(),
+            paddle_l_loc: Vec2::new(paddle_x(PlayerSide::Left), float(self.paddles[0])),
+            paddle_r_loc: Vec2::new(paddle_x(PlayerSide::Right), float(self.paddles[1])),
+            score_l: self.score[0] as i32,
+            score_r: self.score[1] as i32,
+            playing: self.playing,
+            last_input: 0,
+            server_time: 0.0,
+            tick: self.tick,
+        }
+    }
+
+    /// A hash of the whole state. Two simulations with the same hash are, as far as anyone can tell, in the same state.
+    pub fn state_hash(&self) -> u64 {
+        fnv1a(bincode::serialize(self).unwrap())
+    }
+
+    /// Runs one fixed update. `inputs` move the left and right paddle for TIME_STEP.
+    pub fn step(&mut self, inputs: [PlayerInput; 2]) -> Vec<SimEvent> {
+        self.move_paddles(inputs);
+        self.step_ball([None; 2])
+    }
+
+    /// `step`, for when the players see the ball late. See LagCompensation.
+    /// `seen` is where the left and right player saw the ball go during this step, from and to, or None for a player who's up to date.
+    /// Their own paddle they see where it is now, since their client predicts it. So a ball that got past a paddle still bounces off it
+    /// if its player saw it hit the paddle where it is now, and a goal waits until its player has seen the ball get past them.
+    pub fn step_compensated(&mut self, inputs: [PlayerInput; 2], seen: [Option<(SimVec, SimVec)>; 2]) -> Vec<SimEvent> {
+        self.move_paddles(inputs);
+        self.step_ball(seen)
+    }
+
+    /// Moves one paddle by one of its player's inputs, for POLL_RATE, which is how far their client predicted it would go.
+    /// The server's matches move the paddles with this as inputs come in, and step them with no inputs.
+    pub fn play_input(&mut self, side: PlayerSide, input: &PlayerInput) {
+        let y = &mut self.paddles[side as usize];
+        *y = step_paddle(*y, input, real(POLL_RATE));
+    }
+
+    fn move_paddles(&mut self, inputs: [PlayerInput; 2]) {
+        for (y, input) in self.paddles.iter_mut().zip(inputs.iter()) {
+            *y = step_paddle(*y, input, real(TIME_STEP));
+        }
+    }
+
+    fn step_ball(&mut self, seen: [Option<(SimVec, SimVec)>; 2]) -> Vec<SimEvent> {
+        let mut events = Vec::new();
+        // Rooms which are waiting for players or between matches don't do anything.
+        if self.playing {
+            self.serve(&mut events);
+            if self.compensate(seen, &mut events) {
+                self.sweep_ball(seen, &mut events);
+            }
+        }
+        self.tick += 1;
+        events
+    }
+
+    /// Counts down to the serve, and serves once it gets there.
+    fn serve(&mut self, events: &mut Vec<SimEvent>) {
+        if self.serve_in == 0 {
+            return;
+        }
+        self.serve_in -= 1;
+        if self.serve_in > 0 {
+            return;
+        }
+        events.push(GameEvent::Serve { tick: self.tick });
+        // Choose an angle that is in a 60 degree triangle of whoever was scored on last.
+        let init_angle = real(self.rng.next_f32()) * real(60.0) - real(30.0) + real((180 * self.lastpointleft as i32) as f32);
+        // Convert to cartesian coordinates representative of our angle.
+        let init_dir = serve_direction(init_angle);
+        // Give it the starting speed in the direction we specified previously.
+        self.ball_velocity = init_dir * real(BALL_SPEED);
+    }
+
+    /// The box around a side's paddle, where it is now.
+    fn paddle_box(&self, side: PlayerSide) -> (SimVec, SimVec) {
+        grown_box(SimVec::new(real(paddle_x(side)), self.paddles[side as usize]), SimVec::from_vec2(PADDLE_SIZE.truncate()))
+    }
+
+    /// Bounces the ball off any paddle it got past, but whose player saw it hit, and lets waiting goals in once they've been seen.
+    /// Gives back false if the ball doesn't move any more this step.
+    fn compensate(&mut self, seen: [Option<(SimVec, SimVec)>; 2], events: &mut Vec<SimEvent>) -> bool {
+        for side in [PlayerSide::Left, PlayerSide::Right] {
+            let (from, to) = match seen[side as usize] {
+                Some(path) => path,
+                None => continue,
+            };
+            // Only a ball heading for this side's goal that's already past the front of the paddle. Anything else the paddle can still hit for real.
+            let (min, max) = self.paddle_box(side);
+            let front = if side == PlayerSide::Left { max.x } else { min.x };
+            if self.ball_velocity.x * towards(side) <= real(0.0) || (self.ball.x - front) * towards(side) <= real(0.0) {
+                continue;
+            }
+            if let Some((t, normal)) = time_of_impact(from, to - from, min, max) {
+                // Their player saw it hit, so it goes back to where they saw it hit and bounces from there.
+                self.ball = from + (to - from) * t;
+                self.goal_waiting = None;
+                self.bounce(Obstacle::Paddle(side), normal, self.paddles[side as usize], events);
+            }
+        }
+
+        let side = match self.goal_waiting {
+            Some(side) => side,
+            None => return true,
+        };
+        // It goes in once they've seen the ball behind their paddle, where they can't hit it any more.
+        let (min, max) = self.paddle_box(side);
+        let back = if side == PlayerSide::Left { min.x } else { max.x };
+        let seen_past = seen[side as usize].is_none_or(|(_, to)| (to.x - back) * towards(side) > real(0.0));
+        if seen_past {
+            self.goal_waiting = None;
+            let wall = if side == PlayerSide::Left { WallLocation::Left } else { WallLocation::Right };
+            self.bounce(Obstacle::Wall(wall), SimVec::new(-towards(side), real(0.0)), real(0.0), events);
+        }
+        false
+    }
+
+    /// Moves the ball along its velocity for one step, bouncing off everything it runs into on the way.
+    /// Instead of moving and then checking what it overlaps, this works out when during the step the ball first touches something,
+    /// moves it there, bounces, and carries on with whatever's left of the step. So it can't skip through anything however fast it goes,
+    /// and can bounce more than once in a step, e.g. off a paddle and straight into a wall.
+    /// A goal against a player in `seen` doesn't go in yet, the ball waits at the goal for `compensate` instead.
+    fn sweep_ball(&mut self, seen: [Option<(SimVec, SimVec)>; 2], events: &mut Vec<SimEvent>) {
+        // The walls, then the paddles.
+        let mut obstacles = Vec::new();
+        for wall in [WallLocation::Left, WallLocation::Right, WallLocation::Bottom, WallLocation::Top] {
+            let (min, max) = grown_box(SimVec::from_vec2(wall.position()), SimVec::from_vec2(wall.size()));
+            obstacles.push((Obstacle::Wall(wall), min, max, real(0.0)));
+        }
+        for side in [PlayerSide::Left, PlayerSide::Right] {
+            let (min, max) = self.paddle_box(side);
+            obstacles.push((Obstacle::Paddle(side), min, max, self.paddles[side as usize]));
+        }
+
+        if !self.push_out_of_paddles(&obstacles, events) {
+            return;
+        }
+
+        // How much of the step the ball still has to move for.
+        let mut time_left = real(TIME_STEP);
+        for _ in 0..MAX_BOUNCES_PER_STEP {
+            let travel = self.ball_velocity * time_left;
+            // Whatever it reaches first. If two things are reached at the same time, the one earlier in the list wins.
+            let mut first_hit: Option<(Real, SimVec, Obstacle, Real)> = None;
+            for &(obstacle, min, max, y) in &obstacles {
+                if let Some((t, normal)) = time_of_impact(self.ball, travel, min, max) {
+                    if first_hit.is_none_or(|(first_t, ..)| t < first_t) {
+                        first_hit = Some((t, normal, obstacle, y));
+                    }
+                }
+            }
+            let (t, normal, obstacle, y) = match first_hit {
+                Some(hit) => hit,
+                None => {
+                    // Nothing in the way, so it gets to go the whole distance.
+                    self.ball = self.ball + travel;
+                    return;
+                }
+            };
+            self.ball = self.ball + travel * t;
+            time_left -= time_left * t;
+            let conceding = match obstacle {
+                Obstacle::Wall(WallLocation::Left) => Some(PlayerSide::Left),
+                Obstacle::Wall(WallLocation::Right) => Some(PlayerSide::Right),
+                _ => None,
+            };
+            if let Some(side) = conceding.filter(|&side| seen[side as usize].is_some()) {
+                self.goal_waiting = Some(side);
+                return;
+            }
+            if !self.bounce(obstacle, normal, y, events) {
+                return;
+            }
+        }
+    }
+
+    /// Gets the ball out of any paddle that moved on top of it, which sweeping can't see since the ball never ran into it.
+    /// It goes out the shortest way that doesn't put it in a wall, and bounces off that side of the paddle like it had hit it there.
+    /// Gives back false if it has nowhere to go, and stays put until the next step. The paddles never get close enough to a wall for that.
+    fn push_out_of_paddles(&mut self, obstacles: &[(Obstacle, SimVec, SimVec, Real)], events: &mut Vec<SimEvent>) -> bool {
+        for &(obstacle, min, max, y) in obstacles {
+            if !matches!(obstacle, Obstacle::Paddle(_)) || !inside(self.ball, min, max) {
+                continue;
+            }
+            let ball = self.ball;
+            // Each side it could go out of, as where it ends up and that side's normal.
+            let exits = [
+                (SimVec::new(min.x, ball.y), SimVec::new(real(-1.0), real(0.0))),
+                (SimVec::new(max.x, ball.y), SimVec::new(real(1.0), real(0.0))),
+                (SimVec::new(ball.x, min.y), SimVec::new(real(0.0), real(-1.0))),
+                (SimVec::new(ball.x, max.y), SimVec::new(real(0.0), real(1.0))),
+            ];
+            let mut nearest: Option<(Real, SimVec, SimVec)> = None;
+            for (to, normal) in exits {
+                if obstacles.iter().any(|&(_, min, max, _)| inside(to, min, max)) {
+                    continue;
+                }
+                let distance = (to.x - ball.x).abs() + (to.y - ball.y).abs();
+                if nearest.is_none_or(|(nearest_distance, ..)| distance < nearest_distance) {
+                    nearest = Some((distance, to, normal));
+                }
+            }
+            let (_, to, normal) = match nearest {
+                Some(exit) => exit,
+                None => return false,
+            };
+            self.ball = to;
+            self.bounce(obstacle, normal, y, events);
+        }
+        true
+    }
+
+    /// What happens when the ball touches `obstacle` on the side facing `normal`.
+    /// Paddles are at height `paddle_y`. Gives back false if that was a goal and the ball's gone back to the middle.
+    fn bounce(&mut self, obstacle: Obstacle, normal: SimVec, paddle_y: Real, events: &mut Vec<SimEvent>) -> bool {
+        match obstacle {
+            Obstacle::Wall(WallLocation::Left | WallLocation::Right) => {
+                // The ball got past a paddle, so whoever's on the other side scores.
+                let scorer = if obstacle == Obstacle::Wall(WallLocation::Right) { PlayerSide::Left } else { PlayerSide::Right };
+                match scorer {
+                    PlayerSide::Left => self.score[0] += 1,
+                    PlayerSide::Right => self.score[1] += 1,
+                }
+                self.lastpointleft = scorer == PlayerSide::Right;
+                events.push(GameEvent::GoalScored { side: scorer, tick: self.tick });
+
+                // Stop the ball in the middle until it's served again.
+                self.ball_velocity = SimVec::default();
+                self.ball = SimVec::from_vec2(BALL_STARTING_POSITION.truncate());
+                self.serve_in = serve_delay_steps();
+
+                // Somebody just won, so stop the ball until a new match is started.
+                if let Some(winner) = self.winner() {
+                    self.playing = false;
+                    events.push(GameEvent::MatchOver { winner, tick: self.tick });
+                }
+                return false;
+            }
+            Obstacle::Wall(_) => events.push(GameEvent::WallBounce { tick: self.tick }),
+            Obstacle::Paddle(side) => {
+                // Only the front and back of the paddle speed the ball up and aim it, glancing off the top or bottom just bounces.
+                if normal.x != real(0.0) {
+                    // Increase the ball velocity by 1.1x
+                    // This is to apply pressure to the players and prevent drawn out matches.
+                    // Also clamp it below our max speed, otherwise it can become unplayable.
+                    self.ball_velocity.x = (self.ball_velocity.x*real(BALL_SPEED_INCREASE)).clamp(-real(MAX_BALL_SPEED),real(MAX_BALL_SPEED));
+                    // Set the Y velocity proportionally to how far from the center of the paddle we hit.
+                    // This is to give the player more control over where the ball goes.
+                    self.ball_velocity.y = self.ball_velocity.y.signum()*(self.ball_velocity.x * (self.ball.y - paddle_y) / (real(PADDLE_SIZE.y)/real(3.0))).abs();
+                }
+                events.push(GameEvent::PaddleHit { side, tick: self.tick });
+            }
+        }
+
+        // Turn around on whichever axes it hit something, as long as it's still heading into it.
+        if normal.x * self.ball_velocity.x < real(0.0) {
+            self.ball_velocity.x = -self.ball_velocity.x;
+        }
+        if normal.y * self.ball_velocity.y < real(0.0) {
+            self.ball_velocity.y = -self.ball_velocity.y;
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A match with the ball in play at `ball`, moving `travel` in one step, and the paddles at the given heights.
+    fn in_play(ball: (f32, f32), travel: (f32, f32), paddles: [f32; 2]) -> PongSim {
+        let mut sim = PongSim::new(1);
+        sim.restart();
+        sim.serve_in = 0;
+        sim.ball = SimVec::new(real(ball.0), real(ball.1));
+        sim.ball_velocity = SimVec::new(real(travel.0 / TIME_STEP), real(travel.1 / TIME_STEP));
+        sim.paddles = [real(paddles[0]), real(paddles[1])];
+        sim
+    }
+
+    fn sweep(sim: &mut PongSim) -> Vec<SimEvent> {
+        let mut events = Vec::new();
+        sim.sweep_ball([None; 2], &mut events);
+        events
+    }
+
+    /// The right paddle's box reaches from x = 365 to 415, and 75 either way of its height. The top wall's starts at y = 280.
+    #[test]
+    fn bounces_more_than_once_in_a_step() {
+        // Off the top wall half way through the step, then into the front of the right paddle.
+        let mut sim = in_play((340.0, 270.0), (30.0, 20.0), [0.0, 200.0]);
+        let events = sweep(&mut sim);
+        assert_eq!(events, vec![GameEvent::WallBounce { tick: 0 }, GameEvent::PaddleHit { side: PlayerSide::Right, tick: 0 }]);
+        assert!(float(sim.ball.x) < 365.0 && float(sim.ball.y) < 280.0);
+        assert!(float(sim.ball_velocity.x) < 0.0 && float(sim.ball_velocity.y) < 0.0);
+    }
+
+    #[test]
+    fn bounces_off_both_sides_of_an_exact_corner_hit() {
+        // Straight into the bottom left corner of the right paddle.
+        let mut sim = in_play((355.0, -85.0), (20.0, 20.0), [0.0, 0.0]);
+        let events = sweep(&mut sim);
+        assert_eq!(events, vec![GameEvent::PaddleHit { side: PlayerSide::Right, tick: 0 }]);
+        assert!(float(sim.ball_velocity.x) < 0.0 && float(sim.ball_velocity.y) < 0.0);
+        assert!(float(sim.ball.x) < 365.0 && float(sim.ball.y) < -75.0);
+
+        // And the top right corner of the left one, coming the other way.
+        let mut sim = in_play((-355.0, 85.0), (-20.0, -20.0), [0.0, 0.0]);
+        let events = sweep(&mut sim);
+        assert_eq!(events, vec![GameEvent::PaddleHit { side: PlayerSide::Left, tick: 0 }]);
+        assert!(float(sim.ball_velocity.x) > 0.0 && float(sim.ball_velocity.y) > 0.0);
+    }
+
+    #[test]
+    fn a_fast_ball_cant_skip_through_a_thin_paddle() {
+        // It would land past the back of the paddle if it just moved and then looked for overlaps.
+        let mut sim = in_play((330.0, 0.0), (100.0, 0.0), [0.0, 0.0]);
+        let events = sweep(&mut sim);
+        assert_eq!(events, vec![GameEvent::PaddleHit { side: PlayerSide::Right, tick: 0 }]);
+        assert!(float(sim.ball.x) < 365.0);
+        assert_eq!(float(sim.ball_velocity.x), -MAX_BALL_SPEED);
+    }
+
+    #[test]
+    fn a_paddle_moving_onto_the_ball_pushes_it_out() {
+        // 10 in from the front and 15 from the top, so it goes out the front.
+        let mut sim = in_play((375.0, 120.0), (2.0, 0.0), [0.0, 60.0]);
+        let events = sweep(&mut sim);
+        assert_eq!(events, vec![GameEvent::PaddleHit { side: PlayerSide::Right, tick: 0 }]);
+        assert!(float(sim.ball.x) < 365.0);
+        assert!(float(sim.ball_velocity.x) < 0.0);
+
+        // Coming up underneath it, it goes out the top and heads up.
+        let mut sim = in_play((390.0, 130.0), (2.0, -2.0), [0.0, 60.0]);
+        let events = sweep(&mut sim);
+        assert_eq!(events, vec![GameEvent::PaddleHit { side: PlayerSide::Right, tick: 0 }]);
+        assert!(float(sim.ball.y) > 135.0);
+        assert!(float(sim.ball_velocity.y) > 0.0);
+    }
+
+    #[test]
+    fn a_ball_pushed_out_of_a_paddle_doesnt_go_into_a_wall() {
+        // The paddle's as high as it goes, and the top of it is closest, but there's only wall that way.
+        let top = 300.0 - WALL_THICKNESS / 2.0 - PADDLE_SIZE.y / 2.0 - PADDLE_PADDING;
+        let mut sim = in_play((385.0, 270.0), (2.0, 2.0), [0.0, top]);
+        sweep(&mut sim);
+        assert!(float(sim.ball.x) < 365.0);
+        assert!(float(sim.ball.y) < 280.0);
+    }
+}
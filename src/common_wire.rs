@@ -0,0 +1,468 @@
+//! Compact encoding for the GameState the server sends every client 60 times a second.
+//! Every field is quantized to an integer, and only the fields which changed since a snapshot the client
+//! has acknowledged are sent, bit-packed back to back.
+//!
+//! A message looks like this:
+//! - sequence: 16 bits, counts up with every message sent to this client.
+//! - baseline age: 6 bits, how many messages back the baseline is. 0 means there's no baseline and it's compared against all zeroes.
+//! - change mask: one bit per field, set if the field differs from the baseline.
+//! - each changed field: a 1 followed by the difference if it fits in the field's delta bits, otherwise a 0 followed by the whole value.
+
+use std::{collections::VecDeque, fmt};
+
+use bevy::prelude::Vec2;
+
+use crate::common_net::GameState;
+
+/// Positions are sent in eighths of a pixel, which covers -4096..4096 in an i16.
+const POSITION_SCALE: f32 = 8.0;
+/// Velocities are sent in halves of a pixel per second, which covers about 16000 pixels per second each way.
+const VELOCITY_SCALE: f32 = 2.0;
+/// Server time is sent in whole milliseconds.
+const TIME_SCALE: f64 = 1000.0;
+
+/// How many messages either side remembers. The baseline age has to fit in 6 bits, so this can't go past 64.
+const WINDOW: u16 = 64;
+const SEQUENCE_BITS: u32 = 16;
+const BASELINE_AGE_BITS: u32 = 6;
+
+/// How one field of the GameState is written.
+struct Field {
+    /// Width of the whole value.
+    bits: u32,
+    /// Width of a difference from the baseline, or 0 to always send the whole value.
+    delta_bits: u32,
+    signed: bool,
+}
+
+const fn field(bits: u32, delta_bits: u32, signed: bool) -> Field {
+    Field { bits, delta_bits, signed }
+}
+
+const FIELD_COUNT: usize = 14;
+
+/// In the same order as `quantize` lays them out.
+const FIELDS: [Field; FIELD_COUNT] = [
+    // Ball position, x then y.
+    field(16, 12, true),
+    field(16, 12, true),
+    // Ball velocity. It only changes when it bounces, and then it flips sign, so a delta wouldn't help.
+    field(16, 0, true),
+    field(16, 0, true),
+    // Left and right paddle positions. Their x never changes so it's almost never sent.
+    field(16, 0, true),
+    field(16, 10, true),
+    field(16, 0, true),
+    field(16, 10, true),
+    // Scores.
+    field(16, 0, false),
+    field(16, 0, false),
+    // Playing.
+    field(1, 0, false),
+    // Last input, which goes up by one per input.
+    field(32, 6, false),
+    // Server time in milliseconds, and the tick.
+    field(32, 10, false),
+    field(64, 8, false),
+];
+
+type Quantized = [i64; FIELD_COUNT];
+
+/// Why a message couldn't be decoded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WireError {
+    /// The message ended before all of its fields did.
+    Truncated,
+    /// The message was encoded against a snapshot we don't have any more.
+    UnknownBaseline { sequence: u16, baseline: u16 },
+    /// The message says something the encoder never would, like a delta for a field that's always sent whole.
+    Malformed,
+}
+
+impl fmt::Display for WireError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WireError::Truncated => write!(f, "the message was cut short"),
+            WireError::UnknownBaseline { sequence, baseline } => write!(f, "message {} is based on message {}, which we no longer have", sequence, baseline),
+            WireError::Malformed => write!(f, "the message isn't one we could have sent"),
+        }
+    }
+}
+
+impl std::error::Error for WireError {}
+
+fn quantize(state: &GameState) -> Quantized {
+    let position = |v: f32| (v * POSITION_SCALE).round().clamp(i16::MIN as f32, i16::MAX as f32) as i64;
+    let velocity = |v: f32| (v * VELOCITY_SCALE).round().clamp(i16::MIN as f32, i16::MAX as f32) as i64;
+    let score = |s: i32| s.clamp(0, u16::MAX as i32) as i64;
+    [
+        position(state.ball_loc.x),
+        position(state.ball_loc.y),
+        velocity(state.ball_velocity.x),
+        velocity(state.ball_velocity.y),
+        position(state.paddle_l_loc.x),
+        position(state.paddle_l_loc.y),
+        position(state.paddle_r_loc.x),
+        position(state.paddle_r_loc.y),
+        score(state.score_l),
+        score(state.score_r),
+        state.playing as i64,
+        state.last_input as i64,
+        (state.server_time * TIME_SCALE).round().clamp(0.0, u32::MAX as f64) as i64,
+        state.tick as i64,
+    ]
+}
+
+fn dequantize(q: &Quantized) -> GameState {
+    let position = |v: i64| v as f32 / POSITION_SCALE;
+    let velocity = |v: i64| v as f32 / VELOCITY_SCALE;
+    GameState {
+        ball_loc: Vec2::new(position(q[0]), position(q[1])),
+        ball_velocity: Vec2::new(velocity(q[2]), velocity(q[3])),
+        paddle_l_loc: Vec2::new(position(q[4]), position(q[5])),
+        paddle_r_loc: Vec2::new(position(q[6]), position(q[7])),
+        score_l: q[8] as i32,
+        score_r: q[9] as i32,
+        playing: q[10] != 0,
+        last_input: q[11] as u32,
+        server_time: q[12] as f64 / TIME_SCALE,
+        tick: q[13] as u64,
+    }
+}
+
+/// Writes values of any width up to 64 bits, least significant bit first.
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    used: u32,
+}
+
+impl BitWriter {
+    fn write(&mut self, value: u64, bits: u32) {
+        for i in 0..bits {
+            if self.used.is_multiple_of(8) {
+                self.bytes.push(0);
+            }
+            let bit = ((value >> i) & 1) as u8;
+            *self.bytes.last_mut().unwrap() |= bit << (self.used % 8);
+            self.used += 1;
+        }
+    }
+
+    fn finish(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+struct BitReader<'a> {
+    bytes: &'a [u8],
+    read: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        BitReader { bytes, read: 0 }
+    }
+
+    fn read(&mut self, bits: u32) -> Result<u64, WireError> {
+        let mut value = 0;
+        for i in 0..bits {
+            let byte = self.bytes.get(self.read / 8).ok_or(WireError::Truncated)?;
+            value |= (((byte >> (self.read % 8)) & 1) as u64) << i;
+            self.read += 1;
+        }
+        Ok(value)
+    }
+}
+
+/// Turns the low `bits` of a value back into a signed number.
+fn sign_extend(value: u64, bits: u32) -> i64 {
+    if bits == 64 {
+        return value as i64;
+    }
+    let shift = 64 - bits;
+    ((value << shift) as i64) >> shift
+}
+
+/// Whether a signed difference fits in `bits`.
+fn fits(delta: i64, bits: u32) -> bool {
+    bits > 0 && delta >= -(1 << (bits - 1)) && delta < (1 << (bits - 1))
+}
+
+fn mask(bits: u32) -> u64 {
+    if bits == 64 { u64::MAX } else { (1 << bits) - 1 }
+}
+
+fn encode_fields(writer: &mut BitWriter, state: &Quantized, baseline: &Quantized) {
+    for (value, base) in state.iter().zip(baseline.iter()) {
+        writer.write((value != base) as u64, 1);
+    }
+    for ((field, &value), &base) in FIELDS.iter().zip(state.iter()).zip(baseline.iter()) {
+        if value == base {
+            continue;
+        }
+        let delta = value.wrapping_sub(base);
+        if fits(delta, field.delta_bits) {
+            writer.write(1, 1);
+            writer.write(delta as u64 & mask(field.delta_bits), field.delta_bits);
+        } else {
+            writer.write(0, 1);
+            writer.write(value as u64 & mask(field.bits), field.bits);
+        }
+    }
+}
+
+fn decode_fields(reader: &mut BitReader, baseline: &Quantized) -> Result<Quantized, WireError> {
+    let mut changed = [false; FIELD_COUNT];
+    for changed in changed.iter_mut() {
+        *changed = reader.read(1)? == 1;
+    }
+    let mut state = *baseline;
+    for (i, field) in FIELDS.iter().enumerate() {
+        if !changed[i] {
+            continue;
+        }
+        state[i] = if reader.read(1)? == 1 {
+            if field.delta_bits == 0 {
+                return Err(WireError::Malformed);
+            }
+            baseline[i].wrapping_add(sign_extend(reader.read(field.delta_bits)?, field.delta_bits))
+        } else {
+            let value = reader.read(field.bits)?;
+            if field.signed { sign_extend(value, field.bits) } else { value as i64 }
+        };
+    }
+    Ok(state)
+}
+
+/// Whether sequence `a` comes after `b`, allowing for them wrapping around.
+fn sequence_newer(a: u16, b: u16) -> bool {
+    a != b && a.wrapping_sub(b) < u16::MAX / 2
+}
+
+/// The server keeps one of these per client, remembering what it sent them and what they've acknowledged.
+#[derive(Debug, Default)]
+pub struct DeltaEncoder {
+    next_sequence: u16,
+    sent: VecDeque<(u16, Quantized)>,
+    acked: Option<u16>,
+}
+
+impl DeltaEncoder {
+    /// Encodes a state against the newest one the client has acknowledged, or against nothing if they haven't acknowledged any we still have.
+    pub fn encode(&mut self, state: &GameState) -> Vec<u8> {
+        let sequence = self.next_sequence;
+        self.next_sequence = self.next_sequence.wrapping_add(1);
+        let quantized = quantize(state);
+
+        let baseline = self.acked.and_then(|acked| {
+            let age = sequence.wrapping_sub(acked);
+            if age == 0 || age >= WINDOW {
+                return None;
+            }
+            self.sent.iter().find(|(s, _)| *s == acked).map(|(_, q)| (age, *q))
+        });
+
+        let mut writer = BitWriter::default();
+        writer.write(sequence as u64, SEQUENCE_BITS);
+        match baseline {
+            Some((age, base)) => {
+                writer.write(age as u64, BASELINE_AGE_BITS);
+                encode_fields(&mut writer, &quantized, &base);
+            }
+            None => {
+                writer.write(0, BASELINE_AGE_BITS);
+                encode_fields(&mut writer, &quantized, &[0; FIELD_COUNT]);
+            }
+        }
+
+        self.sent.push_back((sequence, quantized));
+        while self.sent.len() > WINDOW as usize {
+            self.sent.pop_front();
+        }
+        writer.finish()
+    }
+
+    /// Records that the client got a message. Acks can arrive out of order, older ones are ignored.
+    /// Returns false if it's for a message we haven't sent yet, which no honest client would do.
+    pub fn ack(&mut self, sequence: u16) -> bool {
+        if !sequence_newer(self.next_sequence, sequence) {
+            return false;
+        }
+        if self.acked.is_none_or(|acked| sequence_newer(sequence, acked)) {
+            self.acked = Some(sequence);
+        }
+        true
+    }
+}
+
+/// The client's side of DeltaEncoder, it remembers recent states so later messages can be based on them.
+#[derive(Debug, Default)]
+pub struct DeltaDecoder {
+    recieved: VecDeque<(u16, Quantized)>,
+    newest: Option<u16>,
+}
+
+impl DeltaDecoder {
+    /// Decodes a message, returning its sequence along with the state. The sequence is what should be acknowledged.
+    pub fn decode(&mut self, bytes: &[u8]) -> Result<(u16, GameState), WireError> {
+        let mut reader = BitReader::new(bytes);
+        let sequence = reader.read(SEQUENCE_BITS)? as u16;
+        let age = reader.read(BASELINE_AGE_BITS)? as u16;
+        let quantized = if age == 0 {
+            decode_fields(&mut reader, &[0; FIELD_COUNT])?
+        } else {
+            let baseline = sequence.wrapping_sub(age);
+            let base = self.recieved.iter()
+                .find(|(s, _)| *s == baseline)
+                .map(|(_, q)| *q)
+                .ok_or(WireError::UnknownBaseline { sequence, baseline })?;
+            decode_fields(&mut reader, &base)?
+        };
+
+        if !self.recieved.iter().any(|(s, _)| *s == sequence) {
+            self.recieved.push_back((sequence, quantized));
+        }
+        if self.newest.is_none_or(|newest| sequence_newer(sequence, newest)) {
+            self.newest = Some(sequence);
+        }
+        // Anything too far behind the newest message can't be a baseline any more.
+        if let Some(newest) = self.newest {
+            self.recieved.retain(|(s, _)| newest.wrapping_sub(*s) < WINDOW);
+        }
+        Ok((sequence, dequantize(&quantized)))
+    }
+
+    /// Newest message decoded so far.
+    pub fn newest(&self) -> Option<u16> {
+        self.newest
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common_game::{BOTTOM_WALL, LEFT_WALL, MAX_BALL_SPEED, RIGHT_WALL, TOP_WALL, WINNING_SCORE};
+
+    /// A state with everything as far out as it goes in a real game.
+    fn at_the_bounds(tick: u64) -> GameState {
+        GameState {
+            ball_loc: Vec2::new(LEFT_WALL, TOP_WALL),
+            ball_velocity: Vec2::new(-MAX_BALL_SPEED, MAX_BALL_SPEED),
+            paddle_l_loc: Vec2::new(LEFT_WALL, BOTTOM_WALL),
+            paddle_r_loc: Vec2::new(RIGHT_WALL, TOP_WALL),
+            score_l: WINNING_SCORE as i32,
+            score_r: WINNING_SCORE as i32 - 1,
+            playing: true,
+            last_input: u32::MAX,
+            server_time: 86_400.0,
+            tick,
+        }
+    }
+
+    fn assert_same(got: &GameState, expected: &GameState) {
+        assert_eq!(got.ball_loc, expected.ball_loc);
+        assert_eq!(got.ball_velocity, expected.ball_velocity);
+        assert_eq!(got.paddle_l_loc, expected.paddle_l_loc);
+        assert_eq!(got.paddle_r_loc, expected.paddle_r_loc);
+        assert_eq!((got.score_l, got.score_r), (expected.score_l, expected.score_r));
+        assert_eq!(got.playing, expected.playing);
+        assert_eq!(got.last_input, expected.last_input);
+        assert_eq!(got.server_time, expected.server_time);
+        assert_eq!(got.tick, expected.tick);
+    }
+
+    #[test]
+    fn round_trips_at_the_arena_bounds() {
+        let mut encoder = DeltaEncoder::default();
+        let mut decoder = DeltaDecoder::default();
+        let first = at_the_bounds(u64::MAX - 1);
+        let (sequence, decoded) = decoder.decode(&encoder.encode(&first)).unwrap();
+        assert_same(&decoded, &first);
+
+        // Then the other corners, as a delta against the first.
+        assert!(encoder.ack(sequence));
+        let mut second = at_the_bounds(u64::MAX);
+        second.ball_loc = Vec2::new(RIGHT_WALL, BOTTOM_WALL);
+        second.ball_velocity = -second.ball_velocity;
+        second.paddle_l_loc.y = TOP_WALL;
+        second.paddle_r_loc.y = BOTTOM_WALL;
+        second.playing = false;
+        let (_, decoded) = decoder.decode(&encoder.encode(&second)).unwrap();
+        assert_same(&decoded, &second);
+    }
+
+    #[test]
+    fn sequences_wrap_around() {
+        assert!(sequence_newer(0, u16::MAX));
+        assert!(sequence_newer(5, u16::MAX - 5));
+        assert!(!sequence_newer(u16::MAX, 0));
+        assert!(!sequence_newer(7, 7));
+
+        let mut encoder = DeltaEncoder { next_sequence: u16::MAX - 2, ..Default::default() };
+        let mut decoder = DeltaDecoder::default();
+        for tick in 0..6 {
+            let state = GameState { tick, ball_loc: Vec2::new(tick as f32, 0.0), ..Default::default() };
+            let (sequence, decoded) = decoder.decode(&encoder.encode(&state)).unwrap();
+            assert_same(&decoded, &state);
+            assert!(encoder.ack(sequence));
+        }
+        // Everything from before the wrap is still older than what came after it.
+        assert_eq!(decoder.newest(), Some(2));
+        assert_eq!(encoder.acked, Some(2));
+        assert!(encoder.ack(u16::MAX));
+        assert_eq!(encoder.acked, Some(2));
+        // And nothing's been sent past the wrap yet.
+        assert!(!encoder.ack(3));
+        assert!(!encoder.ack(100));
+    }
+
+    #[test]
+    fn delta_against_an_unknown_baseline_is_an_error() {
+        let mut encoder = DeltaEncoder::default();
+        let mut decoder = DeltaDecoder::default();
+        let first = encoder.encode(&at_the_bounds(1));
+        assert!(encoder.ack(0));
+        let second = encoder.encode(&at_the_bounds(2));
+
+        // The first one got lost, so the second means nothing to us.
+        assert_eq!(decoder.decode(&second).unwrap_err(), WireError::UnknownBaseline { sequence: 1, baseline: 0 });
+        // Once it turns up, the second decodes fine.
+        decoder.decode(&first).unwrap();
+        assert_same(&decoder.decode(&second).unwrap().1, &at_the_bounds(2));
+    }
+
+    #[test]
+    fn truncated_messages_are_errors() {
+        let message = DeltaEncoder::default().encode(&at_the_bounds(1));
+        for length in 0..message.len() {
+            let result = DeltaDecoder::default().decode(&message[..length]);
+            assert_eq!(result.unwrap_err(), WireError::Truncated, "cut to {} bytes", length);
+        }
+    }
+
+    #[test]
+    fn garbage_is_an_error_not_a_panic() {
+        let mut decoder = DeltaDecoder::default();
+        // Give it some baselines, so garbage can get past the baseline check too.
+        let mut encoder = DeltaEncoder::default();
+        for tick in 0..WINDOW as u64 {
+            decoder.decode(&encoder.encode(&at_the_bounds(tick))).unwrap();
+        }
+        let mut rng = crate::common_sim::SimRng::new(0x6a7b);
+        for length in 0..200 {
+            let garbage: Vec<u8> = (0..length % 40).map(|_| rng.next_u64() as u8).collect();
+            // Whatever it decodes to, it mustn't panic.
+            let _ = decoder.decode(&garbage);
+        }
+
+        // The encoder never sends the ball's velocity as a delta, so a message that says it did is made up.
+        let mut writer = BitWriter::default();
+        writer.write(0, SEQUENCE_BITS);
+        writer.write(0, BASELINE_AGE_BITS);
+        writer.write(0b100, FIELD_COUNT as u32);
+        writer.write(1, 1);
+        writer.write(0, 64);
+        assert_eq!(DeltaDecoder::default().decode(&writer.finish()).unwrap_err(), WireError::Malformed);
+    }
+}
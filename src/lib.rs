@@ -1,3 +1,17 @@
 pub mod common_net;
 
-pub mod common_game;
\ No newline at end of file
+pub mod common_game;
+
+pub mod common_config;
+
+pub mod common_wire;
+
+pub mod common_netsim;
+
+pub mod common_rollback;
+
+pub mod common_sim;
+
+pub mod common_fixed;
+
+pub mod server;
@@ -0,0 +1,1886 @@
+//! This is the server which hosts the game. The server binary in bin/server.rs just reads its settings and runs this.
+//! All of the code in this file is related to networking.
+//! For actual game code see common_game.rs
+
+use rand::{
+    thread_rng,
+    RngCore
+};
+
+use bevy::prelude::*;
+
+use bevy_renet::{
+    renet::{
+        RenetError, 
+        RenetServer, 
+        ServerAuthentication, 
+        ServerConfig, 
+        ServerEvent, 
+        ConnectToken
+    },
+};
+
+use threadpool::ThreadPool;
+
+use std::{time::{Duration, Instant, SystemTime, UNIX_EPOCH}, 
+    collections::{HashMap, VecDeque},
+    fmt,
+    fs,
+    io::Write,
+    path::Path,
+    net::{UdpSocket,TcpListener,TcpStream,SocketAddr},
+    sync::{Arc, Mutex},
+};
+
+use crate::common_net::*;
+use crate::common_game::*;
+use crate::common_config::*;
+use crate::common_wire::DeltaEncoder;
+use crate::common_sim::SimRng;
+
+/// How the connection to each client is doing, see LinkStats.
+#[derive(Default)]
+struct ClientLinks(HashMap<u64, LinkStats>);
+
+/// Ways a client can break the rules.
+#[derive(Debug)]
+enum Offence {
+    /// Sent something that isn't a message we understand on that channel.
+    Malformed { channel: u8, error: bincode::Error },
+    /// Sent more than max_messages_per_sec.
+    Flooding,
+    /// Sent a message that decodes fine but makes no sense.
+    Nonsense(&'static str),
+}
+
+impl fmt::Display for Offence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Offence::Malformed { channel, error } => write!(f, "sent a malformed message on channel {}: {}", channel, error),
+            Offence::Flooding => write!(f, "sent too many messages"),
+            Offence::Nonsense(what) => write!(f, "{}", what),
+        }
+    }
+}
+
+/// Inputs further ahead of the last one than this are never going to be played.
+const MAX_INPUT_SKIP: u32 = 600;
+/// About two seconds of inputs. More than this waiting to be played means they're coming in faster than they're sent.
+const MAX_PENDING_INPUTS: usize = 120;
+/// Clients send one input every POLL_RATE, so that's all channel 0 gets, however high max_messages_per_sec is.
+const INPUTS_PER_SEC: f64 = 1.0 / POLL_RATE as f64;
+
+/// Keeps an eye on what a client sends us.
+struct ClientGuard {
+    /// How many more messages they can send right now. Refills at max_messages_per_sec, up to one second's worth.
+    allowance: f64,
+    /// Same again just for inputs, refilling at INPUTS_PER_SEC.
+    input_allowance: f64,
+    /// Offences that haven't been forgiven yet. One is forgiven every second.
+    strikes: f64,
+    /// Offences since we last logged any, and when that was.
+    unreported: u32,
+    last_report: f64,
+    last_update: f64,
+}
+
+impl ClientGuard {
+    fn new(now: f64, rate: u32) -> Self {
+        ClientGuard {
+            allowance: rate as f64,
+            input_allowance: INPUTS_PER_SEC,
+            strikes: 0.0,
+            unreported: 0,
+            last_report: f64::NEG_INFINITY,
+            last_update: now,
+        }
+    }
+
+    /// Tops up the allowance and forgives old offences for the time since the last update.
+    fn update(&mut self, now: f64, rate: u32) {
+        let elapsed = (now - self.last_update).max(0.0);
+        self.allowance = (self.allowance + elapsed * rate as f64).min(rate as f64);
+        self.input_allowance = (self.input_allowance + elapsed * INPUTS_PER_SEC).min(INPUTS_PER_SEC);
+        self.strikes = (self.strikes - elapsed).max(0.0);
+        self.last_update = now;
+    }
+
+    /// Uses up one message of the allowance. False if there's none left.
+    fn allow(&mut self) -> bool {
+        if self.allowance < 1.0 {
+            return false;
+        }
+        self.allowance -= 1.0;
+        true
+    }
+
+    /// Uses up one input of the input allowance, and one message of the other one. False if either has run out.
+    fn allow_input(&mut self) -> bool {
+        if self.input_allowance < 1.0 {
+            return false;
+        }
+        self.input_allowance -= 1.0;
+        self.allow()
+    }
+
+    /// Deals with an offence the way abuse_policy says. Returns true if the client should be kicked.
+    fn offend(&mut self, client_id: u64, offence: Offence, now: f64, settings: &ServerSettings) -> bool {
+        self.strikes += 1.0;
+        self.unreported += 1;
+        if settings.abuse_policy == AbusePolicy::Drop {
+            return false;
+        }
+        // Someone flooding us shouldn't get to flood the log as well, so this goes out at most once a second.
+        if now - self.last_report >= 1.0 {
+            warn!("Client {} {} ({} offences since the last report).", client_id, offence, self.unreported);
+            self.unreported = 0;
+            self.last_report = now;
+        }
+        settings.abuse_policy == AbusePolicy::Kick && self.strikes >= settings.kick_after_offences as f64
+    }
+}
+
+#[derive(Default)]
+struct ClientGuards(HashMap<u64, ClientGuard>);
+
+/// Goes off every heartbeat_interval_ms to ping everyone.
+struct HeartbeatTimer(Timer);
+
+/// What each client has been sent and acknowledged, so their GameStates can be sent as deltas.
+#[derive(Default)]
+struct DeltaEncoders(HashMap<u64, DeltaEncoder>);
+
+/// How often the GameState bandwidth gets logged.
+const BANDWIDTH_LOG_SECONDS: f32 = 10.0;
+
+/// Adds up how much GameState data we send, and how much it would have been as plain bincode.
+struct SyncBandwidth {
+    full_bytes: u64,
+    sent_bytes: u64,
+    timer: Timer,
+}
+
+#[derive(Debug, Component)]
+struct Player {
+}
+
+/// Put on a room when it has both its players and should be reset to start a new game, served from `seed`.
+/// A match carrying on after a player's resumed it has no seed, its serves come from where they were.
+#[derive(Component)]
+struct ResetDue {
+    seed: Option<u64>,
+}
+
+/// How long the final score stays up after a match is won, before the next one starts.
+const MATCH_RESTART_DELAY: f32 = 5.0;
+
+/// Put on a room when its match has been won. The next match starts once it runs out.
+#[derive(Component)]
+struct MatchOverTimer(Timer);
+
+/// A paddle kept for a player who dropped out, until they resume or run out of time.
+struct HeldSeat {
+    side: PlayerSide,
+    /// The client id they had before they dropped.
+    client: u64,
+    resume_token: ResumeToken,
+    /// Server time when the seat is given up.
+    until: f64,
+}
+
+/// A rollback match going on in a room. The server doesn't simulate these, it passes the inputs between the players
+/// and waits for them to agree on who won.
+struct RollbackMatch {
+    round: u32,
+    /// The frame each side's next input has to be for, left first. They come one per frame, in order.
+    next_frame: [u32; 2],
+    /// Who each side's game says won, left first.
+    results: [Option<PlayerSide>; 2],
+}
+
+/// What to do with a RollbackMessage a player sent, see RoomManager::rollback_message.
+enum RollbackRelay {
+    /// Pass it on to their opponent.
+    Forward(u64),
+    /// Nothing to do. It's late, for a match that's over, or we're still waiting to hear from the other player.
+    Nothing,
+    /// Both players agree on who won.
+    MatchOver { room: Entity, winner: PlayerSide },
+    /// The players' games disagree on who won, so they must have drifted apart.
+    Desync { room: Entity },
+}
+
+/// One match on the server, which client plays each side of it, and who is watching.
+struct RoomSlots {
+    entities: RoomEntities,
+    left: Option<u64>,
+    right: Option<u64>,
+    held: Vec<HeldSeat>,
+    spectators: Vec<u64>,
+    /// How many rollback matches this room has had, so every one gets its own round number.
+    rounds: u32,
+    rollback: Option<RollbackMatch>,
+    /// What the serves in the current match, or the last one, were seeded with.
+    seed: u64,
+}
+
+impl RoomSlots {
+    fn new(entities: RoomEntities) -> Self {
+        RoomSlots { entities, left: None, right: None, held: Vec::new(), spectators: Vec::new(), rounds: 0, rollback: None, seed: 0 }
+    }
+
+    /// Starts a new rollback match, returning its round number.
+    fn start_rollback(&mut self) -> u32 {
+        self.rounds += 1;
+        self.rollback = Some(RollbackMatch { round: self.rounds, next_frame: [0; 2], results: [None; 2] });
+        self.rounds
+    }
+
+    /// Every client playing in this room.
+    fn players(&self) -> impl Iterator<Item = (u64, PlayerSide)> + '_ {
+        self.left.map(|id| (id, PlayerSide::Left)).into_iter()
+            .chain(self.right.map(|id| (id, PlayerSide::Right)))
+    }
+
+    /// Everyone who should hear about this room, players and spectators alike.
+    fn members(&self) -> impl Iterator<Item = u64> + '_ {
+        self.players().map(|(id, _)| id).chain(self.spectators.iter().copied())
+    }
+
+    /// A side nobody is playing, and nobody is coming back to.
+    fn free_side(&self) -> Option<PlayerSide> {
+        let held = |side| self.held.iter().any(|seat| seat.side == side);
+        match (self.left, self.right) {
+            (None, _) if !held(PlayerSide::Left) => Some(PlayerSide::Left),
+            (_, None) if !held(PlayerSide::Right) => Some(PlayerSide::Right),
+            _ => None,
+        }
+    }
+
+    fn set(&mut self, side: PlayerSide, client: Option<u64>) {
+        match side {
+            PlayerSide::Left => self.left = client,
+            PlayerSide::Right => self.right = client,
+        }
+    }
+}
+
+/// Creates matches out of the players waiting in the queue, and tears them down once the players have left.
+#[derive(Default)]
+struct RoomManager {
+    rooms: HashMap<Entity, RoomSlots>,
+    /// Which room each client is in, whether they're playing or watching.
+    clients: HashMap<u64, Entity>,
+    /// Everyone waiting for a match, first come first served.
+    queue: VecDeque<u64>,
+    /// Set whenever the queue changes so everyone in it gets told their new position.
+    queue_changed: bool,
+}
+
+/// Where the matchmaker put a player.
+struct Seating {
+    client: u64,
+    room: Entity,
+    side: PlayerSide,
+}
+
+impl RoomManager {
+    fn enqueue(&mut self, client: u64) {
+        self.queue.push_back(client);
+        self.queue_changed = true;
+    }
+
+    /// Pairs players off the front of the queue.
+    /// Rooms that lost a player get topped up first so nobody is left waiting on their own,
+    /// then everyone left over is paired into new rooms, as long as we're allowed more.
+    fn matchmake(&mut self, commands: &mut Commands, max_rooms: usize) -> Vec<Seating> {
+        let mut seated = Vec::new();
+
+        let mut open: Vec<Entity> = self.rooms.iter()
+            .filter(|(_, slots)| slots.free_side().is_some() && slots.players().count() == 1)
+            .map(|(&room, _)| room)
+            .collect();
+        // HashMap order isn't stable, so fill the oldest rooms first.
+        open.sort();
+        for room in open {
+            match self.queue.pop_front() {
+                Some(client) => seated.push(self.seat(client, room)),
+                None => break,
+            }
+        }
+
+        while self.queue.len() >= 2 && self.rooms.len() < max_rooms {
+            let entities = spawn_room_server(commands);
+            self.rooms.insert(entities.room, RoomSlots::new(entities));
+            for _ in 0..2 {
+                let client = self.queue.pop_front().unwrap();
+                seated.push(self.seat(client, entities.room));
+            }
+        }
+
+        if !seated.is_empty() {
+            self.queue_changed = true;
+        }
+        seated
+    }
+
+    /// Gives a client a paddle in a room, taking them out of whatever room they were watching.
+    fn seat(&mut self, client: u64, room: Entity) -> Seating {
+        if let Some(slots) = self.clients.remove(&client).and_then(|prev| self.rooms.get_mut(&prev)) {
+            slots.spectators.retain(|&id| id != client);
+        }
+        let slots = self.rooms.get_mut(&room).unwrap();
+        let side = slots.free_side().unwrap();
+        slots.set(side, Some(client));
+        self.clients.insert(client, room);
+        Seating { client, room, side }
+    }
+
+    /// Lets a queued client watch a match while they wait.
+    /// Picks the full room with the fewest spectators, so the audience gets spread out.
+    /// Only for server matches, we never have the state of a rollback one to show anyone.
+    fn spectate(&mut self, client: u64) -> Option<Entity> {
+        let (&room, slots) = self.rooms.iter_mut()
+            .min_by_key(|(_, slots)| (slots.free_side().is_some(), slots.spectators.len()))?;
+        slots.spectators.push(client);
+        self.clients.insert(client, room);
+        Some(room)
+    }
+
+    /// Keeps a dropped player's paddle for them, so they can resume with their token before `until`.
+    /// Returns their room, or None if they weren't playing in one.
+    fn hold(&mut self, client: u64, resume_token: ResumeToken, until: f64) -> Option<Entity> {
+        let &room = self.clients.get(&client)?;
+        let slots = self.rooms.get_mut(&room)?;
+        let side = slots.players().find(|(id, _)| *id == client)?.1;
+        slots.set(side, None);
+        // Their game is gone, so a rollback match can't carry on. A new one starts when they're back.
+        slots.rollback = None;
+        slots.held.push(HeldSeat { side, client, resume_token, until });
+        self.clients.remove(&client);
+        Some(room)
+    }
+
+    /// Gives a held paddle to the client resuming it.
+    fn reclaim(&mut self, client: u64, resume_token: ResumeToken) -> Option<Seating> {
+        let (&room, slots) = self.rooms.iter_mut().find(|(_, slots)| slots.held.iter().any(|seat| seat.resume_token == resume_token))?;
+        let i = slots.held.iter().position(|seat| seat.resume_token == resume_token)?;
+        let side = slots.held.remove(i).side;
+        slots.set(side, Some(client));
+        self.clients.insert(client, room);
+        Some(Seating { client, room, side })
+    }
+
+    /// Gives up every held paddle whose time ran out, returning them with the room they were in.
+    fn expire_held(&mut self, now: f64) -> Vec<(Entity, HeldSeat)> {
+        let mut expired = Vec::new();
+        for (&room, slots) in self.rooms.iter_mut() {
+            let (gone, kept) = std::mem::take(&mut slots.held).into_iter().partition(|seat| seat.until <= now);
+            slots.held = kept;
+            expired.extend(gone.into_iter().map(|seat: HeldSeat| (room, seat)));
+        }
+        expired
+    }
+
+    /// Takes a client out of their room and the queue, returning which room they were in and whether they were playing in it.
+    fn leave(&mut self, client: u64) -> Option<(Entity, bool)> {
+        if let Some(i) = self.queue.iter().position(|&id| id == client) {
+            self.queue.remove(i);
+            self.queue_changed = true;
+        }
+        let room = self.clients.remove(&client)?;
+        let slots = self.rooms.get_mut(&room)?;
+        let playing = slots.players().any(|(id, _)| id == client);
+        if slots.left == Some(client) {
+            slots.left = None;
+        }
+        if slots.right == Some(client) {
+            slots.right = None;
+        }
+        if slots.players().count() < 2 {
+            slots.rollback = None;
+        }
+        slots.spectators.retain(|&id| id != client);
+        Some((room, playing))
+    }
+
+    /// Checks a RollbackMessage from a client, and works out what to do with it. Gives back what's wrong with it if it makes no sense.
+    fn rollback_message(&mut self, client: u64, message: RollbackMessage) -> Result<RollbackRelay, &'static str> {
+        // They might have just been moved out of the match, in which case it's only late.
+        let room = match self.clients.get(&client) {
+            Some(&room) => room,
+            None => return Ok(RollbackRelay::Nothing),
+        };
+        let slots = self.rooms.get_mut(&room).unwrap();
+        let side = match slots.players().find(|(id, _)| *id == client) {
+            Some((_, side)) => side,
+            None => return Ok(RollbackRelay::Nothing),
+        };
+        let opponent = slots.players().find(|(id, _)| *id != client).map(|(id, _)| id);
+        let current = match slots.rollback.as_mut() {
+            Some(current) => current,
+            None => return Ok(RollbackRelay::Nothing),
+        };
+        let round = match message {
+            RollbackMessage::Start { .. } => return Err("sent a message only the server sends"),
+            RollbackMessage::Input { round, .. } | RollbackMessage::MatchOver { round, .. } => round,
+        };
+        if round > current.round {
+            return Err("sent inputs for a match that hasn't started");
+        }
+        if round < current.round {
+            return Ok(RollbackRelay::Nothing);
+        }
+        match message {
+            RollbackMessage::Input { frame, .. } => {
+                if frame != current.next_frame[side as usize] {
+                    return Err("sent rollback inputs out of order");
+                }
+                current.next_frame[side as usize] += 1;
+                Ok(opponent.map_or(RollbackRelay::Nothing, RollbackRelay::Forward))
+            }
+            RollbackMessage::MatchOver { winner, .. } => {
+                current.results[side as usize] = Some(winner);
+                let relay = match current.results {
+                    [Some(left), Some(right)] if left == right => RollbackRelay::MatchOver { room, winner },
+                    [Some(_), Some(_)] => RollbackRelay::Desync { room },
+                    _ => return Ok(RollbackRelay::Nothing),
+                };
+                slots.rollback = None;
+                Ok(relay)
+            }
+            RollbackMessage::Start { .. } => unreachable!(),
+        }
+    }
+
+    /// Despawns a room once nobody is playing in it or coming back to it.
+    /// Returns the spectators who were watching it so they can be sent somewhere else, or None if the room is still going.
+    fn remove_if_empty(&mut self, room: Entity, commands: &mut Commands) -> Option<Vec<u64>> {
+        match self.rooms.get(&room) {
+            Some(slots) if slots.players().next().is_none() && slots.held.is_empty() => {
+                slots.entities.despawn(commands);
+                let slots = self.rooms.remove(&room)?;
+                for id in slots.spectators.iter() {
+                    self.clients.remove(id);
+                }
+                Some(slots.spectators)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Sends a message to everyone in a room, spectators included.
+fn send_to_room(server: &mut GameServer, slots: &RoomSlots, channel: u8, message: Vec<u8>) {
+    for client_id in slots.members() {
+        server.send_message(client_id, channel, message.clone());
+    }
+}
+
+/// Tells a client who is playing in a room, so they can put names to the paddles.
+fn send_roster(server: &mut GameServer, slots: &RoomSlots, shared: &SharedTokenState, client_id: u64) {
+    // We could send an InitState with all the players id and positions for the client
+    // but this is easier to do.
+    let state = shared.0.lock().unwrap();
+    for (player_id, side) in slots.players().filter(|(player_id, _)| *player_id != client_id) {
+        if let Some(name) = state.usernames.get(&player_id) {
+            let message = bincode::serialize(&ServerMessages::PlayerConnected { id: player_id, username: name.clone(), side: Some(side) }).unwrap();
+            server.send_message(client_id, 0, message);
+        }
+    }
+}
+
+/// Gives a queued client a match to watch while they wait, if there's one going.
+fn watch_a_room(server: &mut GameServer, rooms: &mut RoomManager, shared: &SharedTokenState, client_id: u64) {
+    let room = match rooms.spectate(client_id) {
+        Some(room) => room,
+        None => return,
+    };
+    let message = bincode::serialize(&ServerMessages::PlayerIsSpectator).unwrap();
+    server.send_message(client_id, 0, message);
+    send_roster(server, &rooms.rooms[&room], shared, client_id);
+}
+
+/// Hands a client the paddle they were just seated at, and tells them and the rest of the room about it.
+fn take_seat(
+    commands: &mut Commands,
+    lobby: &mut Lobby,
+    server: &mut GameServer,
+    rooms: &RoomManager,
+    shared: &SharedTokenState,
+    seating: &Seating,
+) {
+    let Seating { client, room, side } = *seating;
+    let username = shared.0.lock().unwrap().usernames.get(&client).cloned().unwrap_or_else(|| format!("Player {}", client));
+    info!("Player {} ({}) is playing {:?} in room {:?}.", client, username, side, room);
+
+    let slots = &rooms.rooms[&room];
+    let player_entity = slots.entities.paddle(side);
+    commands.entity(player_entity)
+        .insert(Player {})
+        .insert(InputBuffer::default())
+        .insert(PlayerProfile { username: username.clone() });
+    lobby.players.insert(client, player_entity);
+
+    // Tell them first so they throw away whatever room they were watching, then fill them in on the new one.
+    let opponent = {
+        let state = shared.0.lock().unwrap();
+        slots.players().find(|(id, _)| *id != client).and_then(|(id, _)| state.usernames.get(&id).cloned())
+    };
+    let message = bincode::serialize(&ServerMessages::MatchFound { opponent }).unwrap();
+    server.send_message(client, 0, message);
+    send_roster(server, slots, shared, client);
+    let message = bincode::serialize(&ServerMessages::PlayerIsSide { side }).unwrap();
+    server.send_message(client, 0, message);
+
+    // Forward the new player to the rest of the room.
+    let message = bincode::serialize(&ServerMessages::PlayerConnected { id: client, username, side: Some(side) }).unwrap();
+    send_to_room(server, slots, 0, message);
+}
+
+/// Cleans up after someone is gone from a room for good, whether they left or never came back to a held seat.
+/// `was_playing` is false for spectators, who can leave without the match noticing.
+#[allow(clippy::too_many_arguments)]
+fn player_gone(
+    commands: &mut Commands,
+    server: &mut GameServer,
+    rooms: &mut RoomManager,
+    shared: &SharedTokenState,
+    room_states: &mut Query<(&mut RoomSim, Option<&MatchOverTimer>), With<Room>>,
+    room: Entity,
+    id: u64,
+    was_playing: bool,
+) {
+    // Nobody left playing in the room, so get rid of it and find the audience something else to watch.
+    if let Some(spectators) = rooms.remove_if_empty(room, commands) {
+        for client_id in spectators {
+            watch_a_room(server, rooms, shared, client_id);
+        }
+        return;
+    }
+
+    //If a player leaving drops the room below 2 players, then pause the game and reset the score.
+    //Not while a seat is held though, the player coming back to it gets the score they left.
+    let slots = &rooms.rooms[&room];
+    if was_playing && slots.players().count() < 2 && slots.held.is_empty() {
+        if let Ok((mut sim, _)) = room_states.get_mut(room) {
+            sim.playing = false;
+            sim.score = [0; 2];
+        }
+    }
+
+    // Forward the ClientDisconnected event to the rest of the room.
+    let message = bincode::serialize(&ServerMessages::PlayerDisconnected { id }).unwrap();
+    send_to_room(server, &rooms.rooms[&room], 0, message);
+}
+
+/// Starts a new match in a room that has both its players.
+/// The server simulates it from the starting positions, or in rollback mode the players are told to start simulating it themselves.
+/// Either way the serves come from a seed picked here, or the one in the settings, which is logged so the match can be played again.
+fn start_match(commands: &mut Commands, server: &mut GameServer, rooms: &mut RoomManager, room: Entity, settings: &ServerSettings) {
+    let seed = settings.seed.unwrap_or_else(|| thread_rng().next_u64());
+    rooms.rooms.get_mut(&room).unwrap().seed = seed;
+    kick_off(commands, server, rooms, room, settings.netcode, true);
+}
+
+/// Gets a room's match going again once a dropped player is back in their seat. It's still the match that was seeded in start_match:
+/// the server's serves carry on from where they were, and the players of a rollback match, who have to start over, get the same seed again.
+fn resume_match(commands: &mut Commands, server: &mut GameServer, rooms: &mut RoomManager, room: Entity, settings: &ServerSettings) {
+    kick_off(commands, server, rooms, room, settings.netcode, false);
+}
+
+/// Puts the ball and paddles in a room back at the start and serves, from the room's seed if `reseed`, for start_match and resume_match.
+fn kick_off(commands: &mut Commands, server: &mut GameServer, rooms: &mut RoomManager, room: Entity, netcode: NetcodeMode, reseed: bool) {
+    let slots = rooms.rooms.get_mut(&room).unwrap();
+    let seed = slots.seed;
+    let what = if reseed { "Starting" } else { "Resuming" };
+    match netcode {
+        //Signals to the reset system to reset and begin the game.
+        NetcodeMode::Server => {
+            info!("{} match in room {:?} with seed {}.", what, room, seed);
+            commands.entity(room).insert(ResetDue { seed: reseed.then_some(seed) });
+        }
+        NetcodeMode::Rollback => {
+            let round = slots.start_rollback();
+            info!("{} rollback match {} in room {:?} with seed {}.", what, round, room, seed);
+            for (client_id, side) in slots.players() {
+                let message = bincode::serialize(&RollbackMessage::Start { round, seed, side }).unwrap();
+                server.send_message(client_id, ROLLBACK_CHANNEL, message);
+            }
+        }
+    }
+    let message = bincode::serialize(&ServerMessages::MatchStarting { countdown: RESPAWN_DELAY }).unwrap();
+    send_to_room(server, slots, 0, message);
+}
+
+/// The game sockets for each of the game ports, see ServerSettings::udp_ports. The second is only there with key rotation on.
+/// They're bound on the game ports themselves, unless the network simulator has those.
+/// Whoever runs the server binds them, and we hang on to them for as long as it runs, so nothing else can take a port while a key isn't using it.
+pub struct GameSockets(pub [Option<UdpSocket>; 2]);
+
+/// Starts a renet server for one of the game ports, checking tokens signed with `pkey`.
+fn new_renet_server(settings: &ServerSettings, pkey: [u8; 32], sockets: &GameSockets, port: usize) -> std::io::Result<RenetServer> {
+    let socket = match &sockets.0[port] {
+        Some(socket) => socket.try_clone()?,
+        None => return Err(std::io::Error::new(std::io::ErrorKind::NotFound, format!("no socket for game port {}", port))),
+    };
+    let port = settings.udp_ports()[port];
+    let connection_config =  connection_config();
+    let public_addr = SocketAddr::new(settings.public_ip, port);
+    let server_config = ServerConfig::new(settings.max_clients, settings.protocol_id, public_addr, ServerAuthentication::Secure{ private_key:pkey});
+    let current_time = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap();
+    RenetServer::new(current_time, server_config, connection_config, socket)
+}
+
+/// The game server, which every system talks to the clients through.
+/// Renet only checks one private key per server, so for a while after the key is rotated there are two renet servers, each on its own port:
+/// the one for the new key, and the one for the previous key, kept for tokens signed with it and the players who used them.
+/// Messages go to whichever one the client is on.
+pub struct GameServer {
+    current: RenetServer,
+    previous: Option<RenetServer>,
+}
+
+impl GameServer {
+    /// Whether the previous key's server is still up, so tokens signed with either key can be used.
+    pub fn accepts_previous_key(&self) -> bool {
+        self.previous.is_some()
+    }
+
+    /// Where the current key's server is listening, the one new tokens point at.
+    pub fn addr(&self) -> SocketAddr {
+        self.current.addr()
+    }
+
+    fn servers(&mut self) -> impl Iterator<Item = &mut RenetServer> {
+        std::iter::once(&mut self.current).chain(self.previous.as_mut())
+    }
+
+    /// The renet server a client is on. Clients we don't know are looked for on the current one, same as they would have been.
+    fn server_of(&mut self, client_id: u64) -> &mut RenetServer {
+        match &mut self.previous {
+            Some(previous) if previous.network_info(client_id).is_some() => previous,
+            _ => &mut self.current,
+        }
+    }
+
+    pub fn send_message(&mut self, client_id: u64, channel: u8, message: Vec<u8>) {
+        self.server_of(client_id).send_message(client_id, channel, message);
+    }
+
+    pub fn receive_message(&mut self, client_id: u64, channel: u8) -> Option<Vec<u8>> {
+        self.server_of(client_id).receive_message(client_id, channel)
+    }
+
+    pub fn disconnect(&mut self, client_id: u64) {
+        self.server_of(client_id).disconnect(client_id);
+    }
+
+    /// Every connected client, on either server.
+    pub fn clients_id(&self) -> Vec<u64> {
+        let mut clients = self.current.clients_id();
+        if let Some(previous) = &self.previous {
+            clients.extend(previous.clients_id());
+        }
+        clients
+    }
+}
+
+/// What RenetServerPlugin does for a single RenetServer, for both of ours. Recieves packets and turns what happened into ServerEvents.
+fn update_game_server(
+    mut game: ResMut<GameServer>,
+    mut renet_error: EventWriter<RenetError>,
+    mut server_events: EventWriter<ServerEvent>,
+    time: Res<Time>,
+) {
+    for server in game.servers() {
+        if let Err(e) = server.update(time.delta()) {
+            renet_error.send(RenetError::IO(e));
+        }
+        while let Some(event) = server.get_event() {
+            server_events.send(event);
+        }
+    }
+}
+
+fn send_game_packets(mut game: ResMut<GameServer>, mut renet_error: EventWriter<RenetError>) {
+    for server in game.servers() {
+        if let Err(e) = server.send_packets() {
+            renet_error.send(RenetError::IO(e));
+        }
+    }
+}
+
+/// How long a connect token can be used for after we hand it out.
+const TOKEN_EXPIRE_SECONDS: u64 = 120;
+
+/// Makes a new random private key.
+pub fn generate_key() -> [u8; 32] {
+    let mut key = [0u8; 32];
+    thread_rng().fill_bytes(&mut key);
+    key
+}
+
+/// What the key file holds: the private key tokens are signed with, which game port its server is on,
+/// and the previous key while its server is still up. Enough for a restarted server to come back up where the tokens out there point.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SavedKeys {
+    pub active: [u8; 32],
+    /// An index into ServerSettings::udp_ports. The previous key's server, if there is one, is on the other port.
+    pub port: usize,
+    pub previous: Option<[u8; 32]>,
+}
+
+impl SavedKeys {
+    /// Just `active`, on udp_port, like a key that was never rotated.
+    pub fn new(active: [u8; 32]) -> Self {
+        SavedKeys { active, port: 0, previous: None }
+    }
+}
+
+/// Reads the keys written by `write_key_file`. The first line is the active key as 64 hex characters, which is all `--generate-key` writes.
+/// After a rotation there's also a `port = 1` line when the key is on the second game port, and `previous = <key>` until the previous key is retired.
+pub fn read_key_file(path: &Path) -> std::io::Result<SavedKeys> {
+    let contents = fs::read_to_string(path)?;
+    let mut lines = contents.lines().map(str::trim).filter(|line| !line.is_empty());
+    let mut keys = SavedKeys::new(parse_key(lines.next().unwrap_or_default())?);
+    for line in lines {
+        match line.split_once('=').map(|(name, value)| (name.trim(), value.trim())) {
+            Some(("port", "0")) => keys.port = 0,
+            Some(("port", "1")) => keys.port = 1,
+            Some(("previous", hex)) => keys.previous = Some(parse_key(hex)?),
+            _ => return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("unexpected line in key file: {}", line))),
+        }
+    }
+    Ok(keys)
+}
+
+fn parse_key(hex: &str) -> std::io::Result<[u8; 32]> {
+    let invalid = || std::io::Error::new(std::io::ErrorKind::InvalidData, "keys must be exactly 64 hex characters");
+    if hex.len() != 64 || !hex.is_ascii() {
+        return Err(invalid());
+    }
+    let mut key = [0u8; 32];
+    for (i, byte) in key.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).map_err(|_| invalid())?;
+    }
+    Ok(key)
+}
+
+fn key_hex(key: &[u8; 32]) -> String {
+    key.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Writes the keys as hex, see read_key_file. Goes through a temporary file so a crash can't leave half a key behind.
+/// On unix only we can read it, like an ssh key.
+pub fn write_key_file(path: &Path, keys: &SavedKeys) -> std::io::Result<()> {
+    let mut contents = key_hex(&keys.active) + "\n";
+    if keys.port != 0 {
+        contents += &format!("port = {}\n", keys.port);
+    }
+    if let Some(previous) = &keys.previous {
+        contents += &format!("previous = {}\n", key_hex(previous));
+    }
+
+    let tmp = path.with_extension("tmp");
+    // Permissions only apply to new files, so don't reuse one left behind by a crash.
+    match fs::remove_file(&tmp) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(&tmp)?;
+    file.write_all(contents.as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)
+}
+
+/// The private keys connect tokens are signed with, see GameServer.
+/// Every new key goes on the other game port, so its server can start while the previous key's is still up.
+#[derive(Debug, Default)]
+struct KeyRing {
+    /// Every token is signed with this one.
+    active: [u8; 32],
+    /// Which game port the active key's server is on, an index into ServerSettings::udp_ports.
+    port: usize,
+    /// The key before this one, while its server is still up.
+    previous: Option<[u8; 32]>,
+    /// When we last signed a token with the active key.
+    last_signed: Option<Instant>,
+    /// When we last signed a token with the previous key, while its server is still up.
+    previous_signed: Option<Instant>,
+}
+
+impl KeyRing {
+    /// Picks up from the keys in a key file. We can't know when a token was last signed with the previous key before the restart,
+    /// so it gets the whole grace period again.
+    fn new(saved: SavedKeys) -> Self {
+        let previous_signed = saved.previous.map(|_| Instant::now());
+        KeyRing { active: saved.active, port: saved.port, previous: saved.previous, previous_signed, ..default() }
+    }
+
+    /// What to save to the key file.
+    fn saved(&self) -> SavedKeys {
+        SavedKeys { active: self.active, port: self.port, previous: self.previous }
+    }
+
+    /// Makes `key` the one tokens are signed with, on the other game port. Gives back that port's index.
+    fn rotate(&mut self, key: [u8; 32]) -> usize {
+        self.previous = Some(std::mem::replace(&mut self.active, key));
+        self.port = 1 - self.port;
+        self.previous_signed = self.last_signed.take();
+        self.port
+    }
+
+    /// Forgets the previous key, once its server is gone.
+    fn retire(&mut self) {
+        self.previous = None;
+        self.previous_signed = None;
+    }
+
+    /// Why the previous key's server has to stay up, for the log. None once it can go.
+    /// It stays until every token signed with the previous key has had the grace period to be used, and all of its `connected` players have left.
+    fn previous_needed(&self, grace: Duration, connected: usize) -> Option<String> {
+        let signed = self.previous_signed.map(|signed| signed.elapsed()).filter(|&elapsed| elapsed < grace);
+        match (connected, signed) {
+            (0, None) => None,
+            (0, Some(elapsed)) => Some(format!("a token was signed with it {}s ago", elapsed.as_secs())),
+            (clients, None) => Some(format!("{} clients are connected to it", clients)),
+            (clients, Some(elapsed)) => {
+                Some(format!("{} clients are connected to it and a token was signed with it {}s ago", clients, elapsed.as_secs()))
+            }
+        }
+    }
+}
+
+/// Counts down to the next key rotation.
+struct KeyRotationTimer(Timer);
+
+/// How long a name stays taken after we hand out a token for it, giving the client time to actually connect.
+/// As long as the token lasts, so nobody else can get a token for the name while the first one can still be used.
+const NAME_RESERVATION: Duration = Duration::from_secs(TOKEN_EXPIRE_SECONDS);
+
+/// A name we've handed out a token for, which hasn't been used yet.
+#[derive(Debug, Clone, Copy)]
+struct NameReservation {
+    /// The instance of the client that asked for it, see ClientMessagesTcp::AuthenticationRequest.
+    instance: u64,
+    /// The client id in the newest token for it. Only that one gets the name, older tokens are spent.
+    client: u64,
+    until: Instant,
+}
+
+/// State shared between the game and the token service thread, so the token service can answer with up to date information.
+#[derive(Debug, Default)]
+struct TokenServiceState {
+    /// Number of clients currently connected to the game server.
+    connected: usize,
+    /// Names of the players currently in the game.
+    usernames: HashMap<u64, String>,
+    /// Names we've handed out tokens for, keyed by the lowercase name.
+    reserved: HashMap<String, NameReservation>,
+    /// The resume token handed out to each client, and when. Entries for clients that never turn up are dropped by update_token_state.
+    sessions: HashMap<u64, (ResumeToken, Instant)>,
+    /// Players who dropped out of a match and can still come back, with their name and when their seat goes.
+    held: HashMap<ResumeToken, (String, Instant)>,
+    /// Clients we've given a token to resume with, and which session they're resuming.
+    resuming: HashMap<u64, ResumeToken>,
+    /// Connections someone has resumed from before the game server noticed they were gone.
+    /// The game server disconnects them, which holds their seat like any other dropped connection.
+    superseded: Vec<u64>,
+    keys: KeyRing,
+}
+
+impl TokenServiceState {
+    /// Claims a name for a client, `instance` being the client instance asking. Returns false if someone else already has it.
+    /// A client asking again, because it couldn't connect with the last token, takes over its own reservation.
+    /// Names are compared ignoring case so nobody can pose as another player.
+    fn reserve_username(&mut self, id: u64, instance: u64, username: &str) -> bool {
+        let now = Instant::now();
+        self.reserved.retain(|_, reservation| reservation.until > now);
+
+        let key = username.to_lowercase();
+        let in_game = self.usernames.iter().any(|(&client, name)| client != id && name.to_lowercase() == key);
+        let reserved = self.reserved.get(&key).is_some_and(|reservation| reservation.instance != instance);
+        // Someone who dropped out keeps their name until they're back or their seat is gone.
+        let held = self.held.values().any(|(name, _)| name.to_lowercase() == key);
+        if in_game || reserved || held {
+            return false;
+        }
+        self.reserved.insert(key, NameReservation { instance, client: id, until: now + NAME_RESERVATION });
+        true
+    }
+
+    /// Puts a client that just connected in the game under `username`. Returns false, and leaves them out, if someone else has the name.
+    /// The token service already checked, but the name could have been taken since, and the token could have come from somewhere else.
+    fn player_joined(&mut self, id: u64, username: &str) -> bool {
+        let key = username.to_lowercase();
+        let in_game = self.usernames.iter().any(|(&client, name)| client != id && name.to_lowercase() == key);
+        let reserved = self.reserved.get(&key).is_some_and(|reservation| reservation.client != id && reservation.until > Instant::now());
+        // Only the player a held seat is being kept for gets its name.
+        let resuming = self.resuming.get(&id);
+        let held = self.held.iter().any(|(token, (name, _))| Some(token) != resuming && name.to_lowercase() == key);
+        if in_game || reserved || held {
+            return false;
+        }
+        self.reserved.remove(&key);
+        self.usernames.insert(id, username.to_string());
+        true
+    }
+
+    fn player_left(&mut self, id: u64) {
+        self.usernames.remove(&id);
+    }
+
+    /// The connected client whose session `resume_token` belongs to, and their name.
+    /// Their connection is usually dead, the client just noticed before we did.
+    fn live_session(&self, resume_token: ResumeToken) -> Option<(u64, String)> {
+        self.sessions.iter()
+            .filter(|(_, (token, _))| *token == resume_token)
+            .find_map(|(&id, _)| Some((id, self.usernames.get(&id)?.clone())))
+    }
+
+    /// Makes a resume token for a client we're about to give a connect token.
+    fn start_session(&mut self, id: u64) -> ResumeToken {
+        let mut token = [0; 16];
+        thread_rng().fill_bytes(&mut token);
+        let token = ResumeToken(token);
+        self.sessions.insert(id, (token, Instant::now()));
+        token
+    }
+}
+
+/// The token service's state, shared between its thread and the game server. Made from the private keys tokens are first signed with.
+#[derive(Clone)]
+pub struct SharedTokenState(Arc<Mutex<TokenServiceState>>);
+
+impl SharedTokenState {
+    pub fn new(keys: SavedKeys) -> Self {
+        SharedTokenState(Arc::new(Mutex::new(TokenServiceState { keys: KeyRing::new(keys), ..default() })))
+    }
+}
+
+/// Everything that can go wrong while serving a token request.
+/// None of these are fatal, the worker just logs it and moves on to the next connection.
+#[derive(Debug)]
+enum TokenServiceError {
+    Io(std::io::Error),
+    Decode(bincode::Error),
+}
+
+impl fmt::Display for TokenServiceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TokenServiceError::Io(e) => write!(f, "io error: {}", e),
+            TokenServiceError::Decode(e) => write!(f, "bad message: {}", e),
+        }
+    }
+}
+
+impl From<std::io::Error> for TokenServiceError {
+    fn from(e: std::io::Error) -> Self {
+        TokenServiceError::Io(e)
+    }
+}
+
+impl From<bincode::Error> for TokenServiceError {
+    fn from(e: bincode::Error) -> Self {
+        TokenServiceError::Decode(e)
+    }
+}
+
+/// Serves a single token request. Always tries to send the client a reply, even if it's just a rejection.
+fn handle_connection(mut stream: TcpStream, settings: &ServerSettings, shared: &SharedTokenState) -> Result<(), TokenServiceError> {
+    // Without these a client that connects and never sends anything would tie up a worker forever.
+    stream.set_read_timeout(Some(TCP_TIMEOUT))?;
+    stream.set_write_timeout(Some(TCP_TIMEOUT))?;
+
+    let message: ClientMessagesTcp = match read_tcp_message(&mut stream) {
+        Ok(message) => message,
+        Err(e) => {
+            // They might not be listening anymore, but let them know in case they are.
+            let reply = ServerMessagesTcp::Rejected { reason: "Malformed request.".to_string() };
+            let _ = write_tcp_message(&mut stream, &reply);
+            return Err(e.into());
+        }
+    };
+    let (id, version, client_rules_hash) = match &message {
+        ClientMessagesTcp::AuthenticationRequest { id, version, rules_hash, .. } => (*id, version, *rules_hash),
+        ClientMessagesTcp::ResumeRequest { id, version, rules_hash, .. } => (*id, version, *rules_hash),
+    };
+    // Old clients would happily connect and then misread every GameState, so turn them away here with a useful message.
+    let reply = if version != GAME_VERSION || client_rules_hash != rules_hash() {
+        warn!("Refusing client {} running version {} with rules {:016x}.", id, version, client_rules_hash);
+        ServerMessagesTcp::VersionMismatch { server_version: GAME_VERSION.to_string(), server_rules_hash: rules_hash() }
+    } else {
+        match message {
+            ClientMessagesTcp::AuthenticationRequest { id, username, instance, .. } => authenticate(id, instance, username, settings, shared),
+            ClientMessagesTcp::ResumeRequest { id, resume_token, .. } => resume(id, resume_token, settings, shared),
+        }
+    };
+    write_tcp_message(&mut stream, &reply)?;
+    Ok(())
+}
+
+/// Decides whether the client gets a connect token, and generates it if so.
+fn authenticate(id: u64, instance: u64, username: String, settings: &ServerSettings, shared: &SharedTokenState) -> ServerMessagesTcp {
+    let username = match validate_username(&username) {
+        Ok(username) => username,
+        Err(e) => return ServerMessagesTcp::Rejected { reason: format!("Invalid username: {}.", e) },
+    };
+    let (pkey, port) = {
+        let mut state = shared.0.lock().unwrap();
+        if state.connected >= settings.max_clients {
+            return ServerMessagesTcp::ServerFull;
+        }
+        if !state.reserve_username(id, instance, &username) {
+            return ServerMessagesTcp::NameTaken { username };
+        }
+        // Noting this keeps the key's server up after the next rotation, until this token has had its chance to be used.
+        state.keys.last_signed = Some(Instant::now());
+        (state.keys.active, settings.udp_ports()[state.keys.port])
+    };
+
+    match sign_token(id, &username, settings, &pkey, port) {
+        Ok(token) => {
+            let resume_token = shared.0.lock().unwrap().start_session(id);
+            ServerMessagesTcp::TokenGranted { token, resume_token }
+        }
+        Err(reply) => reply,
+    }
+}
+
+/// Gives a client that dropped out of a match a connect token to get back in with, if their seat is still being held.
+fn resume(id: u64, resume_token: ResumeToken, settings: &ServerSettings, shared: &SharedTokenState) -> ServerMessagesTcp {
+    let (username, pkey, port) = {
+        let mut state = shared.0.lock().unwrap();
+        let held = match state.held.get(&resume_token) {
+            Some((username, until)) if *until > Instant::now() => Some(username.clone()),
+            _ => None,
+        };
+        let username = match (held, state.live_session(resume_token)) {
+            (Some(username), _) => {
+                if state.connected >= settings.max_clients {
+                    return ServerMessagesTcp::ServerFull;
+                }
+                username
+            }
+            // Their old connection is still up as far as we know. It can go now, and its seat is held for them as usual.
+            // That frees up the connection they're about to make, so the server being full doesn't matter.
+            (None, Some((old_id, username))) if settings.resume_grace_secs > 0 => {
+                info!("Client {} is resuming from client {}, which we hadn't noticed was gone.", id, old_id);
+                state.superseded.push(old_id);
+                username
+            }
+            _ => return ServerMessagesTcp::Rejected { reason: "There's no match to go back to any more.".to_string() },
+        };
+        // The game server looks this up when they connect, to give them their paddle back.
+        state.resuming.insert(id, resume_token);
+        state.sessions.insert(id, (resume_token, Instant::now()));
+        state.keys.last_signed = Some(Instant::now());
+        (username, state.keys.active, settings.udp_ports()[state.keys.port])
+    };
+    info!("Client {} is resuming {}'s session.", id, username);
+
+    match sign_token(id, &username, settings, &pkey, port) {
+        Ok(token) => ServerMessagesTcp::TokenGranted { token, resume_token },
+        Err(reply) => reply,
+    }
+}
+
+/// Generates a connect token for the game server on `port`, and writes it out for sending. Gives back the reply to send instead if that fails.
+fn sign_token(id: u64, username: &str, settings: &ServerSettings, pkey: &[u8; 32], port: u16) -> Result<Vec<u8>, ServerMessagesTcp> {
+    // The name travels to the game server inside the token, so it can't be tampered with on the way.
+    let data = username_to_user_data(username);
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    let addr = SocketAddr::new(settings.public_ip, port);
+    let token = match ConnectToken::generate(
+        now,
+        settings.protocol_id,
+        TOKEN_EXPIRE_SECONDS,
+        id,
+        30,
+        vec![addr],
+        Some(&data),
+        pkey
+    ) {
+        Ok(token) => token,
+        Err(e) => {
+            error!("Failed to generate connect token for {}: {:?}", id, e);
+            return Err(ServerMessagesTcp::Rejected { reason: "The server could not create a connect token.".to_string() });
+        }
+    };
+    let mut token_bytes = Vec::new();
+    // Writing into a Vec can't fail.
+    token.write(&mut token_bytes).unwrap();
+    Ok(token_bytes)
+}
+
+/// Runs the token service on `listener`, handing out connect tokens until the process exits.
+pub fn tcpserver(listener: TcpListener, settings: ServerSettings, shared: SharedTokenState) {
+    let pool = ThreadPool::new(4);
+    for stream in listener.incoming() {
+        match stream {
+            Ok(s) => {
+                let settings = settings.clone();
+                let shared = shared.clone();
+                pool.execute(move|| {
+                    let peer = s.peer_addr();
+                    if let Err(e) = handle_connection(s, &settings, &shared) {
+                        warn!("Token request from {:?} failed: {}", peer, e);
+                    }
+                });
+            }
+            // Usually this is the client hanging up before we accepted, nothing we can do about it.
+            Err(e) => error!("Failed to accept token request: {}", e),
+        }
+    }
+    pool.join();
+}
+
+/// Adds the game server to an app: the renet servers on `sockets` for the keys in `shared`,
+/// all of its resources and systems, and the game itself (see common_game.rs).
+/// Which plugins it runs with, the token service thread and the network simulator are left to whoever is running it.
+/// bin/server.rs runs it for real, the tests in tests/ run it alongside their own clients.
+pub fn add_server_to_app(mut app: App, settings: ServerSettings, shared: SharedTokenState, sockets: GameSockets) -> std::io::Result<App> {
+    app.insert_resource(Lobby::default());
+    app.insert_resource(RoomManager::default());
+    app.insert_resource(SendTimer(Timer::from_seconds(POLL_RATE, true)));
+    app.insert_resource(DeltaEncoders::default());
+    app.insert_resource(SyncBandwidth { full_bytes: 0, sent_bytes: 0, timer: Timer::from_seconds(BANDWIDTH_LOG_SECONDS, true) });
+    app.add_event::<ServerEvent>();
+    app.add_event::<RenetError>();
+    app.add_system_to_stage(CoreStage::PreUpdate, update_game_server);
+    app.add_system_to_stage(CoreStage::PostUpdate, send_game_packets);
+    app.insert_resource(ClientLinks::default());
+    app.insert_resource(ClientGuards::default());
+    app.insert_resource(HeartbeatTimer(Timer::new(Duration::from_millis(settings.heartbeat_interval_ms), true)));
+    let keys = {
+        let mut state = shared.0.lock().unwrap();
+        // Without rotation there's only udp_port, whatever a key file from when it was on says.
+        if settings.key_rotation_secs == 0 {
+            state.keys = KeyRing::new(SavedKeys::new(state.keys.active));
+        }
+        state.keys.saved()
+    };
+    // Each key comes back up on the port its tokens point at.
+    let current = new_renet_server(&settings, keys.active, &sockets, keys.port)?;
+    let previous = keys.previous.map(|key| new_renet_server(&settings, key, &sockets, 1 - keys.port)).transpose()?;
+    app.insert_resource(GameServer { current, previous });
+    app.insert_resource(sockets);
+    if settings.key_rotation_secs > 0 {
+        app.insert_resource(KeyRotationTimer(Timer::from_seconds(settings.key_rotation_secs as f32, true)));
+        app.add_system(rotate_keys);
+        app.add_system(retire_previous_key);
+    }
+    app.insert_resource(settings);
+    app.insert_resource(shared);
+    app.add_system(server_update_system);
+    app.add_system(matchmaking_system);
+    app.add_system(server_sync_players);
+    app.add_system(heartbeat_system);
+    app.add_system(update_lag_compensation);
+    app.add_system(log_error_system);
+    app.add_system(resetter);
+    app.add_system(forward_game_events);
+    app.add_system(restart_finished_matches);
+    app.add_system(expire_held_seats);
+    app.add_system(update_token_state);
+
+    // All of the actual game systems and resources are added in here. See common_game.rs
+    Ok(add_to_app_server(app))
+}
+
+/// Puts every room that just got its second player back to the starting positions, and starts the game.
+fn resetter(mut commands: Commands, mut rooms: Query<(Entity, &mut RoomSim, &ResetDue), With<Room>>) {
+    for (room, mut sim, reset) in rooms.iter_mut() {
+        //Make sure system only fires this once
+        commands.entity(room).remove::<ResetDue>();
+
+        //Reset the ball and paddles, reseed the serves and allow the game to start.
+        if let Some(seed) = reset.seed {
+            sim.rng = SimRng::new(seed);
+        }
+        sim.restart();
+    }
+}
+
+/// Server update system recieves from all of the clients.
+/// Manages users connecting, disconnecting, input, etc.
+#[allow(clippy::too_many_arguments)]
+fn server_update_system(
+    mut server_events: EventReader<ServerEvent>,
+    mut commands: Commands,
+    mut lobby: ResMut<Lobby>,
+    mut rooms: ResMut<RoomManager>,
+    mut server: ResMut<GameServer>,
+    mut links: ResMut<ClientLinks>,
+    mut room_states: Query<(&mut RoomSim, Option<&MatchOverTimer>), With<Room>>,
+    mut input_buffers: Query<&mut InputBuffer>,
+    mut encoders: ResMut<DeltaEncoders>,
+    mut guards: ResMut<ClientGuards>,
+    settings: Res<ServerSettings>,
+    shared: Res<SharedTokenState>,
+    time: Res<Time>,
+) {
+    let now = time.seconds_since_startup();
+    for event in server_events.iter() {
+        match event {
+            ServerEvent::ClientConnected(id, user_data) => {
+                // The token service already checked the name, so this only falls back if the token came from somewhere else.
+                let username = username_from_user_data(&user_data[..]).unwrap_or_else(|| format!("Player {}", id));
+                if !shared.0.lock().unwrap().player_joined(*id, &username) {
+                    warn!("Player {} connected as {}, but someone else already has that name. Disconnecting them.", id, username);
+                    server.disconnect(*id);
+                    continue;
+                }
+                info!("Player {} ({}) connected.", id, username);
+                links.0.insert(*id, LinkStats::new(now));
+
+                // Someone coming back to a held seat gets their paddle back, and the match carries on where it was.
+                let resuming = {
+                    let mut state = shared.0.lock().unwrap();
+                    let token = state.resuming.remove(id);
+                    if let Some(token) = token {
+                        state.held.remove(&token);
+                    }
+                    token
+                };
+                if let Some(seating) = resuming.and_then(|token| rooms.reclaim(*id, token)) {
+                    info!("Player {} ({}) resumed their match.", id, username);
+                    take_seat(&mut commands, &mut lobby, &mut server, &rooms, &shared, &seating);
+                    let match_over = room_states.get(seating.room).is_ok_and(|(_, over)| over.is_some());
+                    if rooms.rooms[&seating.room].players().count() >= 2 && !match_over {
+                        // The score is kept, only the ball and paddles go back to the start.
+                        // Rollback matches start over, the score was only ever in the players' games.
+                        resume_match(&mut commands, &mut server, &mut rooms, seating.room, &settings);
+                    }
+                    continue;
+                }
+
+                // Everyone else starts in the queue, matchmaking_system hands out the paddles.
+                // Rollback matches are only played out in the players' games, so there's nothing to watch in those.
+                rooms.enqueue(*id);
+                if settings.netcode == NetcodeMode::Server {
+                    watch_a_room(&mut server, &mut rooms, &shared, *id);
+                }
+            }
+            ServerEvent::ClientDisconnected(id) => {
+                info!("Player {} disconnected.", id);
+                let (username, session) = {
+                    let mut state = shared.0.lock().unwrap();
+                    let username = state.usernames.get(id).cloned();
+                    state.player_left(*id);
+                    (username, state.sessions.remove(id))
+                };
+                encoders.0.remove(id);
+                links.0.remove(id);
+                guards.0.remove(id);
+
+                // If they're associated with an entity, remove that association. This frees up paddles for other players who connect.
+                if let Some(player_entity) = lobby.players.remove(id) {
+                    commands.entity(player_entity).remove::<Player>().remove::<InputBuffer>().remove::<PlayerProfile>();
+                }
+
+                // Players get a while to come back before their paddle is given up. The match waits for them, score and all.
+                if let (Some(username), Some((resume_token, _))) = (username, session) {
+                    let grace = settings.resume_grace_secs;
+                    if grace > 0 {
+                        if let Some(room) = rooms.hold(*id, resume_token, now + grace as f64) {
+                            info!("Holding {}'s paddle for {} seconds.", username, grace);
+                            shared.0.lock().unwrap().held.insert(resume_token, (username, Instant::now() + Duration::from_secs(grace)));
+                            if let Ok((mut sim, _)) = room_states.get_mut(room) {
+                                sim.playing = false;
+                            }
+                            let message = bincode::serialize(&ServerMessages::PlayerReconnecting { id: *id, grace: grace as f32 }).unwrap();
+                            send_to_room(&mut server, &rooms.rooms[&room], 0, message);
+                            continue;
+                        }
+                    }
+                }
+
+                let (room, was_playing) = match rooms.leave(*id) {
+                    Some(left) => left,
+                    None => continue,
+                };
+                player_gone(&mut commands, &mut server, &mut rooms, &shared, &mut room_states, room, *id, was_playing);
+            }
+        }
+    }
+
+    for client_id in server.clients_id().into_iter() {
+        // Anything at all from a client shows they're still there.
+        let link = links.0.entry(client_id).or_insert_with(|| LinkStats::new(now));
+        // Nothing they send is trusted until it's decoded and checked, and they only get to send so much of it.
+        let guard = guards.0.entry(client_id).or_insert_with(|| ClientGuard::new(now, settings.max_messages_per_sec));
+        guard.update(now, settings.max_messages_per_sec);
+        let mut kick = false;
+
+        // Recieve input here.
+        while let Some(message) = server.receive_message(client_id, 0) {
+            link.heard(now);
+            if !guard.allow_input() {
+                kick |= guard.offend(client_id, Offence::Flooding, now, &settings);
+                continue;
+            }
+            let input_message: InputMessage = match decode_client_message(&message) {
+                Ok(input_message) => input_message,
+                Err(error) => {
+                    kick |= guard.offend(client_id, Offence::Malformed { channel: 0, error }, now, &settings);
+                    continue;
+                }
+            };
+            // Queue the player inputs on their entity for the movement system to work through.
+            // Spectators have nothing to steer, their inputs are just ignored.
+            if let Some(player_entity) = lobby.players.get(&client_id) {
+                if let Ok(mut buffer) = input_buffers.get_mut(*player_entity) {
+                    // Channel 0 is ordered, but there's no reason to trust a client that says otherwise.
+                    let newest = buffer.pending.back().map_or(buffer.last_applied, |queued| queued.sequence);
+                    if input_message.sequence > newest.saturating_add(MAX_INPUT_SKIP) {
+                        kick |= guard.offend(client_id, Offence::Nonsense("skipped ahead too many inputs"), now, &settings);
+                    } else if buffer.pending.len() >= MAX_PENDING_INPUTS {
+                        kick |= guard.offend(client_id, Offence::Nonsense("sent inputs faster than they can be played"), now, &settings);
+                    } else if input_message.sequence > newest {
+                        buffer.pending.push_back(input_message);
+                    }
+                }
+            }
+        }
+        // Recieve acknowledgements for the GameStates we've sent, so later ones can be based on them.
+        while let Some(message) = server.receive_message(client_id, 1) {
+            link.heard(now);
+            if !guard.allow() {
+                kick |= guard.offend(client_id, Offence::Flooding, now, &settings);
+                continue;
+            }
+            let ack: SnapshotAck = match decode_client_message(&message) {
+                Ok(ack) => ack,
+                Err(error) => {
+                    kick |= guard.offend(client_id, Offence::Malformed { channel: 1, error }, now, &settings);
+                    continue;
+                }
+            };
+            if let Some(encoder) = encoders.0.get_mut(&client_id) {
+                if !encoder.ack(ack.sequence) {
+                    kick |= guard.offend(client_id, Offence::Nonsense("acknowledged a GameState we never sent"), now, &settings);
+                }
+            }
+        }
+        // Recieve the inputs and results of rollback matches, and pass the inputs on.
+        while let Some(message) = server.receive_message(client_id, ROLLBACK_CHANNEL) {
+            link.heard(now);
+            if !guard.allow() {
+                kick |= guard.offend(client_id, Offence::Flooding, now, &settings);
+                continue;
+            }
+            let rollback_message: RollbackMessage = match decode_client_message(&message) {
+                Ok(rollback_message) => rollback_message,
+                Err(error) => {
+                    kick |= guard.offend(client_id, Offence::Malformed { channel: ROLLBACK_CHANNEL, error }, now, &settings);
+                    continue;
+                }
+            };
+            match rooms.rollback_message(client_id, rollback_message) {
+                // It's already been checked, so it can go on exactly as it came in.
+                Ok(RollbackRelay::Forward(opponent)) => server.send_message(opponent, ROLLBACK_CHANNEL, message),
+                Ok(RollbackRelay::Nothing) => (),
+                Ok(RollbackRelay::MatchOver { room, winner }) => {
+                    info!("{:?} won the rollback match in room {:?}, seed {}.", winner, room, rooms.rooms[&room].seed);
+                    commands.entity(room).insert(MatchOverTimer(Timer::from_seconds(MATCH_RESTART_DELAY, false)));
+                }
+                Ok(RollbackRelay::Desync { room }) => {
+                    // Nobody can say who really won. Start them off again from the same place.
+                    warn!("The players in room {:?} disagree on who won their rollback match with seed {}, their games must have drifted apart.", room, rooms.rooms[&room].seed);
+                    commands.entity(room).insert(MatchOverTimer(Timer::from_seconds(MATCH_RESTART_DELAY, false)));
+                }
+                Err(what) => kick |= guard.offend(client_id, Offence::Nonsense(what), now, &settings),
+            }
+        }
+        // Recieve ClientMessages here. Currently this is just answers to our pings.
+        while let Some(message) = server.receive_message(client_id, HEARTBEAT_CHANNEL) {
+            if !guard.allow() {
+                kick |= guard.offend(client_id, Offence::Flooding, now, &settings);
+                continue;
+            }
+            let recieved: ClientMessages = match decode_client_message(&message) {
+                Ok(recieved) => recieved,
+                Err(error) => {
+                    kick |= guard.offend(client_id, Offence::Malformed { channel: HEARTBEAT_CHANNEL, error }, now, &settings);
+                    continue;
+                }
+            };
+            match recieved {
+                ClientMessages::Pong { sequence, sent_at, view_delay_ms } => link.pong(sequence, sent_at, view_delay_ms, now),
+            }
+        }
+
+        if kick {
+            warn!("Kicking client {} for abusing the server.", client_id);
+            // No holding their paddle for them either.
+            shared.0.lock().unwrap().sessions.remove(&client_id);
+            server.disconnect(client_id);
+        }
+    }
+}
+
+/// Pairs up players from the queue, hands them their paddles, and lets everyone still waiting know where they are in line.
+fn matchmaking_system(
+    mut commands: Commands,
+    mut lobby: ResMut<Lobby>,
+    mut rooms: ResMut<RoomManager>,
+    mut server: ResMut<GameServer>,
+    settings: Res<ServerSettings>,
+    shared: Res<SharedTokenState>,
+) {
+    let seated = rooms.matchmake(&mut commands, settings.max_rooms);
+    let mut ready = Vec::new();
+    for seating in seated {
+        take_seat(&mut commands, &mut lobby, &mut server, &rooms, &shared, &seating);
+        if rooms.rooms[&seating.room].players().count() >= 2 && !ready.contains(&seating.room) {
+            ready.push(seating.room);
+        }
+    }
+
+    for room in ready {
+        //If the room was sitting on the end of the last match, that's over now too.
+        commands.entity(room).remove::<MatchOverTimer>();
+        start_match(&mut commands, &mut server, &mut rooms, room, &settings);
+    }
+
+    if rooms.queue_changed {
+        rooms.queue_changed = false;
+        for (i, &client_id) in rooms.queue.iter().enumerate() {
+            let message = bincode::serialize(&ServerMessages::QueuePosition { position: i + 1 }).unwrap();
+            server.send_message(client_id, 0, message);
+        }
+    }
+}
+
+/// Passes everything that happened in each room on to the people in it.
+fn forward_game_events(
+    mut commands: Commands,
+    mut room_events: EventReader<RoomEvent>,
+    mut server: ResMut<GameServer>,
+    rooms: Res<RoomManager>,
+) {
+    for RoomEvent { room, event } in room_events.iter() {
+        // The room might have emptied out since.
+        let slots = match rooms.rooms.get(room) {
+            Some(slots) => slots,
+            None => continue,
+        };
+        if let GameEvent::MatchOver { winner, .. } = event {
+            info!("{:?} won the match in room {:?}, seed {}.", winner, room, slots.seed);
+            commands.entity(*room).insert(MatchOverTimer(Timer::from_seconds(MATCH_RESTART_DELAY, false)));
+        }
+        let message = bincode::serialize(&ServerMessages::GameEvent { event: *event }).unwrap();
+        send_to_room(&mut server, slots, 0, message);
+    }
+}
+
+/// Starts a new match in every room whose last one finished a while ago, as long as both players are still there.
+fn restart_finished_matches(
+    mut commands: Commands,
+    mut finished: Query<(Entity, &mut MatchOverTimer, &mut RoomSim), With<Room>>,
+    mut server: ResMut<GameServer>,
+    mut rooms: ResMut<RoomManager>,
+    settings: Res<ServerSettings>,
+    time: Res<Time>,
+) {
+    for (room, mut timer, mut sim) in finished.iter_mut() {
+        if !timer.0.tick(time.delta()).just_finished() {
+            continue;
+        }
+        commands.entity(room).remove::<MatchOverTimer>();
+        sim.score = [0; 2];
+
+        // If someone left in the meantime, the matchmaker will start it again when the room fills up.
+        if rooms.rooms.get(&room).is_none_or(|slots| slots.players().count() < 2) {
+            continue;
+        }
+        start_match(&mut commands, &mut server, &mut rooms, room, &settings);
+    }
+}
+
+/// Gives up the paddles of players who didn't come back in time.
+fn expire_held_seats(
+    mut commands: Commands,
+    mut rooms: ResMut<RoomManager>,
+    mut server: ResMut<GameServer>,
+    mut room_states: Query<(&mut RoomSim, Option<&MatchOverTimer>), With<Room>>,
+    shared: Res<SharedTokenState>,
+    time: Res<Time>,
+) {
+    for (room, seat) in rooms.expire_held(time.seconds_since_startup()) {
+        info!("Player {} didn't come back in time, giving up their paddle.", seat.client);
+        shared.0.lock().unwrap().held.remove(&seat.resume_token);
+        player_gone(&mut commands, &mut server, &mut rooms, &shared, &mut room_states, room, seat.client, true);
+    }
+}
+
+/// Keeps the token service up to date with how many clients are connected, and disconnects the ones resumed from elsewhere.
+/// It also forgets sessions for tokens that ran out before anyone used them.
+fn update_token_state(mut server: ResMut<GameServer>, shared: Res<SharedTokenState>) {
+    let mut state = shared.0.lock().unwrap();
+    // Renet reports these as disconnected on the next update, before the clients resuming them can have connected.
+    for old_id in std::mem::take(&mut state.superseded) {
+        server.disconnect(old_id);
+    }
+    let clients = server.clients_id();
+    state.connected = clients.len();
+
+    let expire = Duration::from_secs(TOKEN_EXPIRE_SECONDS);
+    state.sessions.retain(|id, (_, issued)| clients.contains(id) || issued.elapsed() < expire);
+    let TokenServiceState { sessions, resuming, .. } = &mut *state;
+    resuming.retain(|id, _| sessions.contains_key(id));
+}
+
+/// Every time the rotation timer runs out, makes a new key and starts a game server for it on the other game port.
+/// Tokens are signed with the new key from then on. The previous key's server has to be gone first, see retire_previous_key,
+/// and every time the timer runs out while it's still up, it says why.
+fn rotate_keys(
+    time: Res<Time>,
+    mut timer: ResMut<KeyRotationTimer>,
+    mut game: ResMut<GameServer>,
+    shared: Res<SharedTokenState>,
+    settings: Res<ServerSettings>,
+    sockets: Res<GameSockets>,
+) {
+    if !timer.0.tick(time.delta()).just_finished() {
+        return;
+    }
+    // Keep the token service locked out until the new server is up, so it can't sign anything for a server that isn't there yet.
+    let mut state = shared.0.lock().unwrap();
+    if let Some(previous) = &game.previous {
+        let grace = Duration::from_secs(settings.key_grace_secs);
+        if let Some(reason) = state.keys.previous_needed(grace, previous.clients_id().len()) {
+            warn!("Key rotation is overdue, the server for the key before this one is still up because {}.", reason);
+            return;
+        }
+    }
+
+    let key = generate_key();
+    let port = 1 - state.keys.port;
+    let server = match new_renet_server(&settings, key, &sockets, port) {
+        Ok(server) => server,
+        Err(e) => {
+            error!("Could not start a game server on port {} for the new private key, keeping the old one: {}", settings.udp_ports()[port], e);
+            return;
+        }
+    };
+    state.keys.rotate(key);
+    game.previous = Some(std::mem::replace(&mut game.current, server));
+    let saved = state.keys.saved();
+    drop(state);
+
+    save_keys(&settings, &saved);
+    info!("Rotated to a new private key, on port {}.", settings.udp_ports()[port]);
+}
+
+/// Saves the keys to the key file, if there is one, so a restart comes back up with them.
+fn save_keys(settings: &ServerSettings, keys: &SavedKeys) {
+    if let Some(path) = &settings.key_file {
+        if let Err(e) = write_key_file(path, keys) {
+            error!("Failed to save the private keys to {}: {}", path.display(), e);
+        }
+    }
+}
+
+/// Shuts down the previous key's game server once nobody needs it any more, see KeyRing::previous_needed.
+fn retire_previous_key(mut game: ResMut<GameServer>, shared: Res<SharedTokenState>, settings: Res<ServerSettings>) {
+    let connected = match &game.previous {
+        Some(previous) => previous.clients_id().len(),
+        None => return,
+    };
+    let mut state = shared.0.lock().unwrap();
+    if state.keys.previous_needed(Duration::from_secs(settings.key_grace_secs), connected).is_none() {
+        state.keys.retire();
+        game.previous = None;
+        let saved = state.keys.saved();
+        drop(state);
+        save_keys(&settings, &saved);
+        info!("Stopped accepting the previous private key.");
+    }
+}
+
+/// So, I decided to put the code that actually gets the gamestate information in the common_game.rs file.
+/// It felt fitting to have the code that gets and sets gamestate in the same place.
+/// Every player only gets the state of their own room, along with which of their inputs it includes.
+#[allow(clippy::too_many_arguments)]
+fn server_sync_players(
+    mut server: ResMut<GameServer>, 
+    rooms: Res<RoomManager>,
+    lobby: Res<Lobby>,
+    input_buffers: Query<&InputBuffer>,
+    room_states: Query<(&Scoreboard, &Playing), With<Room>>,
+    balls: Query<(&Transform, &Velocity, &InRoom), With<Ball>>, 
+    paddles: Query<(&Transform, &PaddleSide, &InRoom), With<Paddle>>, 
+    tick: Res<SimulationTick>,
+    mut encoders: ResMut<DeltaEncoders>,
+    mut bandwidth: ResMut<SyncBandwidth>,
+    settings: Res<ServerSettings>,
+    time:Res<Time>, 
+    mut timer: ResMut<SendTimer>,) {
+    // In rollback mode the players simulate their matches themselves, so there's nothing to send them.
+    if settings.netcode == NetcodeMode::Rollback {
+        return;
+    }
+    if timer.0.tick(time.delta()).just_finished() {
+        //Just get each room's gamestate, stamp it with the time, tick and the reciever's last input, encode it, send it.
+        //See common_wire.rs for how the encoding works.
+        let mut gamestates = get_room_gamestates(&room_states, &balls, &paddles);
+        for gamestate in gamestates.values_mut() {
+            gamestate.server_time = time.seconds_since_startup();
+            gamestate.tick = tick.0;
+        }
+        for (&client_id, room) in rooms.clients.iter() {
+            if let Some(gamestate) = gamestates.get_mut(room) {
+                gamestate.last_input = lobby.players.get(&client_id)
+                    .and_then(|&player_entity| input_buffers.get(player_entity).ok())
+                    .map_or(0, |buffer| buffer.last_applied);
+                let message = encoders.0.entry(client_id).or_default().encode(gamestate);
+                bandwidth.full_bytes += bincode::serialized_size(gamestate).unwrap();
+                bandwidth.sent_bytes += message.len() as u64;
+                server.send_message(client_id, 1, message);
+            }
+        }
+    }
+
+    if bandwidth.timer.tick(time.delta()).just_finished() && bandwidth.full_bytes > 0 {
+        info!(
+            "GameState sync: {} bytes/s sent, would have been {} bytes/s as plain bincode.",
+            bandwidth.sent_bytes / BANDWIDTH_LOG_SECONDS as u64,
+            bandwidth.full_bytes / BANDWIDTH_LOG_SECONDS as u64,
+        );
+        bandwidth.full_bytes = 0;
+        bandwidth.sent_bytes = 0;
+    }
+}
+
+/// Pings every client, and disconnects anyone who has gone quiet for longer than idle_timeout_secs.
+fn heartbeat_system(
+    mut server: ResMut<GameServer>,
+    mut links: ResMut<ClientLinks>,
+    mut timer: ResMut<HeartbeatTimer>,
+    settings: Res<ServerSettings>,
+    time: Res<Time>,
+) {
+    if !timer.0.tick(time.delta()).just_finished() {
+        return;
+    }
+    let now = time.seconds_since_startup();
+    for client_id in server.clients_id() {
+        let link = match links.0.get_mut(&client_id) {
+            Some(link) => link,
+            None => continue,
+        };
+        if link.idle_for(now) > settings.idle_timeout_secs as f64 {
+            info!(
+                "Player {} timed out after {:.1}s without a word (rtt {:.0}ms, {:.0}% loss), disconnecting them.",
+                client_id,
+                link.idle_for(now),
+                link.rtt().unwrap_or(0.0) * 1000.0,
+                link.loss(now) * 100.0,
+            );
+            server.disconnect(client_id);
+            continue;
+        }
+        let message = bincode::serialize(&link.ping(now)).unwrap();
+        server.send_message(client_id, HEARTBEAT_CHANNEL, message);
+    }
+}
+
+/// Works out how late each player sees the ball, see LagCompensation.
+/// Their GameStates are about half a round trip old when they get them, and they draw them a bit later still. All of it up to max_rewind_ms.
+fn update_lag_compensation(
+    lobby: Res<Lobby>,
+    links: Res<ClientLinks>,
+    settings: Res<ServerSettings>,
+    mut lag: ResMut<LagCompensation>,
+) {
+    lag.0.clear();
+    let max_rewind = settings.max_rewind_ms as f64 / 1000.0;
+    for (client_id, &paddle) in lobby.players.iter() {
+        let (rtt, view_delay) = match links.0.get(client_id).and_then(|link| Some((link.rtt()?, link.view_delay()))) {
+            Some(link) => link,
+            None => continue,
+        };
+        let rewind = (rtt / 2.0 + view_delay).min(max_rewind);
+        lag.0.insert(paddle, (rewind / TIME_STEP as f64).round() as u64);
+    }
+}
+
+/// Usually these errors are some result of a client forcequitting, and the heartbeat will catch anyone who's really gone.
+/// So there's nothing to do except make a note of it.
+fn log_error_system(mut renet_error: EventReader<RenetError>) {
+    for e in renet_error.iter() {
+        error!("Network error: {:?}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_are_unique_ignoring_case() {
+        let mut state = TokenServiceState::default();
+        assert!(state.reserve_username(1, 10, "Paddle"));
+        assert!(!state.reserve_username(2, 20, "paddle"));
+        assert!(!state.reserve_username(2, 20, "PADDLE"));
+        assert!(state.reserve_username(2, 20, "Paddles"));
+
+        assert!(state.player_joined(1, "Paddle"));
+        assert!(!state.reserve_username(3, 30, "pAdDlE"));
+        assert!(!state.player_joined(3, "paddle"));
+        // Until they leave.
+        state.player_left(1);
+        assert!(state.reserve_username(3, 30, "paddle"));
+    }
+
+    #[test]
+    fn reservations_keep_the_name_for_the_token_holder() {
+        let mut state = TokenServiceState::default();
+        assert!(state.reserve_username(1, 10, "paddle"));
+        assert!(!state.player_joined(2, "paddle"));
+        assert!(state.player_joined(1, "paddle"));
+        // Using the token used up the reservation.
+        assert!(state.reserved.is_empty());
+    }
+
+    #[test]
+    fn asking_again_takes_over_our_own_reservation() {
+        let mut state = TokenServiceState::default();
+        assert!(state.reserve_username(1, 10, "paddle"));
+        // The first token didn't get us connected, so we ask again with a new client id.
+        assert!(state.reserve_username(2, 10, "paddle"));
+        assert!(!state.reserve_username(3, 30, "paddle"));
+        // Only the newest token gets the name.
+        assert!(!state.player_joined(1, "paddle"));
+        assert!(state.player_joined(2, "paddle"));
+    }
+
+    #[test]
+    fn reservations_run_out() {
+        let mut state = TokenServiceState::default();
+        assert!(state.reserve_username(1, 10, "paddle"));
+        state.reserved.get_mut("paddle").unwrap().until = Instant::now() - Duration::from_secs(1);
+        // Nobody's stopped from joining with a name whose reservation ran out.
+        assert!(state.player_joined(2, "paddle"));
+        state.player_left(2);
+
+        assert!(state.reserve_username(3, 30, "paddle"));
+        state.reserved.get_mut("paddle").unwrap().until = Instant::now() - Duration::from_secs(1);
+        assert!(state.reserve_username(4, 40, "paddle"));
+        assert_eq!(state.reserved["paddle"].instance, 40);
+    }
+
+    #[test]
+    fn held_seats_keep_the_name_for_whoever_resumes_them() {
+        let mut state = TokenServiceState::default();
+        let resume_token = ResumeToken([7; 16]);
+        state.held.insert(resume_token, ("Paddle".to_string(), Instant::now() + Duration::from_secs(30)));
+        assert!(!state.reserve_username(1, 10, "paddle"));
+        assert!(!state.player_joined(1, "paddle"));
+
+        state.resuming.insert(2, ResumeToken([8; 16]));
+        assert!(!state.player_joined(2, "Paddle"));
+        state.resuming.insert(3, resume_token);
+        assert!(state.player_joined(3, "Paddle"));
+    }
+
+    /// A path in the temp directory nobody else is using, for a key file.
+    fn tmp_key_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("pong-{}-{}.key", name, std::process::id()))
+    }
+
+    #[test]
+    fn key_files_round_trip() {
+        let path = tmp_key_path("round-trip");
+        let keys = SavedKeys::new(generate_key());
+        write_key_file(&path, &keys).unwrap();
+        // Nothing's left behind from going through the temporary file.
+        assert!(!path.with_extension("tmp").exists());
+        assert_eq!(read_key_file(&path).unwrap(), keys);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        }
+        // Writing over it, like a rotation does, leaves just the new keys.
+        let keys = SavedKeys { active: generate_key(), port: 1, previous: Some(keys.active) };
+        write_key_file(&path, &keys).unwrap();
+        assert_eq!(read_key_file(&path).unwrap(), keys);
+        let keys = SavedKeys { previous: None, ..keys };
+        write_key_file(&path, &keys).unwrap();
+        assert_eq!(read_key_file(&path).unwrap(), keys);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn key_files_must_be_64_hex_characters() {
+        let path = tmp_key_path("bad");
+        // Whitespace around it is fine, people edit these by hand.
+        fs::write(&path, format!("  {}\n\n", "ab".repeat(32))).unwrap();
+        assert_eq!(read_key_file(&path).unwrap(), SavedKeys::new([0xab; 32]));
+        fs::write(&path, format!("{}\n port=1 \nprevious = {}\n", "ab".repeat(32), "cd".repeat(32))).unwrap();
+        assert_eq!(read_key_file(&path).unwrap(), SavedKeys { active: [0xab; 32], port: 1, previous: Some([0xcd; 32]) });
+        for contents in ["ab".repeat(31), "ab".repeat(33), "zz".repeat(32), "é".repeat(32), String::new()] {
+            fs::write(&path, &contents).unwrap();
+            let error = read_key_file(&path).unwrap_err();
+            assert_eq!(error.kind(), std::io::ErrorKind::InvalidData, "{:?} was read", contents);
+        }
+        for extra in ["port = 2", "previous = abab", "rotated = yes"] {
+            fs::write(&path, format!("{}\n{}\n", "ab".repeat(32), extra)).unwrap();
+            assert_eq!(read_key_file(&path).unwrap_err().kind(), std::io::ErrorKind::InvalidData, "{:?} was read", extra);
+        }
+        fs::remove_file(&path).unwrap();
+        assert_eq!(read_key_file(&path).unwrap_err().kind(), std::io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn rotating_moves_the_key_to_the_other_port() {
+        let mut keys = KeyRing::new(SavedKeys::new([1; 32]));
+        let signed = Instant::now();
+        keys.last_signed = Some(signed);
+        assert_eq!(keys.rotate([2; 32]), 1);
+        assert_eq!(keys.saved(), SavedKeys { active: [2; 32], port: 1, previous: Some([1; 32]) });
+        // The tokens signed so far were signed with what's now the previous key.
+        assert_eq!((keys.last_signed, keys.previous_signed), (None, Some(signed)));
+        keys.retire();
+        assert_eq!(keys.rotate([3; 32]), 0);
+        assert_eq!(keys.previous_signed, None);
+        assert_eq!(keys.saved(), SavedKeys { active: [3; 32], port: 0, previous: Some([2; 32]) });
+    }
+
+    #[test]
+    fn restarting_keeps_the_previous_key_for_the_grace_period() {
+        let grace = Duration::from_secs(60);
+        let keys = KeyRing::new(SavedKeys { active: [2; 32], port: 1, previous: Some([1; 32]) });
+        assert_eq!((keys.active, keys.port), ([2; 32], 1));
+        assert!(keys.previous_needed(grace, 0).is_some());
+        assert!(KeyRing::new(SavedKeys::new([1; 32])).previous_needed(grace, 0).is_none());
+    }
+
+    #[test]
+    fn the_previous_key_is_needed_for_the_grace_period_and_while_anyone_uses_it() {
+        let grace = Duration::from_secs(60);
+        let mut keys = KeyRing::new(SavedKeys::new([1; 32]));
+        // Nothing was ever signed with it and nobody's on it.
+        keys.rotate([2; 32]);
+        assert_eq!(keys.previous_needed(grace, 0), None);
+
+        keys.previous_signed = Some(Instant::now() - Duration::from_secs(10));
+        assert!(keys.previous_needed(grace, 0).unwrap().contains("a token was signed with it"));
+        assert!(keys.previous_needed(grace, 2).unwrap().contains("2 clients are connected to it and a token"));
+        keys.previous_signed = Some(Instant::now() - Duration::from_secs(61));
+        assert_eq!(keys.previous_needed(grace, 2).as_deref(), Some("2 clients are connected to it"));
+        assert_eq!(keys.previous_needed(grace, 0), None);
+        // Tokens signed with the new key don't keep the previous one up.
+        keys.last_signed = Some(Instant::now());
+        assert_eq!(keys.previous_needed(grace, 0), None);
+    }
+}
@@ -0,0 +1,393 @@
+//! Runs the real server and a few headless clients in one process, so the netcode can be tested without a real network.
+//! The server is the one from src/server.rs, with the token service running on a thread like it does for real.
+//! Clients get their connect tokens from it over TCP, and everything else goes over UDP on loopback.
+//!
+//! Nothing runs on its own. Each `step` moves every app's clock on by exactly one frame and updates them once,
+//! so a test plays out the same however fast the machine running it is.
+//! Clients don't draw anything or simulate anything, they just answer pings, acknowledge GameStates,
+//! send whatever their script says to press and keep everything the server tells them in their Inbox.
+
+// Every test file gets its own copy of this, and not all of them use all of it.
+#![allow(dead_code)]
+
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener, TcpStream, UdpSocket},
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+
+use bevy::{core::CorePlugin, prelude::*};
+use bevy_renet::{
+    renet::{ClientAuthentication, ConnectToken, RenetClient},
+    RenetClientPlugin,
+};
+
+use pong_multiplayer_rs::{
+    common_config::ServerSettings,
+    common_game::{rules_hash, GameEvent, PlayerSide, Playing, Room, RoomSim, Scoreboard},
+    common_net::*,
+    common_sim::SimRng,
+    common_wire::DeltaDecoder,
+    server::{add_server_to_app, generate_key, tcpserver, GameSockets, SavedKeys, SharedTokenState},
+};
+
+/// How far the clock moves on every step. Clients send one input a frame, like the real one does.
+pub const FRAME_SECONDS: f32 = POLL_RATE;
+
+/// What a client presses, worked out from what it's been told so far.
+pub type Script = Box<dyn FnMut(&Inbox) -> PlayerInput + Send + Sync>;
+
+/// Everything a client has heard from the server.
+#[derive(Debug, Default)]
+pub struct Inbox {
+    /// Every message from the reliable channel, oldest first.
+    pub messages: Vec<ServerMessages>,
+    /// Every RollbackMessage, oldest first.
+    pub rollback: Vec<RollbackMessage>,
+    /// The newest GameState, and how many have been decoded altogether.
+    pub gamestate: Option<GameState>,
+    pub gamestates: usize,
+    /// Which paddle we've got, if any.
+    pub side: Option<PlayerSide>,
+    /// Where in the queue we are, if we're in it.
+    pub queue_position: Option<usize>,
+}
+
+impl Inbox {
+    /// Every GameEvent we've been sent.
+    pub fn events(&self) -> Vec<GameEvent> {
+        self.messages.iter().filter_map(|message| match message {
+            ServerMessages::GameEvent { event } => Some(*event),
+            _ => None,
+        }).collect()
+    }
+
+    /// How many goals we've been told about for each side, left first.
+    pub fn goals(&self) -> [usize; 2] {
+        let mut goals = [0; 2];
+        for event in self.events() {
+            if let GameEvent::GoalScored { side, .. } = event {
+                goals[side as usize] += 1;
+            }
+        }
+        goals
+    }
+
+    /// Every rollback match we've been told to start, as its round, seed and our side.
+    pub fn rollback_starts(&self) -> Vec<(u32, u64, PlayerSide)> {
+        self.rollback.iter().filter_map(|message| match *message {
+            RollbackMessage::Start { round, seed, side } => Some((round, seed, side)),
+            _ => None,
+        }).collect()
+    }
+
+    /// Every input the opponent sent us in rollback matches, as its round, frame and what was pressed.
+    pub fn rollback_inputs(&self) -> Vec<(u32, u32, PlayerInput)> {
+        self.rollback.iter().filter_map(|message| match *message {
+            RollbackMessage::Input { round, frame, input } => Some((round, frame, input)),
+            _ => None,
+        }).collect()
+    }
+
+    /// Whether we've been sent a message that `matches` says yes to.
+    pub fn got(&self, matches: impl Fn(&ServerMessages) -> bool) -> bool {
+        self.messages.iter().any(matches)
+    }
+}
+
+/// The resume token the token service gave a client along with its last connect token.
+struct Session(ResumeToken);
+
+/// What the token service hands a new client: the id it was asked for, its connect token and its resume token.
+pub struct Granted {
+    pub id: u64,
+    pub token: Vec<u8>,
+    pub resume_token: ResumeToken,
+}
+
+/// A client's script, and how many inputs it has sent for its current paddle.
+struct Scripted {
+    script: Script,
+    sequence: u32,
+}
+
+/// One room on the server, as the server sees it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RoomView {
+    pub score: [usize; 2],
+    pub playing: bool,
+}
+
+pub struct Harness {
+    pub server: App,
+    pub clients: Vec<App>,
+    /// Where every app's clock is at.
+    now: Instant,
+    token_service: SocketAddr,
+    next_client_id: u64,
+}
+
+impl Harness {
+    /// Starts a server with `settings`, on loopback ports of its own. The addresses and ports in `settings` are ignored.
+    pub fn new(settings: ServerSettings) -> Self {
+        Self::with_keys(settings, SavedKeys::new(generate_key()))
+    }
+
+    /// Starts a server like `new`, picking up `keys` like it would from a key file.
+    pub fn with_keys(mut settings: ServerSettings, keys: SavedKeys) -> Self {
+        let loopback = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        // Let the OS find free ports for the game, and hand their sockets over to the server. With key rotation on it uses the one after udp_port too.
+        let (game_port, game_sockets) = game_socket_pair(loopback);
+        let listener = TcpListener::bind((loopback, 0)).unwrap();
+        let token_service = listener.local_addr().unwrap();
+        settings.bind_ip = loopback;
+        settings.public_ip = loopback;
+        settings.udp_port = game_port;
+        settings.tcp_port = token_service.port();
+
+        let shared = SharedTokenState::new(keys);
+        let (threadsettings, threadshared) = (settings.clone(), shared.clone());
+        // The thread's left waiting on the listener when the test finishes, which is fine since nothing ever connects to it again.
+        thread::spawn(move || tcpserver(listener, threadsettings, threadshared));
+
+        // The same plugins the real server has, except for the clock, which is ours, and logging, which can only be set up once per process.
+        let mut server = App::new();
+        server.add_plugin(CorePlugin)
+            .add_plugin(TransformPlugin)
+            .add_plugin(HierarchyPlugin)
+            .insert_resource(Time::default());
+        let server = add_server_to_app(server, settings, shared, GameSockets(game_sockets.map(Some))).unwrap();
+
+        let mut harness = Harness { server, clients: Vec::new(), now: Instant::now(), token_service, next_client_id: 1 };
+        // Startup systems run on the first update, let them get it out of the way.
+        harness.step();
+        harness
+    }
+
+    /// Gets a connect token for `username` and connects a new client with it. Gives back the client's index in `clients`.
+    pub fn add_client(&mut self, username: &str, script: impl FnMut(&Inbox) -> PlayerInput + Send + Sync + 'static) -> usize {
+        let granted = self.grant(username);
+        self.connect(granted, script)
+    }
+
+    /// Gets a connect token for `username`, without connecting with it yet.
+    pub fn grant(&mut self, username: &str) -> Granted {
+        let id = self.next_id();
+        let message = ClientMessagesTcp::AuthenticationRequest {
+            id,
+            username: username.to_string(),
+            instance: id,
+            version: GAME_VERSION.to_string(),
+            rules_hash: rules_hash(),
+        };
+        match self.request_token(&message) {
+            ServerMessagesTcp::TokenGranted { token, resume_token } => Granted { id, token, resume_token },
+            other => panic!("{} didn't get a connect token: {:?}", username, other),
+        }
+    }
+
+    /// Connects a new client with a token from `grant`. Gives back the client's index in `clients`.
+    pub fn connect(&mut self, granted: Granted, script: impl FnMut(&Inbox) -> PlayerInput + Send + Sync + 'static) -> usize {
+        let Granted { id, token, resume_token } = granted;
+        let mut app = App::new();
+        app.add_plugin(CorePlugin)
+            .insert_resource(Time::default())
+            .add_plugin(RenetClientPlugin)
+            .insert_resource(new_client(id, &token))
+            .insert_resource(Session(resume_token))
+            .insert_resource(Inbox::default())
+            .insert_resource(DeltaDecoder::default())
+            .insert_resource(Scripted { script: Box::new(script), sequence: 0 })
+            .add_system(client_system);
+        self.clients.push(app);
+        self.clients.len() - 1
+    }
+
+    /// Asks the token service for the paddle a client dropped, with the resume token it was given, and connects it again under a new id.
+    /// Like the real client this doesn't care whether the server has noticed the old connection is gone.
+    /// The old connection is dropped without a word, the way a connection that's been lost would be.
+    /// Gives back the token service's answer if it didn't grant a token, and leaves the client as it was.
+    pub fn resume(&mut self, client: usize) -> Result<(), ServerMessagesTcp> {
+        let id = self.next_id();
+        let message = ClientMessagesTcp::ResumeRequest {
+            id,
+            resume_token: self.clients[client].world.resource::<Session>().0,
+            version: GAME_VERSION.to_string(),
+            rules_hash: rules_hash(),
+        };
+        let (token, resume_token) = match self.request_token(&message) {
+            ServerMessagesTcp::TokenGranted { token, resume_token } => (token, resume_token),
+            other => return Err(other),
+        };
+        let world = &mut self.clients[client].world;
+        world.insert_resource(new_client(id, &token));
+        world.insert_resource(Session(resume_token));
+        world.insert_resource(DeltaDecoder::default());
+        // The server tells us our side again once we're back.
+        world.resource_mut::<Inbox>().side = None;
+        Ok(())
+    }
+
+    fn next_id(&mut self) -> u64 {
+        self.next_client_id += 1;
+        self.next_client_id - 1
+    }
+
+    /// Sends a request to the token service and reads back its answer, the same way the client does.
+    fn request_token(&self, message: &ClientMessagesTcp) -> ServerMessagesTcp {
+        let mut stream = TcpStream::connect_timeout(&self.token_service, TCP_TIMEOUT).unwrap();
+        stream.set_read_timeout(Some(TCP_TIMEOUT)).unwrap();
+        write_tcp_message(&mut stream, message).unwrap();
+        read_tcp_message(&mut stream).unwrap()
+    }
+
+    /// Moves the clock on by one frame, and updates the server and then every client.
+    pub fn step(&mut self) {
+        self.now += Duration::from_secs_f32(FRAME_SECONDS);
+        let now = self.now;
+        for app in std::iter::once(&mut self.server).chain(self.clients.iter_mut()) {
+            app.world.resource_mut::<Time>().update_with_instant(now);
+            app.update();
+        }
+    }
+
+    /// Steps for `seconds` of game time.
+    pub fn run_for(&mut self, seconds: f32) {
+        for _ in 0..(seconds / FRAME_SECONDS).round() as usize {
+            self.step();
+        }
+    }
+
+    /// Steps until `done` says so, for at most `seconds` of game time. Gives back whether it ever did.
+    pub fn run_until(&mut self, seconds: f32, mut done: impl FnMut(&mut Harness) -> bool) -> bool {
+        for _ in 0..(seconds / FRAME_SECONDS).round() as usize {
+            if done(self) {
+                return true;
+            }
+            self.step();
+        }
+        done(self)
+    }
+
+    pub fn inbox(&self, client: usize) -> &Inbox {
+        self.clients[client].world.resource::<Inbox>()
+    }
+
+    /// Whether a client has finished connecting to the server.
+    pub fn connected(&self, client: usize) -> bool {
+        self.clients[client].world.resource::<RenetClient>().is_connected()
+    }
+
+    /// Sends a RollbackMessage from a client, the way the real one's RollbackSession would. Harness clients don't simulate rollback matches,
+    /// so tests play the players' part themselves.
+    pub fn send_rollback(&mut self, client: usize, message: &RollbackMessage) {
+        let message = bincode::serialize(message).unwrap();
+        self.clients[client].world.resource_mut::<RenetClient>().send_message(ROLLBACK_CHANNEL, message);
+    }
+
+    /// Hangs up a client, the way the real one does when its window is closed. It stays in `clients` so its inbox can still be read.
+    pub fn disconnect(&mut self, client: usize) {
+        self.clients[client].world.resource_mut::<RenetClient>().disconnect();
+    }
+
+    /// Every room on the server, oldest first.
+    pub fn rooms(&mut self) -> Vec<RoomView> {
+        let mut query = self.server.world.query_filtered::<(Entity, &Scoreboard, &Playing), With<Room>>();
+        let mut rooms: Vec<_> = query.iter(&self.server.world)
+            .map(|(room, scoreboard, playing)| (room, RoomView { score: [scoreboard.scoreleft, scoreboard.scoreright], playing: playing.0 }))
+            .collect();
+        rooms.sort_by_key(|(room, _)| *room);
+        rooms.into_iter().map(|(_, view)| view).collect()
+    }
+
+    /// Where the serves in each room are at, in the same order as `rooms`.
+    pub fn room_rngs(&mut self) -> Vec<SimRng> {
+        let mut query = self.server.world.query_filtered::<(Entity, &RoomSim), With<Room>>();
+        let mut rooms: Vec<_> = query.iter(&self.server.world).map(|(room, sim)| (room, sim.rng)).collect();
+        rooms.sort_by_key(|(room, _)| *room);
+        rooms.into_iter().map(|(_, rng)| rng).collect()
+    }
+}
+
+/// Sockets on two free UDP ports on `ip`, one after the other, and the first port.
+fn game_socket_pair(ip: IpAddr) -> (u16, [UdpSocket; 2]) {
+    loop {
+        let first = UdpSocket::bind((ip, 0)).unwrap();
+        let port = first.local_addr().unwrap().port();
+        if port < u16::MAX {
+            if let Ok(second) = UdpSocket::bind((ip, port + 1)) {
+                return (port, [first, second]);
+            }
+        }
+    }
+}
+
+/// A client connecting with `token`, as written by the token service.
+fn new_client(id: u64, token: &[u8]) -> RenetClient {
+    let token = ConnectToken::read(&mut &token[..]).unwrap();
+    let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    let current_time = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap();
+    let authentication = ClientAuthentication::Secure { connect_token: token };
+    RenetClient::new(current_time, socket, id, connection_config(), authentication).unwrap()
+}
+
+/// Everything a harness client does each frame. The parts of the real client that matter to the server, without the rest.
+fn client_system(
+    mut client: ResMut<RenetClient>,
+    mut inbox: ResMut<Inbox>,
+    mut decoder: ResMut<DeltaDecoder>,
+    mut scripted: ResMut<Scripted>,
+) {
+    if !client.is_connected() {
+        return;
+    }
+    while let Some(message) = client.receive_message(0) {
+        let server_message: ServerMessages = bincode::deserialize(&message).unwrap();
+        match server_message {
+            // It's a new paddle, so the server starts counting our inputs from scratch.
+            ServerMessages::MatchFound { .. } => {
+                inbox.side = None;
+                inbox.queue_position = None;
+                scripted.sequence = 0;
+            }
+            ServerMessages::PlayerIsSide { side } => inbox.side = Some(side),
+            ServerMessages::QueuePosition { position } => inbox.queue_position = Some(position),
+            _ => (),
+        }
+        inbox.messages.push(server_message);
+    }
+    while let Some(message) = client.receive_message(ROLLBACK_CHANNEL) {
+        inbox.rollback.push(bincode::deserialize(&message).unwrap());
+    }
+    let mut decoded = false;
+    while let Some(message) = client.receive_message(1) {
+        // A delta against something we never got is dropped, same as the real client does.
+        if let Ok((_, gamestate)) = decoder.decode(&message) {
+            inbox.gamestate = Some(gamestate);
+            inbox.gamestates += 1;
+            decoded = true;
+        }
+    }
+    if decoded {
+        if let Some(sequence) = decoder.newest() {
+            client.send_message(1, bincode::serialize(&SnapshotAck { sequence }).unwrap());
+        }
+    }
+    while let Some(message) = client.receive_message(HEARTBEAT_CHANNEL) {
+        // We look at GameStates as soon as they get here.
+        if let ServerMessages::Ping { sequence, sent_at } = bincode::deserialize(&message).unwrap() {
+            let pong = ClientMessages::Pong { sequence, sent_at, view_delay_ms: 0 };
+            client.send_message(HEARTBEAT_CHANNEL, bincode::serialize(&pong).unwrap());
+        }
+    }
+
+    // Spectators have nothing to steer.
+    if inbox.side.is_none() {
+        return;
+    }
+    let scripted = &mut *scripted;
+    let input = (scripted.script)(&inbox);
+    scripted.sequence += 1;
+    let input_message = InputMessage { sequence: scripted.sequence, input };
+    client.send_message(0, bincode::serialize(&input_message).unwrap());
+}
@@ -0,0 +1,113 @@
+//! Private key files, and rotating the key on a running server. See KeyRing and GameServer in server.rs.
+
+mod harness;
+
+use std::{fs, process::Command, time::Duration};
+
+use harness::{Harness, Inbox};
+use pong_multiplayer_rs::{
+    common_config::ServerSettings,
+    common_net::PlayerInput,
+    server::{generate_key, read_key_file, GameServer, SavedKeys},
+};
+
+fn idle(_: &Inbox) -> PlayerInput {
+    PlayerInput::default()
+}
+
+/// The port new tokens point at.
+fn current_port(harness: &Harness) -> u16 {
+    harness.server.world.resource::<GameServer>().addr().port()
+}
+
+fn accepts_previous_key(harness: &Harness) -> bool {
+    harness.server.world.resource::<GameServer>().accepts_previous_key()
+}
+
+#[test]
+fn generate_key_writes_a_key_and_never_overwrites_one() {
+    let path = std::env::temp_dir().join(format!("pong-generate-{}.key", std::process::id()));
+    let _ = fs::remove_file(&path);
+    let generate = || Command::new(env!("CARGO_BIN_EXE_server")).arg("--generate-key").arg(&path).output().unwrap();
+
+    let output = generate();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let key = read_key_file(&path).unwrap();
+    assert_eq!((key.port, key.previous), (0, None));
+
+    let output = generate();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("refusing to overwrite"));
+    assert_eq!(read_key_file(&path).unwrap(), key);
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn tokens_signed_before_a_rotation_still_connect_after_it() {
+    let settings = ServerSettings { key_rotation_secs: 1, key_grace_secs: 120, seed: Some(0x5eed), ..Default::default() };
+    let mut harness = Harness::new(settings);
+    let first_port = current_port(&harness);
+    let alice = harness.grant("Alice");
+
+    let rotated = harness.run_until(2.0, |h| current_port(h) != first_port);
+    assert!(rotated, "the key was never rotated");
+    assert_eq!(current_port(&harness), first_port + 1);
+    assert!(accepts_previous_key(&harness));
+
+    // Alice's token is for the previous key's server, Bob's is for the new one, and they still end up playing each other.
+    let alice = harness.connect(alice, idle);
+    let bob = harness.add_client("Bob", idle);
+    let seated = harness.run_until(5.0, |h| h.inbox(alice).side.is_some() && h.inbox(bob).side.is_some());
+    assert!(seated, "the players never got their paddles");
+    let playing = harness.run_until(2.0, |h| h.inbox(alice).gamestates > 30 && h.inbox(bob).gamestates > 30);
+    assert!(playing, "the match never reached both players");
+    // Tokens are still within their grace period, so no more rotations yet.
+    assert_eq!(current_port(&harness), first_port + 1);
+}
+
+#[test]
+fn the_next_rotation_waits_for_the_previous_key_to_be_retired() {
+    let settings = ServerSettings { key_rotation_secs: 5, key_grace_secs: 0, ..Default::default() };
+    let mut harness = Harness::new(settings);
+    let first_port = current_port(&harness);
+    let alice = harness.add_client("Alice", idle);
+    assert!(harness.run_until(2.0, |h| h.connected(alice)), "alice never connected");
+
+    let rotated = harness.run_until(5.0, |h| current_port(h) != first_port);
+    assert!(rotated, "the key was never rotated");
+    // Alice is still on the previous key's server, so it stays up, and the rotation due 5 seconds later waits.
+    harness.run_for(6.0);
+    assert!(accepts_previous_key(&harness));
+    assert_eq!(current_port(&harness), first_port + 1);
+
+    harness.disconnect(alice);
+    let retired = harness.run_until(2.0, |h| !accepts_previous_key(h));
+    assert!(retired, "the previous key was never retired");
+    // The next rotation comes when the timer runs out again, back on the first port.
+    assert_eq!(current_port(&harness), first_port + 1);
+    let rotated = harness.run_until(5.0, |h| current_port(h) == first_port);
+    assert!(rotated, "the key was never rotated again");
+}
+
+#[test]
+fn a_restart_mid_rotation_brings_both_keys_back_on_their_ports() {
+    let path = std::env::temp_dir().join(format!("pong-restart-{}.key", std::process::id()));
+    let settings = ServerSettings { key_rotation_secs: 3600, key_grace_secs: 1, key_file: Some(path.clone()), ..Default::default() };
+    let keys = SavedKeys { active: generate_key(), port: 1, previous: Some(generate_key()) };
+    let mut harness = Harness::with_keys(settings, keys);
+    let udp_port = harness.server.world.resource::<ServerSettings>().udp_port;
+    assert_eq!(current_port(&harness), udp_port + 1);
+    assert!(accepts_previous_key(&harness));
+
+    // New tokens still point at the second port, and work there.
+    let alice = harness.add_client("Alice", idle);
+    assert!(harness.run_until(2.0, |h| h.connected(alice)), "alice never connected");
+
+    // Once the grace period is over the previous key is retired, and the key file stops mentioning it.
+    // The grace period goes by the real clock, not the harness's.
+    std::thread::sleep(Duration::from_millis(1100));
+    let retired = harness.run_until(3.0, |h| !accepts_previous_key(h));
+    assert!(retired, "the previous key was never retired");
+    assert_eq!(read_key_file(&path).unwrap(), SavedKeys { previous: None, ..keys });
+    fs::remove_file(&path).unwrap();
+}
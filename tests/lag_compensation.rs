@@ -0,0 +1,74 @@
+//! Checks PongSim judges the ball the way a player who sees it late saw it, see step_compensated.
+
+use bevy::math::Vec2;
+use pong_multiplayer_rs::{
+    common_game::{GameEvent, PlayerSide},
+    common_net::PlayerInput,
+    common_sim::{float, real, PongSim, SimVec},
+};
+
+fn at(x: f32, y: f32) -> SimVec {
+    SimVec::from_vec2(Vec2::new(x, y))
+}
+
+/// A match in play with the ball at `ball` heading left at `speed`, and the left paddle at `paddle_y`.
+fn heading_left(ball: SimVec, speed: f32, paddle_y: f32) -> PongSim {
+    let mut sim = PongSim::new(0);
+    sim.restart();
+    sim.serve_in = 0;
+    sim.ball = ball;
+    sim.ball_velocity = at(-speed, 0.0);
+    sim.paddles[0] = real(paddle_y);
+    sim
+}
+
+/// Steps with the left player seeing the ball go from `from` to `to`.
+fn step_seen(sim: &mut PongSim, from: SimVec, to: SimVec) -> Vec<GameEvent> {
+    sim.step_compensated([PlayerInput::default(); 2], [Some((from, to)), None])
+}
+
+#[test]
+fn a_ball_the_player_saw_hit_bounces_after_all() {
+    // The left paddle's front is at -365 once the ball is counted in. The ball's already past it, but its player only just saw it get there.
+    let mut sim = heading_left(at(-380.0, 0.0), 400.0, 0.0);
+    let events = step_seen(&mut sim, at(-362.0, 0.0), at(-368.0, 0.0));
+    assert!(events.iter().any(|event| matches!(event, GameEvent::PaddleHit { side: PlayerSide::Left, .. })), "{:?}", events);
+    assert!(float(sim.ball_velocity.x) > 0.0);
+    assert!(float(sim.ball.x) > -365.0);
+}
+
+#[test]
+fn a_goal_waits_until_the_player_has_seen_the_ball_get_past() {
+    // Paddle well out of the way, ball about to reach the goal.
+    let mut sim = heading_left(at(-428.0, 0.0), 400.0, 200.0);
+    let seen_far_off = (at(-300.0, 0.0), at(-303.0, 0.0));
+    assert!(step_seen(&mut sim, seen_far_off.0, seen_far_off.1).is_empty());
+    assert_eq!(sim.goal_waiting, Some(PlayerSide::Left));
+    let waiting_at = sim.ball;
+    assert!(step_seen(&mut sim, seen_far_off.0, seen_far_off.1).is_empty());
+    assert_eq!(sim.ball, waiting_at);
+    assert_eq!(sim.score, [0, 0]);
+
+    // Now they've seen it behind their paddle.
+    let events = step_seen(&mut sim, at(-413.0, 0.0), at(-417.0, 0.0));
+    assert!(events.iter().any(|event| matches!(event, GameEvent::GoalScored { side: PlayerSide::Right, .. })), "{:?}", events);
+    assert_eq!(sim.score, [0, 1]);
+    assert_eq!(sim.goal_waiting, None);
+}
+
+#[test]
+fn where_the_paddle_used_to_be_doesnt_count() {
+    // The player saw the ball go nowhere near where their paddle is now, so it's a goal.
+    let mut sim = heading_left(at(-428.0, 0.0), 400.0, 200.0);
+    step_seen(&mut sim, at(-405.0, 0.0), at(-410.0, 0.0));
+    let events = step_seen(&mut sim, at(-410.0, 0.0), at(-420.0, 0.0));
+    assert!(events.iter().any(|event| matches!(event, GameEvent::GoalScored { side: PlayerSide::Right, .. })), "{:?}", events);
+    assert!(!events.iter().any(|event| matches!(event, GameEvent::PaddleHit { .. })));
+}
+
+#[test]
+fn players_who_are_up_to_date_score_straight_away() {
+    let mut sim = heading_left(at(-428.0, 0.0), 400.0, 200.0);
+    let events = sim.step_compensated([PlayerInput::default(); 2], [None, None]);
+    assert!(events.iter().any(|event| matches!(event, GameEvent::GoalScored { side: PlayerSide::Right, .. })), "{:?}", events);
+}
@@ -0,0 +1,252 @@
+//! Plays matches between scripted clients on a real server, all in this process. See harness/mod.rs.
+
+mod harness;
+
+use harness::{Harness, Inbox, RoomView};
+use pong_multiplayer_rs::{
+    common_config::{NetcodeMode, ServerSettings},
+    common_game::PlayerSide,
+    common_net::{PlayerInput, RollbackMessage, ServerMessages},
+};
+
+/// Every test seeds its serves, so the matches go the same way each time.
+fn settings() -> ServerSettings {
+    ServerSettings { seed: Some(0x5eed), ..Default::default() }
+}
+
+/// Doesn't touch anything.
+fn idle(_: &Inbox) -> PlayerInput {
+    PlayerInput::default()
+}
+
+/// Gets out of the way of the ball, by heading for the other half of the arena from it.
+fn dodge(inbox: &Inbox) -> PlayerInput {
+    let gamestate = match &inbox.gamestate {
+        Some(gamestate) => gamestate,
+        None => return PlayerInput::default(),
+    };
+    let paddle = match inbox.side {
+        Some(PlayerSide::Left) => gamestate.paddle_l_loc.y,
+        Some(PlayerSide::Right) => gamestate.paddle_r_loc.y,
+        None => return PlayerInput::default(),
+    };
+    let target = if gamestate.ball_loc.y > 0.0 { -300.0 } else { 300.0 };
+    PlayerInput { up: target > paddle, down: target < paddle, ..Default::default() }
+}
+
+/// Starts a server with two players on it, and waits for them to be given their paddles and the match to start.
+fn two_players(script: fn(&Inbox) -> PlayerInput) -> (Harness, usize, usize) {
+    let mut harness = Harness::new(settings());
+    let alice = harness.add_client("Alice", script);
+    let bob = harness.add_client("Bob", script);
+    let seated = harness.run_until(5.0, |h| h.inbox(alice).side.is_some() && h.inbox(bob).side.is_some());
+    assert!(seated, "the players never got their paddles");
+    // The matchmaker only marks the room for a reset, the match starts on the frame after.
+    let started = harness.run_until(1.0, |h| h.rooms().first().is_some_and(|room| room.playing));
+    assert!(started, "the match never started");
+    (harness, alice, bob)
+}
+
+#[test]
+fn two_players_are_matched_and_the_match_starts() {
+    let (mut harness, alice, bob) = two_players(idle);
+    assert_ne!(harness.inbox(alice).side, harness.inbox(bob).side);
+    for (player, opponent) in [(alice, "Bob"), (bob, "Alice")] {
+        let inbox = harness.inbox(player);
+        assert!(inbox.got(|m| matches!(m, ServerMessages::MatchFound { opponent: Some(name) } if name == opponent)));
+        assert!(inbox.got(|m| matches!(m, ServerMessages::MatchStarting { .. })));
+    }
+    assert_eq!(harness.rooms(), vec![RoomView { score: [0, 0], playing: true }]);
+
+    // The game itself comes over the unreliable channel.
+    harness.run_for(1.0);
+    for player in [alice, bob] {
+        let inbox = harness.inbox(player);
+        assert!(inbox.gamestates > 30, "only {} GameStates", inbox.gamestates);
+        assert!(inbox.gamestate.as_ref().unwrap().playing);
+    }
+}
+
+#[test]
+fn third_player_waits_in_the_queue_and_watches() {
+    let (mut harness, _, _) = two_players(idle);
+    let carol = harness.add_client("Carol", idle);
+    // GameStates come over another channel, so they can get there before we've been told where we stand.
+    let watching = harness.run_until(5.0, |h| {
+        let inbox = h.inbox(carol);
+        inbox.gamestates > 0 && inbox.queue_position.is_some() && inbox.got(|m| matches!(m, ServerMessages::PlayerIsSpectator))
+    });
+    assert!(watching, "the spectator never got to see the match");
+
+    let inbox = harness.inbox(carol);
+    assert_eq!(inbox.side, None);
+    assert_eq!(inbox.queue_position, Some(1));
+    assert!(inbox.got(|m| matches!(m, ServerMessages::PlayerIsSpectator)));
+    // Nobody to play against, so there's still just the one match.
+    assert_eq!(harness.rooms().len(), 1);
+}
+
+#[test]
+fn goals_are_scored_and_everyone_hears_about_them() {
+    let (mut harness, alice, bob) = two_players(dodge);
+    let scored = harness.run_until(120.0, |h| h.rooms()[0].score.iter().sum::<usize>() >= 2);
+    assert!(scored, "nobody scored, the score is {:?}", harness.rooms()[0].score);
+    // Let the messages about the last goal get there.
+    harness.run_for(0.5);
+
+    let score = harness.rooms()[0].score;
+    for player in [alice, bob] {
+        let inbox = harness.inbox(player);
+        assert_eq!(inbox.goals(), score);
+        let gamestate = inbox.gamestate.as_ref().unwrap();
+        assert_eq!([gamestate.score_l as usize, gamestate.score_r as usize], score);
+    }
+}
+
+#[test]
+fn a_player_dropping_out_pauses_the_match() {
+    let (mut harness, alice, bob) = two_players(idle);
+    harness.run_for(1.0);
+    assert!(harness.rooms()[0].playing);
+
+    harness.disconnect(alice);
+    let told = harness.run_until(5.0, |h| h.inbox(bob).got(|m| matches!(m, ServerMessages::PlayerReconnecting { .. })));
+    assert!(told, "the other player was never told");
+    assert!(!harness.rooms()[0].playing);
+}
+
+#[test]
+fn a_dropped_player_resumes_their_seat_and_the_match_carries_on() {
+    let (mut harness, alice, bob) = two_players(dodge);
+    let scored = harness.run_until(120.0, |h| h.rooms()[0].score.iter().sum::<usize>() >= 1);
+    assert!(scored, "nobody scored");
+    let score = harness.rooms()[0].score;
+    let side = harness.inbox(alice).side;
+
+    harness.disconnect(alice);
+    let held = harness.run_until(5.0, |h| h.inbox(bob).got(|m| matches!(m, ServerMessages::PlayerReconnecting { .. })));
+    assert!(held, "alice's seat was never held");
+    // Nobody plays on while she's gone.
+    harness.run_for(2.0);
+    assert_eq!(harness.rooms(), vec![RoomView { score, playing: false }]);
+    let rng = harness.room_rngs()[0];
+
+    harness.resume(alice).unwrap();
+    let back = harness.run_until(5.0, |h| h.inbox(alice).side.is_some() && h.rooms()[0].playing);
+    assert!(back, "alice never got back into the match");
+    assert_eq!(harness.inbox(alice).side, side);
+    assert_eq!(harness.rooms()[0].score, score);
+    // It's the same match, so the serves aren't reseeded.
+    assert_eq!(harness.room_rngs()[0], rng);
+    assert!(!harness.inbox(bob).got(|m| matches!(m, ServerMessages::PlayerDisconnected { .. })));
+
+    // The match goes on from where it was, and alice sees it.
+    let total = score.iter().sum::<usize>();
+    let scored = harness.run_until(120.0, |h| h.rooms()[0].score.iter().sum::<usize>() > total);
+    assert!(scored, "nobody scored after alice came back");
+    let now = harness.rooms()[0].score;
+    assert!(now[0] >= score[0] && now[1] >= score[1], "the score went from {:?} to {:?}", score, now);
+    harness.run_for(0.5);
+    let gamestate = harness.inbox(alice).gamestate.as_ref().unwrap();
+    assert_eq!([gamestate.score_l as usize, gamestate.score_r as usize], harness.rooms()[0].score);
+}
+
+#[test]
+fn a_spectator_leaving_while_a_seat_is_held_keeps_the_score() {
+    let (mut harness, alice, bob) = two_players(dodge);
+    let scored = harness.run_until(120.0, |h| h.rooms()[0].score.iter().sum::<usize>() >= 1);
+    assert!(scored, "nobody scored");
+    let score = harness.rooms()[0].score;
+    let side = harness.inbox(alice).side;
+
+    let carol = harness.add_client("Carol", idle);
+    let watching = harness.run_until(5.0, |h| h.inbox(carol).got(|m| matches!(m, ServerMessages::PlayerIsSpectator)));
+    assert!(watching, "the spectator never got to watch");
+
+    harness.disconnect(alice);
+    let held = harness.run_until(5.0, |h| h.inbox(bob).got(|m| matches!(m, ServerMessages::PlayerReconnecting { .. })));
+    assert!(held, "alice's seat was never held");
+    harness.disconnect(carol);
+    harness.run_for(1.0);
+    assert_eq!(harness.rooms(), vec![RoomView { score, playing: false }]);
+
+    harness.resume(alice).unwrap();
+    let back = harness.run_until(5.0, |h| h.inbox(alice).side.is_some() && h.rooms()[0].playing);
+    assert!(back, "alice never got back into the match");
+    assert_eq!(harness.inbox(alice).side, side);
+    assert_eq!(harness.rooms()[0].score, score);
+}
+
+#[test]
+fn a_player_can_resume_before_the_server_notices_they_dropped() {
+    let (mut harness, alice, bob) = two_players(dodge);
+    let scored = harness.run_until(120.0, |h| h.rooms()[0].score.iter().sum::<usize>() >= 1);
+    assert!(scored, "nobody scored");
+    let score = harness.rooms()[0].score;
+    let side = harness.inbox(alice).side;
+
+    // As far as the server knows alice is still connected, but she's already asking to come back.
+    harness.resume(alice).unwrap();
+    let back = harness.run_until(5.0, |h| h.inbox(alice).side.is_some() && h.rooms()[0].playing);
+    assert!(back, "alice never got back into the match");
+    assert_eq!(harness.inbox(alice).side, side);
+    assert_eq!(harness.rooms()[0].score, score);
+    // Bob was told she dropped out, and didn't lose his opponent.
+    assert!(harness.inbox(bob).got(|m| matches!(m, ServerMessages::PlayerReconnecting { .. })));
+    assert!(!harness.inbox(bob).got(|m| matches!(m, ServerMessages::PlayerDisconnected { .. })));
+}
+
+#[test]
+fn rollback_inputs_are_passed_on_and_the_winner_both_players_report_stands() {
+    let mut harness = Harness::new(ServerSettings { netcode: NetcodeMode::Rollback, ..settings() });
+    let alice = harness.add_client("Alice", idle);
+    let bob = harness.add_client("Bob", idle);
+    let started = harness.run_until(5.0, |h| !h.inbox(alice).rollback_starts().is_empty() && !h.inbox(bob).rollback_starts().is_empty());
+    assert!(started, "the rollback match never started");
+    let (round, seed, alice_side) = harness.inbox(alice).rollback_starts()[0];
+    let (bob_round, bob_seed, bob_side) = harness.inbox(bob).rollback_starts()[0];
+    assert_eq!((round, seed), (bob_round, bob_seed));
+    assert_ne!(alice_side, bob_side);
+
+    // Each player's inputs reach the other one as they were sent, in order.
+    let up = PlayerInput { up: true, ..Default::default() };
+    let down = PlayerInput { down: true, ..Default::default() };
+    for frame in 0..10 {
+        harness.send_rollback(alice, &RollbackMessage::Input { round, frame, input: up });
+        harness.send_rollback(bob, &RollbackMessage::Input { round, frame, input: down });
+    }
+    let relayed = harness.run_until(1.0, |h| h.inbox(alice).rollback_inputs().len() == 10 && h.inbox(bob).rollback_inputs().len() == 10);
+    assert!(relayed, "the inputs were never passed on");
+    assert_eq!(harness.inbox(bob).rollback_inputs(), (0..10).map(|frame| (round, frame, up)).collect::<Vec<_>>());
+    assert_eq!(harness.inbox(alice).rollback_inputs(), (0..10).map(|frame| (round, frame, down)).collect::<Vec<_>>());
+
+    // One player saying they won isn't enough. The server waits 5 seconds after a match before starting the next.
+    harness.send_rollback(alice, &RollbackMessage::MatchOver { round, winner: alice_side });
+    harness.run_for(6.0);
+    assert_eq!(harness.inbox(alice).rollback_starts().len(), 1);
+    // Once both agree, the next match starts.
+    harness.send_rollback(bob, &RollbackMessage::MatchOver { round, winner: alice_side });
+    let next = harness.run_until(6.0, |h| h.inbox(alice).rollback_starts().len() == 2 && h.inbox(bob).rollback_starts().len() == 2);
+    assert!(next, "the next match never started");
+    assert_eq!(harness.inbox(alice).rollback_starts()[1].0, round + 1);
+    assert_eq!(harness.inbox(bob).rollback_starts()[1].0, round + 1);
+}
+
+#[test]
+fn nobody_spectates_rollback_matches() {
+    let mut harness = Harness::new(ServerSettings { netcode: NetcodeMode::Rollback, ..settings() });
+    let alice = harness.add_client("Alice", idle);
+    let bob = harness.add_client("Bob", idle);
+    let started = harness.run_until(5.0, |h| !h.inbox(alice).rollback_starts().is_empty() && !h.inbox(bob).rollback_starts().is_empty());
+    assert!(started, "the rollback match never started");
+
+    // The server doesn't know what's happening in the match, so Carol would only get a frozen screen. She just waits her turn.
+    let carol = harness.add_client("Carol", idle);
+    let queued = harness.run_until(5.0, |h| h.inbox(carol).queue_position.is_some());
+    assert!(queued, "carol was never put in the queue");
+    harness.run_for(1.0);
+    let inbox = harness.inbox(carol);
+    assert_eq!(inbox.queue_position, Some(1));
+    assert!(!inbox.got(|m| matches!(m, ServerMessages::PlayerIsSpectator)));
+    assert_eq!(inbox.gamestates, 0);
+}